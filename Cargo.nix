@@ -965,6 +965,12 @@ rec {
             name = "enum-map-derive";
             packageId = "enum-map-derive";
           }
+          {
+            name = "serde";
+            packageId = "serde";
+            optional = true;
+            usesDefaultFeatures = false;
+          }
         ];
         features = {
           "arbitrary" = [ "dep:arbitrary" ];
@@ -1964,10 +1970,6 @@ rec {
             packageId = "clap";
             features = [ "derive" "suggestions" "color" "wrap_help" ];
           }
-          {
-            name = "dashmap";
-            packageId = "dashmap";
-          }
           {
             name = "enum-map";
             packageId = "enum-map";
@@ -1991,6 +1993,7 @@ rec {
           {
             name = "rayon";
             packageId = "rayon";
+            optional = true;
           }
           {
             name = "regex";
@@ -2000,6 +2003,12 @@ rec {
             name = "scarlet";
             packageId = "scarlet";
           }
+          {
+            name = "serde";
+            packageId = "serde";
+            optional = true;
+            features = [ "derive" ];
+          }
           {
             name = "walkdir";
             packageId = "walkdir";
@@ -2037,7 +2046,12 @@ rec {
             packageId = "which 5.0.0";
           }
         ];
-
+        features = {
+          "default" = [ "rayon" ];
+          "rayon" = [ "dep:rayon" ];
+          "serde" = [ "dep:serde" "petgraph/serde-1" "enum-map/serde" ];
+        };
+        resolvedDefaultFeatures = [ "default" "rayon" ];
       };
       "nom" = rec {
         crateName = "nom";
@@ -2429,6 +2443,16 @@ rec {
             name = "indexmap";
             packageId = "indexmap";
           }
+          {
+            name = "serde";
+            packageId = "serde";
+            optional = true;
+          }
+          {
+            name = "serde_derive";
+            packageId = "serde_derive";
+            optional = true;
+          }
         ];
         features = {
           "all" = [ "unstable" "quickcheck" "matrix_graph" "stable_graph" "graphmap" ];
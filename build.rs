@@ -64,7 +64,9 @@ fn main() {
         // bindings for.
         .header("wrapper.hpp")
         .allowlist_function("populateGraph")
+        .allowlist_function("backendVersion")
         .allowlist_type("path_t")
+        .allowlist_var("NIX_DU_.*")
         .opaque_type("std::.*")
         .clang_arg(format!("-DNIXVER={}", version))
         .clang_arg(standard)
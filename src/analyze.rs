@@ -0,0 +1,359 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::{DepInfos, StoreError};
+use crate::msg::*;
+use crate::{opt, reduction};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+/// Whether `analyze` takes store optimisation into account, see
+/// `opt::refine_optimized_store`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Optimisation {
+    /// count hardlinked files in each store path containing them
+    Ignore,
+    /// like `Alive` if `opt::store_is_optimised` says so or cannot tell
+    #[default]
+    Auto,
+    /// drop dead paths, and refine the others
+    Alive,
+    /// refine all paths, which takes longer
+    All,
+}
+
+/// Where the files of a store are, see `store_location`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreLocation {
+    /// on this machine, under this directory instead of `/` if any, see
+    /// `DepInfos::store_root`
+    Local(Option<PathBuf>),
+    /// on another machine or in a binary cache, they cannot be stat'ed
+    Remote,
+}
+
+/// Where the files of the store at `store_uri` are, `None` being the default
+/// store. `local?root=/mnt` and `/mnt` are stores under `/mnt`, `daemon`, `local`,
+/// `auto` and `unix://` stores are here, and other stores, like `ssh://host`,
+/// are remote.
+pub fn store_location(store_uri: Option<&OsStr>) -> StoreLocation {
+    let uri = match store_uri {
+        Some(uri) => uri.as_bytes(),
+        None => return StoreLocation::Local(None),
+    };
+    let (store, params) = match uri.iter().position(|&c| c == b'?') {
+        Some(i) => (&uri[..i], &uri[i + 1..]),
+        None => (uri, &b""[..]),
+    };
+    let root = params
+        .split(|&c| c == b'&')
+        .find_map(|param| param.strip_prefix(b"root="));
+    let path = |p: &[u8]| PathBuf::from(OsStr::from_bytes(p));
+    match store {
+        b"auto" | b"daemon" | b"local" => StoreLocation::Local(root.map(path)),
+        s if s.starts_with(b"unix://") => StoreLocation::Local(root.map(path)),
+        s if s.starts_with(b"/") => StoreLocation::Local(Some(path(root.unwrap_or(s)))),
+        _ => StoreLocation::Remote,
+    }
+}
+
+/// Options for `analyze`. The default reads the whole default store and only
+/// computes the quotient graph, like `nix-du` without options.
+#[derive(Debug, Clone, Default)]
+pub struct AnalyzeOptions {
+    /// the store to read, see `DepInfos::read_from_store_uri`, or the default one.
+    /// Store optimisation is ignored for remote stores, see `store_location`.
+    pub store_uri: Option<OsString>,
+    /// consider the dependencies of this path instead of all gc roots
+    pub root: Option<OsString>,
+    /// only read the paths reachable from gc roots, see `DepInfos::read_from_store`
+    pub live_only: bool,
+    /// also read derivations, see `DepInfos::read_from_store`
+    pub derivations: bool,
+    pub optimisation: Optimisation,
+    /// how many files `Optimisation::Auto` checks, see
+    /// `opt::store_is_optimised_probing`, or `opt::DEFAULT_PROBES`
+    pub optimisation_probes: Option<usize>,
+    /// how to refine the graph when taking store optimisation into account
+    pub refine: opt::RefineOptions,
+    /// see `reduction::drop_transient_roots`
+    pub no_transient: bool,
+    /// see `reduction::merge_by_name`
+    pub group_by_name: bool,
+    /// see `reduction::retain_above`
+    pub min_size: u64,
+}
+
+/// Reads the store and reduces its graph per `options`, as `nix-du` does before
+/// rendering it: take store optimisation into account, merge transient roots,
+/// compute the quotient graph, hide small nodes and remove redundant edges.
+///
+/// Failing to take store optimisation into account is not fatal: a warning is
+/// printed and the graph is used as read.
+pub fn analyze(options: &AnalyzeOptions) -> Result<DepInfos, StoreError> {
+    let g = DepInfos::read_with(
+        crate::bindings::populateGraph,
+        options.root.clone(),
+        options.store_uri.clone(),
+        options.live_only,
+        options.derivations,
+    )?;
+    Ok(reduce(g, options))
+}
+
+/// what `analyze` does once the store is read
+fn reduce(mut g: DepInfos, options: &AnalyzeOptions) -> DepInfos {
+    let optimisation = match store_location(options.store_uri.as_deref()) {
+        StoreLocation::Remote => Optimisation::Ignore,
+        StoreLocation::Local(root) => {
+            if g.store_root.is_none() {
+                g.store_root = root;
+            }
+            options.optimisation
+        }
+    };
+    let probes = options.optimisation_probes.unwrap_or(opt::DEFAULT_PROBES);
+    g = take_optimisation_into_account(g, optimisation, probes, &options.refine);
+    if options.group_by_name {
+        g = reduction::merge_by_name(reduction::keep_reachable(g));
+    }
+    g = transient_roots(g, options.no_transient);
+    g = reduction::condense(g);
+    if options.min_size > 0 {
+        g = reduction::retain_above(g, options.min_size);
+    }
+    reduction::transitive_reduction(g)
+}
+
+/// Refines `g` per `optimisation`, see `opt::refine_optimized_store`.
+/// `Optimisation::Auto` checks `probes` files, see
+/// `opt::store_is_optimised_probing`. The files of the store must be on this
+/// machine, see `store_location`.
+///
+/// Failing to take store optimisation into account is not fatal: a warning is
+/// printed and the graph is used as read.
+pub fn take_optimisation_into_account(
+    mut g: DepInfos,
+    optimisation: Optimisation,
+    probes: usize,
+    options: &opt::RefineOptions,
+) -> DepInfos {
+    let optimisation = match optimisation {
+        Optimisation::Auto => match opt::store_is_optimised_probing(&g, probes) {
+            Ok(Some(false)) => Optimisation::Ignore,
+            Ok(_) => Optimisation::Alive,
+            Err(e) => {
+                msg!("Warning: could not detect store optimisation: {}\n", e);
+                Optimisation::Alive
+            }
+        },
+        o => o,
+    };
+    if optimisation == Optimisation::Ignore {
+        return g;
+    }
+    if optimisation == Optimisation::Alive {
+        g = reduction::keep_reachable(g);
+    }
+    msg!("Looking for optimized paths... (this could take a long time, pass option -O0 to skip)\n");
+    if let Err(e) = opt::refine_optimized_store(&mut g, options) {
+        msg!(
+            "Warning: could not take store optimisation into account: {}\n",
+            e
+        );
+    }
+    g
+}
+
+/// Drops transient roots if `drop` is set, and merges the remaining ones, see
+/// `reduction::drop_transient_roots` and `reduction::merge_transient_roots`.
+pub fn transient_roots(mut g: DepInfos, drop: bool) -> DepInfos {
+    if drop {
+        g = reduction::drop_transient_roots(g);
+    }
+    reduction::merge_transient_roots(g)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindings;
+    use crate::depgraph::*;
+    use std::os::raw::{c_char, c_int, c_void};
+
+    /// pretends to be `populateGraph` on a store where a profile keeps an app and
+    /// its library alive, a build keeps a tool alive, a path is dead and another
+    /// one is invalid
+    unsafe extern "C" fn mock_populate(
+        graph: *mut c_void,
+        root_path: *const c_char,
+        _store_uri: *const c_char,
+        reachable_only: c_int,
+        _with_derivations: c_int,
+    ) -> c_int {
+        assert!(root_path.is_null());
+        let ctx = graph as *mut ReadContext;
+        let register_valid = |path: &[u8], size: u64, is_root: bool, is_valid: bool| {
+            let path = std::ffi::CString::new(path).unwrap();
+            let p = bindings::path_t {
+                path: path.as_ptr(),
+                size,
+                is_root: is_root as c_int,
+                is_valid: is_valid as c_int,
+            };
+            register_node(ctx, &p);
+        };
+        let register =
+            |path: &[u8], size: u64, is_root: bool| register_valid(path, size, is_root, true);
+        register(b"/nix/store/aaaa-app", 100, false);
+        register(b"/nix/store/bbbb-lib", 1000, false);
+        register(b"/nix/store/cccc-tool", 10, false);
+        register_edge(ctx, 0, 1);
+        register(b"/nix/var/nix/profiles/default-1-link", 0, true);
+        register_edge(ctx, 3, 0);
+        register(b"{temp:42}", 0, true);
+        register_edge(ctx, 4, 2);
+        if reachable_only == 0 {
+            register(b"/nix/store/dddd-dead", 1, false);
+            register_valid(b"/nix/store/eeee-partial", 0, false, false);
+        }
+        0
+    }
+
+    fn analyze(options: &AnalyzeOptions) -> DepInfos {
+        let g = DepInfos::read_with(
+            mock_populate,
+            options.root.clone(),
+            options.store_uri.clone(),
+            options.live_only,
+            options.derivations,
+        )
+        .unwrap();
+        reduce(g, options)
+    }
+
+    fn names(di: &DepInfos) -> Vec<String> {
+        let mut res: Vec<_> = di
+            .graph
+            .node_weights()
+            .map(|n| String::from_utf8_lossy(&n.name()).into_owned())
+            .collect();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn analyze_mocked_store() {
+        let options = AnalyzeOptions {
+            optimisation: Optimisation::Ignore,
+            ..AnalyzeOptions::default()
+        };
+        let di = analyze(&options);
+        di.check_metadata();
+        assert_eq!(di.reachable_size(), 1110);
+        // the quotient graph drops dead paths, invalid ones included, and merges
+        // paths with the roots keeping them alive
+        assert_eq!(di.unreachable_size(), 0);
+        assert!(di.invalid_paths().is_empty());
+        assert_eq!(
+            names(&di),
+            vec![
+                "/nix/var/nix/profiles/default-1-link".to_owned(),
+                "{dummy}".to_owned(),
+                "{transient}".to_owned()
+            ]
+        );
+
+        let options = AnalyzeOptions {
+            optimisation: Optimisation::Ignore,
+            live_only: true,
+            no_transient: true,
+            min_size: 50,
+            ..AnalyzeOptions::default()
+        };
+        let di = analyze(&options);
+        di.check_metadata();
+        assert_eq!(di.reachable_size(), 1100);
+        assert_eq!(
+            names(&di),
+            vec![
+                "/nix/var/nix/profiles/default-1-link".to_owned(),
+                "{dummy}".to_owned()
+            ]
+        );
+
+        // the tool is hidden, its size goes to the transient root
+        let options = AnalyzeOptions {
+            optimisation: Optimisation::Ignore,
+            min_size: 50,
+            ..AnalyzeOptions::default()
+        };
+        assert_eq!(analyze(&options).reachable_size(), 1110);
+    }
+
+    #[test]
+    fn invalid_paths_are_dead() {
+        let di = DepInfos::read_with(mock_populate, None, None, false, false).unwrap();
+        let invalid = di.invalid_paths();
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(&*di.graph[invalid[0]].name(), b"partial");
+        assert!(di.unreachable().contains(&invalid[0]));
+        assert!(di.roots().all(|idx| di.graph[idx].is_valid()));
+    }
+
+    #[test]
+    fn store_location() {
+        use super::StoreLocation::*;
+        let location = |uri: &str| super::store_location(Some(OsStr::new(uri)));
+        assert_eq!(super::store_location(None), Local(None));
+        assert_eq!(location("daemon"), Local(None));
+        assert_eq!(location("unix:///run/nix/socket"), Local(None));
+        assert_eq!(
+            location("local?read-only=true&root=/mnt"),
+            Local(Some(PathBuf::from("/mnt")))
+        );
+        assert_eq!(location("/mnt"), Local(Some(PathBuf::from("/mnt"))));
+        assert_eq!(location("ssh://builder"), Remote);
+        assert_eq!(location("ssh-ng://builder"), Remote);
+        assert_eq!(location("https://cache.nixos.org"), Remote);
+    }
+
+    #[test]
+    fn analyze_store_root() {
+        // app and lib share a file, in a store under `root`
+        let root = std::env::temp_dir().join(format!(
+            "nix-du-test-analyze_store_root-{}",
+            std::process::id()
+        ));
+        let store = root.join("nix/store");
+        for path in &["aaaa-app", "bbbb-lib", "cccc-tool", ".links"] {
+            std::fs::create_dir_all(store.join(path)).unwrap();
+        }
+        std::fs::write(store.join("aaaa-app/file"), vec![0u8; 10]).unwrap();
+        std::fs::hard_link(store.join("aaaa-app/file"), store.join("bbbb-lib/file")).unwrap();
+        std::fs::hard_link(store.join("aaaa-app/file"), store.join(".links/file")).unwrap();
+
+        let mut uri = OsString::from("local?root=");
+        uri.push(&root);
+        let options = AnalyzeOptions {
+            store_uri: Some(uri),
+            ..AnalyzeOptions::default()
+        };
+        let di = analyze(&options);
+        di.check_metadata();
+        assert_eq!(di.store_root.as_ref(), Some(&root));
+        // the file is counted once
+        assert_eq!(di.reachable_size(), 1110 - 10);
+
+        // the local files are not those of a remote store
+        let options = AnalyzeOptions {
+            store_uri: Some(OsString::from("ssh://builder")),
+            optimisation: Optimisation::All,
+            ..AnalyzeOptions::default()
+        };
+        let di = analyze(&options);
+        assert_eq!(di.store_root, None);
+        assert_eq!(di.reachable_size(), 1110);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
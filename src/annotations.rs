@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use petgraph::prelude::NodeIndex;
+use std::collections::BTreeMap;
+
+/// Data attached to some nodes of a graph, like tags or colors, without changing
+/// `DepNode`.
+///
+/// Transforms renumber nodes. Those with a `_remapping` variant, like
+/// `reduction::merge_by_name_remapping`, report where each node went, and the
+/// reported moves, collected for example with `|old, new| moves.push((old, new))`,
+/// are then applied with `remap`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotations<T>(BTreeMap<NodeIndex, T>);
+
+impl<T> Default for Annotations<T> {
+    fn default() -> Self {
+        Annotations(BTreeMap::new())
+    }
+}
+
+impl<T> Annotations<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// attaches `value` to `idx`, returning what was attached before.
+    pub fn insert(&mut self, idx: NodeIndex, value: T) -> Option<T> {
+        self.0.insert(idx, value)
+    }
+
+    pub fn get(&self, idx: NodeIndex) -> Option<&T> {
+        self.0.get(&idx)
+    }
+
+    pub fn get_mut(&mut self, idx: NodeIndex) -> Option<&mut T> {
+        self.0.get_mut(&idx)
+    }
+
+    pub fn remove(&mut self, idx: NodeIndex) -> Option<T> {
+        self.0.remove(&idx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// the annotated nodes and their annotations, by increasing index
+    pub fn iter(&self) -> impl Iterator<Item = (NodeIndex, &T)> {
+        self.0.iter().map(|(&idx, value)| (idx, value))
+    }
+
+    /// moves the annotations according to `moves`, pairs of an old index and the
+    /// new index of the same node, as reported by a transform.
+    ///
+    /// Annotations of nodes without a move are dropped, since the node was
+    /// removed. When several annotated nodes are merged into one, their
+    /// annotations are combined with `merge(first, other)`, in the order of
+    /// `moves`.
+    pub fn remap(&mut self, moves: &[(NodeIndex, NodeIndex)], mut merge: impl FnMut(&mut T, T)) {
+        let mut old = std::mem::take(&mut self.0);
+        for &(from, to) in moves {
+            if let Some(value) = old.remove(&from) {
+                match self.0.entry(to) {
+                    std::collections::btree_map::Entry::Vacant(e) => {
+                        e.insert(value);
+                    }
+                    std::collections::btree_map::Entry::Occupied(mut e) => {
+                        merge(e.get_mut(), value)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Annotations;
+    use crate::depgraph::*;
+    use crate::reduction;
+    use petgraph::prelude::NodeIndex;
+
+    #[test]
+    fn follow_merge_by_name() {
+        // hello is there twice, with different hashes
+        let mut di = DepInfos::from_spec(
+            &[("app", 1), ("hello", 2), ("lib", 4), ("hello", 8)],
+            &[(0, 1), (0, 2), (2, 3)],
+            &[0],
+        );
+        // from_spec makes distinct hashes, but merge_by_name compares names
+        for i in [1, 3] {
+            di.graph[NodeIndex::new(i)].description =
+                NodeDescription::Path(format!("/nix/store/{:032}-hello", i).into_bytes());
+        }
+        let mut tags = Annotations::new();
+        tags.insert(NodeIndex::new(0), vec!["app"]);
+        tags.insert(NodeIndex::new(1), vec!["old hello"]);
+        tags.insert(NodeIndex::new(3), vec!["new hello"]);
+
+        let mut moves = Vec::new();
+        let merged = reduction::merge_by_name_remapping(di, |old, new| moves.push((old, new)));
+        tags.remap(&moves, |kept, other| kept.extend(other));
+        assert_eq!(tags.len(), 2);
+        for (idx, tag) in tags.iter() {
+            let name = merged.graph[idx].name().into_owned();
+            match &name[..] {
+                b"app" => assert_eq!(tag, &vec!["app"]),
+                b"hello" => {
+                    assert_eq!(tag, &vec!["old hello", "new hello"]);
+                    assert_eq!(merged.graph[idx].size, 2 + 8);
+                }
+                _ => panic!("unexpected annotated node {:?}", merged.graph[idx]),
+            }
+        }
+
+        // lib is dropped, its annotation too
+        tags.insert(
+            merged
+                .graph
+                .node_indices()
+                .find(|&idx| &*merged.graph[idx].name() == b"lib")
+                .unwrap(),
+            vec!["lib"],
+        );
+        let mut moves = Vec::new();
+        let kept = reduction::retain_above_remapping(merged, 5, |old, new| moves.push((old, new)));
+        tags.remap(&moves, |_, _| panic!("nothing is merged"));
+        let mut names: Vec<_> = tags
+            .iter()
+            .map(|(idx, tag)| (kept.graph[idx].name().into_owned(), tag.clone()))
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                (b"app".to_vec(), vec!["app"]),
+                (b"hello".to_vec(), vec!["old hello", "new hello"])
+            ]
+        );
+    }
+}
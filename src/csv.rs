@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph;
+use std::cell::Cell;
+use std::io::{self, Write};
+
+/// writes `s` as a quoted CSV field, as per RFC 4180, with invalid UTF-8 rendered
+/// lossily.
+fn write_field<W: Write>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    let text = String::from_utf8_lossy(s);
+    w.write_all(b"\"")?;
+    w.write_all(text.replace('"', "\"\"").as_bytes())?;
+    w.write_all(b"\"")
+}
+
+/// Outputs the nodes of the graph as CSV, with a header line and columns `name`,
+/// `path`, `size`, `closure_size` (from `closure_sizes`, those of `dependencies`)
+/// and `is_root`, sorted by decreasing size.
+///
+/// `name` and `path` are always quoted, and `path` is empty for nodes without
+/// path. Invalid UTF-8 is replaced by U+FFFD, and the nodes concerned are
+/// counted in `non_utf8`. The dummy root is not output.
+pub fn render<W: Write>(
+    dependencies: &depgraph::DepInfos,
+    closure_sizes: &depgraph::ClosureSizes,
+    non_utf8: &Cell<usize>,
+    w: &mut W,
+) -> io::Result<()> {
+    let mut is_root = vec![false; dependencies.graph.node_count()];
+    for idx in dependencies.roots() {
+        is_root[idx.index()] = true;
+    }
+    let mut nodes: Vec<_> = dependencies
+        .graph
+        .node_indices()
+        .filter(|&idx| idx != dependencies.root)
+        .collect();
+    nodes.sort_by_key(|&idx| (std::cmp::Reverse(dependencies.graph[idx].size), idx));
+
+    w.write_all(b"name,path,size,closure_size,is_root\r\n")?;
+    for idx in nodes {
+        let node = &dependencies.graph[idx];
+        if !node.is_utf8() {
+            non_utf8.set(non_utf8.get() + 1);
+        }
+        write_field(w, &node.name())?;
+        w.write_all(b",")?;
+        write_field(w, node.description.path().map_or(&[][..], |p| p))?;
+        write!(
+            w,
+            ",{},{},{}\r\n",
+            node.size,
+            closure_sizes.get(idx),
+            is_root[idx.index()]
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+
+    /// splits `csv` into records of fields, as per RFC 4180
+    fn records(csv: &str) -> Vec<Vec<String>> {
+        let mut records = Vec::new();
+        let mut record = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = csv.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => record.push(std::mem::take(&mut field)),
+                '\r' if !quoted && chars.peek() == Some(&'\n') => {
+                    chars.next();
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                c => field.push(c),
+            }
+        }
+        assert!(!quoted && field.is_empty() && record.is_empty());
+        records
+    }
+
+    #[test]
+    fn parse() {
+        let mut out = Vec::new();
+        let non_utf8 = std::cell::Cell::new(0);
+        let di = DepInfos::fixture();
+        super::render(&di, &ClosureSizes::new(&di), &non_utf8, &mut out).unwrap();
+        let records = records(&String::from_utf8(out).unwrap());
+        assert_eq!(
+            records[0],
+            ["name", "path", "size", "closure_size", "is_root"]
+        );
+        // the dummy root is not output
+        assert_eq!(records.len() - 1, di.graph.node_count() - 1);
+        assert!(records.iter().all(|record| record.len() == 5));
+        // fields with commas, quotes and newlines round-trip
+        let b = &di.graph[petgraph::prelude::NodeIndex::new(1)];
+        let row = records.iter().find(|record| record[2] == "2").unwrap();
+        assert_eq!(row[0].as_bytes(), &*b.name());
+        assert_eq!(row[1].as_bytes(), &b.description.path().unwrap()[..]);
+        let roots: usize = records[1..]
+            .iter()
+            .filter(|record| record[4] == "true")
+            .count();
+        assert_eq!(roots, di.roots().count());
+    }
+
+    #[test]
+    fn render() {
+        let mut out = Vec::new();
+        let non_utf8 = std::cell::Cell::new(0);
+        let di = DepInfos::fixture();
+        super::render(&di, &ClosureSizes::new(&di), &non_utf8, &mut out).unwrap();
+        // the gc root
+        assert_eq!(non_utf8.get(), 1);
+        let expected = "name,path,size,closure_size,is_root\r
+\"shared:c\",\"shared:c\",16,16,false\r
+\"/roots/\u{fffd}\u{1}\",\"/roots/\u{fffd}\u{1}\",8,28,true\r
+\"c\",\"/nix/store/00000000000000000000000000000002-c\",4,20,false\r
+\"b\"\"<&>,\\\n\",\"/nix/store/00000000000000000000000000000001-b\"\"<&>,\\\n\",2,22,false\r
+\"a\",\"/nix/store/00000000000000000000000000000000-a\",1,23,true\r
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
@@ -1,14 +1,14 @@
 // SPDX-License-Identifier: LGPL-3.0
 
 use crate::bindings;
+use crate::msg::quiet;
 use enum_map::{enum_map, Enum};
 use std;
 use std::borrow::Cow;
-#[cfg(test)]
 use std::collections;
 use std::ffi::{CStr, OsStr, OsString};
 use std::fmt::{self, Display};
-use std::os::raw::c_void;
+use std::os::raw::{c_char, c_int, c_void};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::path::PathBuf;
@@ -17,7 +17,6 @@ use std::vec::Vec;
 
 use petgraph::prelude::NodeIndex;
 use petgraph::visit::Dfs;
-use petgraph::visit::IntoNodeReferences;
 
 use enum_map::EnumMap;
 
@@ -29,10 +28,15 @@ pub enum NodeKind {
     Link,
     Dummy,
     FilteredOut,
+    Others,
     Memory,
     Temporary,
     Transient,
     Shared,
+    Cycle,
+    Deeper,
+    Dedup,
+    Invalid,
 }
 
 impl NodeKind {
@@ -40,7 +44,9 @@ impl NodeKind {
         use self::NodeKind::*;
         match self {
             Transient | Link | Memory | Temporary => true,
-            FilteredOut | Path | Shared | Dummy => false,
+            FilteredOut | Others | Path | Shared | Dummy | Cycle | Deeper | Dedup | Invalid => {
+                false
+            }
         }
     }
 
@@ -48,34 +54,64 @@ impl NodeKind {
         use self::NodeKind::*;
         match self {
             Memory | Temporary => true,
-            Transient | Link | FilteredOut | Path | Shared | Dummy => false,
+            Transient | Link | FilteredOut | Others | Path | Shared | Dummy | Cycle | Deeper
+            | Dedup | Invalid => false,
         }
     }
 }
 
+/// What keeps a gc root alive
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum RootKind {
+    /// A generation of a profile
+    Profile,
+    /// A link outside the nix state directory, registered as an indirect root,
+    /// like `result` symlinks
+    Indirect,
+    /// A path used by a running process
+    Memory,
+    /// A temporary root, for example of a running build
+    Temp,
+    /// Any other root, for example links in `/nix/var/nix/gcroots`
+    Other,
+}
+
 pub type Path = Vec<u8>;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub enum NodeDescription {
     /// A real, valid store path
-    Path(Path),
+    Path(#[cfg_attr(feature = "serde", serde(with = "crate::serialization::bytes"))] Path),
     /// A indirect root, as a link on the filesystem
-    Link(Path),
+    Link(#[cfg_attr(feature = "serde", serde(with = "crate::serialization::bytes"))] Path),
     /// A dummy node, for example the fake root whose all gc roots are children
     Dummy,
     /// A node gathering all filtered out ones
     FilteredOut,
+    /// A node gathering all nodes but the largest ones, see `reduction::keep_top`
+    Others,
     /// A node gathering all Memory and Temporary roots
     Transient,
     /// An in-memory root
-    Memory(Path),
+    Memory(#[cfg_attr(feature = "serde", serde(with = "crate::serialization::bytes"))] Path),
     /// A temporary root
-    Temporary(Path),
-    /// Symbolises a set of inodes de-duplicated by store optimisation
-    Shared(Path),
+    Temporary(#[cfg_attr(feature = "serde", serde(with = "crate::serialization::bytes"))] Path),
+    /// Symbolises a set of inodes de-duplicated by store optimisation. Contains its
+    /// name, see `opt::RefineOptions::shared_prefix`.
+    Shared(#[cfg_attr(feature = "serde", serde(with = "crate::serialization::bytes"))] Path),
+    /// A set of nodes forming a cycle, merged together. Contains their names.
+    Cycle(#[cfg_attr(feature = "serde", serde(with = "crate::serialization::bytes"))] Path),
+    /// A node gathering nodes too far from the roots, see `DepInfos::truncate_depth`
+    Deeper,
+    /// A node gathering all `Shared` nodes, see `reduction::merge_shared`
+    Dedup,
+    /// A path in the store directory which is not valid, like the output of an
+    /// interrupted build. Nothing refers to it, and its size is not known.
+    Invalid(#[cfg_attr(feature = "serde", serde(with = "crate::serialization::bytes"))] Path),
 }
 
-const SHARED_PREFIX: &[u8] = b"shared:";
+const CYCLE_PREFIX: &[u8] = b"{cycle: ";
 
 /// Converts `/home/symphorien/.cache/lorri/gc_roots/02ebed43adca1d7ca863ce9b0a537205/gc_root/shell_gc_root/` into `/home/symphorien/src/lorri/tests/integration/bug23_gopath/shell.nix`
 fn resolve_lorri_root(path: &[u8]) -> std::io::Result<PathBuf> {
@@ -119,6 +155,45 @@ impl Display for LinkAge {
     }
 }
 
+/// splits the name of a store path into package name, version, and what follows the
+/// version, typically an output name.
+///
+/// The version is the last run of `-` separated components starting with a digit, so
+/// `openssl-3.0.8-dev` is split into `openssl`, `3.0.8` and `dev`, `glibc-2.37-8` into
+/// `glibc` and `2.37-8`, and `foo-2-bar-1.0` into `foo-2-bar` and `1.0`. Without
+/// version, the package name is the whole name.
+fn split_name(name: &[u8]) -> (&[u8], Option<&[u8]>, &[u8]) {
+    let dash_digit =
+        |i: usize| name[i] == b'-' && matches!(name.get(i + 1), Some(c) if c.is_ascii_digit());
+    let last = match (0..name.len()).rev().find(|&i| dash_digit(i)) {
+        Some(last) => last,
+        None => return (name, None, b""),
+    };
+    let end = (last + 1..name.len()).find(|&i| name[i] == b'-');
+    let mut start = last;
+    while let Some(previous) = (0..start).rev().find(|&i| name[i] == b'-') {
+        if !dash_digit(previous) {
+            break;
+        }
+        start = previous;
+    }
+    match end {
+        None => (&name[..start], Some(&name[start + 1..]), b""),
+        Some(end) => (
+            &name[..start],
+            Some(&name[start + 1..end]),
+            &name[end + 1..],
+        ),
+    }
+}
+
+lazy_static! {
+    /// captures the hash and the name of a store path
+    static ref STORE_PATH: regex::bytes::Regex =
+        regex::bytes::Regex::new(r"^/(?:.*)/([a-z0-9]*)-([^/]*)$")
+            .expect("regex compilation failed");
+}
+
 impl NodeDescription {
     /// a short but human readable description of the node
     /// for a store path, only shows the name
@@ -127,9 +202,6 @@ impl NodeDescription {
     pub fn name(&self) -> Cow<[u8]> {
         use self::NodeDescription::*;
         lazy_static! {
-            static ref STORE_PATH: regex::bytes::Regex =
-                regex::bytes::Regex::new(r"^/(?:.*)/[a-z0-9]*-([^/]*)$")
-                    .expect("regex compilation failed");
             static ref PER_USER_PROFILE: regex::Regex =
                 regex::Regex::new(r"^/(?:.*)/profiles/per-user/([^/]*)/([^/]*)-([0-9]*)-link$")
                     .expect("regex compilation failed");
@@ -142,9 +214,9 @@ impl NodeDescription {
             .expect("regex compilation failed");
         };
         match self {
-            Path(path) => match STORE_PATH.captures(&path) {
+            Path(path) | Invalid(path) => match STORE_PATH.captures(&path) {
                 Some(c) => {
-                    let name = c.get(1).unwrap().as_bytes();
+                    let name = c.get(2).unwrap().as_bytes();
                     Cow::Borrowed(name)
                 }
                 None => Cow::Borrowed(&path),
@@ -204,19 +276,119 @@ impl NodeDescription {
                 }
                 Err(_) => Cow::Borrowed(path),
             },
-            Memory(path) | Temporary(path) => Cow::Borrowed(&path),
+            Memory(path) | Temporary(path) | Shared(path) => Cow::Borrowed(&path),
             Dummy => Cow::Borrowed(b"{dummy}"),
             FilteredOut => Cow::Borrowed(b"{filtered out}"),
+            Others => Cow::Borrowed(b"{others}"),
             Transient => Cow::Borrowed(b"{transient}"),
-            Shared(name) => {
-                let mut res = Vec::with_capacity(SHARED_PREFIX.len() + name.len());
-                res.extend(SHARED_PREFIX);
-                res.extend(name);
+            Deeper => Cow::Borrowed(b"{deeper}"),
+            Dedup => Cow::Borrowed(b"{dedup}"),
+            Cycle(names) => {
+                let mut res = Vec::with_capacity(CYCLE_PREFIX.len() + names.len() + 1);
+                res.extend(CYCLE_PREFIX);
+                res.extend(names);
+                res.push(b'}');
                 Cow::Owned(res)
             }
         }
     }
 
+    /// the hash part of a store path, as in `nix path-info`
+    /// returns `None` for nodes which are not store paths, like roots.
+    pub fn hash(&self) -> Option<&[u8]> {
+        match self {
+            NodeDescription::Path(path) => STORE_PATH
+                .captures(path)
+                .map(|c| c.get(1).unwrap().as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// the name part of a store path, without the hash, like `name` but without
+    /// allocation. Returns `None` for nodes which are not store paths.
+    fn store_name(&self) -> Option<&[u8]> {
+        match self {
+            NodeDescription::Path(path) => STORE_PATH
+                .captures(path)
+                .map(|c| c.get(2).unwrap().as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// the name of the package of a store path, without version, for example `glibc`
+    /// for `glibc-2.37-8`. Returns `None` for nodes which are not store paths.
+    pub fn pname(&self) -> Option<&[u8]> {
+        self.store_name().map(|name| split_name(name).0)
+    }
+
+    /// the version of the package of a store path, for example `2.37-8` for
+    /// `glibc-2.37-8`. Returns `None` for nodes which are not store paths, and store
+    /// paths without version.
+    pub fn version(&self) -> Option<&[u8]> {
+        self.store_name().and_then(|name| split_name(name).1)
+    }
+
+    /// the output of its derivation a store path is, for example `dev` for
+    /// `openssl-3.0.8-dev`: what follows the version, see `version`.
+    /// Returns `None` for the default output `out`, which has no suffix, for store
+    /// paths without version, and for nodes which are not store paths.
+    pub fn output_name(&self) -> Option<&[u8]> {
+        self.store_name()
+            .map(|name| split_name(name).2)
+            .filter(|output| !output.is_empty())
+    }
+
+    /// whether this store path looks like sources, like `nixpkgs-source`,
+    /// `hello-2.12-src` or a tarball, which are usually fixed-output derivations.
+    ///
+    /// This is a heuristic on the name: path infos do not tell how a path was built.
+    pub fn looks_like_source(&self) -> bool {
+        // what fetchers name their outputs
+        const SUFFIXES: &[&[u8]] = &[
+            b"-source",
+            b"-src",
+            b".tar",
+            b".tar.gz",
+            b".tgz",
+            b".tar.bz2",
+            b".tar.xz",
+            b".tar.zst",
+            b".zip",
+            b".patch",
+            b".diff",
+        ];
+        match self.store_name() {
+            Some(name) => name == b"source" || SUFFIXES.iter().any(|s| name.ends_with(s)),
+            None => false,
+        }
+    }
+
+    /// the kind of gc root this node is, or `None` if it is not a gc root.
+    /// `{transient}` counts as `RootKind::Other`.
+    pub fn root_kind(&self) -> Option<RootKind> {
+        use self::NodeDescription::*;
+        match self {
+            Memory(_) => Some(RootKind::Memory),
+            Temporary(_) => Some(RootKind::Temp),
+            Link(path) => {
+                let path = std::path::Path::new(OsStr::from_bytes(path));
+                if path
+                    .components()
+                    .any(|c| c.as_os_str() == OsStr::new("profiles"))
+                {
+                    Some(RootKind::Profile)
+                } else if path.starts_with("/nix/var/nix") {
+                    Some(RootKind::Other)
+                } else {
+                    Some(RootKind::Indirect)
+                }
+            }
+            Transient => Some(RootKind::Other),
+            Path(_) | Dummy | FilteredOut | Others | Shared(_) | Cycle(_) | Deeper | Dedup
+            | Invalid(_) => None,
+        }
+    }
+
     /// returns the path as an `OsStr` if this node is on the filesystem
     pub fn path_as_os_str(&self) -> Option<&OsStr> {
         use self::NodeDescription::*;
@@ -229,9 +401,20 @@ impl NodeDescription {
     pub fn path(&self) -> Option<&Path> {
         use self::NodeDescription::*;
         match self {
-            Link(path) | Path(path) | Memory(path) | Temporary(path) => Some(&path),
-            Shared(name) => Some(&name),
-            Transient | Dummy | FilteredOut => None,
+            Link(path) | Path(path) | Memory(path) | Temporary(path) | Invalid(path) => Some(&path),
+            Shared(name) | Cycle(name) => Some(&name),
+            Transient | Dummy | FilteredOut | Others | Deeper | Dedup => None,
+        }
+    }
+
+    /// the path identifying this node across graphs, see `DepInfos::dedup_paths`.
+    /// Nodes made up by nix-du, like `Shared` nodes, have none even when they are
+    /// named after a path.
+    fn identity(&self) -> Option<&Path> {
+        use self::NodeDescription::*;
+        match self {
+            Link(path) | Path(path) | Invalid(path) => Some(path),
+            _ => None,
         }
     }
 
@@ -243,9 +426,14 @@ impl NodeDescription {
             Memory(_) => NodeKind::Memory,
             Temporary(_) => NodeKind::Temporary,
             Shared(_) => NodeKind::Shared,
+            Cycle(_) => NodeKind::Cycle,
             Dummy => NodeKind::Dummy,
             FilteredOut => NodeKind::FilteredOut,
+            Others => NodeKind::Others,
             Transient => NodeKind::Transient,
+            Deeper => NodeKind::Deeper,
+            Dedup => NodeKind::Dedup,
+            Invalid(_) => NodeKind::Invalid,
         }
     }
 }
@@ -261,11 +449,15 @@ impl fmt::Debug for NodeDescription {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub struct DepNode {
     pub description: NodeDescription,
     /// size in bytes
     pub size: u64,
+    /// for a gc root whose link was resolved by `DepInfos::follow_root_links`,
+    /// the link originally registered as gc root.
+    pub link_path: Option<Path>,
 }
 
 impl DepNode {
@@ -282,6 +474,8 @@ impl DepNode {
                 description = Memory(path);
             } else if p.is_root != 0 {
                 description = Link(path);
+            } else if p.is_valid == 0 {
+                description = Invalid(path);
             } else {
                 description = Path(path);
             }
@@ -302,6 +496,7 @@ impl DepNode {
         Self {
             description,
             size: p.size,
+            link_path: None,
         }
     }
 
@@ -309,6 +504,7 @@ impl DepNode {
         DepNode {
             description: NodeDescription::Dummy,
             size: 0,
+            link_path: None,
         }
     }
 
@@ -319,6 +515,52 @@ impl DepNode {
     pub fn name(&self) -> Cow<[u8]> {
         self.description.name()
     }
+
+    pub fn hash(&self) -> Option<&[u8]> {
+        self.description.hash()
+    }
+
+    pub fn pname(&self) -> Option<&[u8]> {
+        self.description.pname()
+    }
+
+    pub fn version(&self) -> Option<&[u8]> {
+        self.description.version()
+    }
+
+    pub fn output_name(&self) -> Option<&[u8]> {
+        self.description.output_name()
+    }
+
+    pub fn root_kind(&self) -> Option<RootKind> {
+        self.description.root_kind()
+    }
+
+    pub fn looks_like_source(&self) -> bool {
+        self.description.looks_like_source()
+    }
+
+    /// whether this node is a valid store path or a node of nix-du, see
+    /// `NodeDescription::Invalid`.
+    pub fn is_valid(&self) -> bool {
+        self.kind() != NodeKind::Invalid
+    }
+
+    /// whether this node is a derivation (a `.drv` file), which is only in the
+    /// graph when read with `derivations`, see `DepInfos::read_from_store`.
+    pub fn is_derivation(&self) -> bool {
+        matches!(&self.description, NodeDescription::Path(path) if path.ends_with(b".drv"))
+    }
+
+    /// whether the name and the path of this node are valid UTF-8
+    pub fn is_utf8(&self) -> bool {
+        std::str::from_utf8(&self.name()).is_ok()
+            && self
+                .description
+                .path()
+                .and_then(|p| std::str::from_utf8(p).err())
+                .is_none()
+    }
 }
 
 impl fmt::Debug for DepNode {
@@ -328,6 +570,7 @@ impl fmt::Debug for DepNode {
 }
 
 /// Whether all nodes are reachable from the root
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Reachability {
     Connected,
@@ -335,12 +578,14 @@ pub enum Reachability {
 }
 
 /// Whether deduplicated nodes are counted several times
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DedupAwareness {
     Aware,
     Unaware,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone, Debug)]
 pub struct SizeMetadata {
     pub reachable: Reachability,
@@ -348,49 +593,268 @@ pub struct SizeMetadata {
     pub size: EnumMap<DedupAwareness, EnumMap<Reachability, Option<u64>>>,
 }
 
-pub type Edge = ();
+/// The weight of the edges of a `DepGraph`
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Edge {
+    /// for an edge from a store path to a `Shared` node, the number of bytes of the
+    /// hardlinked files that the store path shares through this node.
+    ///
+    /// `None` for other edges. Reductions which merge edges add up their weights,
+    /// see `Edge::merge`.
+    pub shared_size: Option<u64>,
+}
+
+impl Edge {
+    /// the weight of a single edge replacing edges of weight `self` and `other`
+    pub fn merge(self, other: Edge) -> Edge {
+        let shared_size = match (self.shared_size, other.shared_size) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+        Edge { shared_size }
+    }
+}
+
+/// Adds an edge of weight `weight` from `from` to `to`, or merges `weight` into the
+/// existing one if there is already such an edge.
+pub fn merge_edge(graph: &mut DepGraph, from: NodeIndex, to: NodeIndex, weight: Edge) {
+    match graph.find_edge(from, to) {
+        Some(e) => graph[e] = graph[e].merge(weight),
+        None => {
+            graph.add_edge(from, to, weight);
+        }
+    }
+}
 
 pub type DepGraph = petgraph::graph::Graph<DepNode, Edge, petgraph::Directed>;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone)]
 pub struct DepInfos {
     pub graph: DepGraph,
     pub root: NodeIndex,
     pub metadata: SizeMetadata,
+    /// When the store is not at its usual location, the directory on the filesystem
+    /// which plays the role of `/` for store paths.
+    pub store_root: Option<PathBuf>,
+}
+
+/// Overall figures about a graph, see `DepInfos::summary`.
+///
+/// Displays as a one line human readable summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub nodes: usize,
+    pub edges: usize,
+    /// the sum of the size of all nodes
+    pub total_size: u64,
+    /// the size of what is reachable from the root
+    pub reachable_size: u64,
+    /// the size of what is not reachable from the root, ie. what the garbage
+    /// collector would free
+    pub unreachable_size: u64,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use crate::msg::format_size;
+        write!(
+            f,
+            "{} nodes, {} edges: {} in total, {} alive, {} reclaimable",
+            self.nodes,
+            self.edges,
+            format_size(self.total_size),
+            format_size(self.reachable_size),
+            format_size(self.unreachable_size)
+        )
+    }
+}
+
+/// What libnix_adapter fills through `register_node` and `register_edge` when
+/// reading the store.
+#[derive(Debug, Default)]
+pub struct ReadContext {
+    pub graph: DepGraph,
+    /// gc roots, recorded as they are added so that we don't have to look them up
+    /// in the whole graph afterwards.
+    pub gc_roots: Vec<NodeIndex>,
 }
 
 // symbol exported to libnix_adapter
 /// # Safety
-/// `g` must have been obtained by rust code, and not modified by C code.
+/// `ctx` must have been obtained by rust code, and not modified by C code.
 /// `p` must be a valid pointer and contain no null pointer members.
 /// Its `path` field must contain a valid C string.
 #[no_mangle]
-pub unsafe extern "C" fn register_node(g: *mut DepGraph, p: *const bindings::path_t) {
+pub unsafe extern "C" fn register_node(ctx: *mut ReadContext, p: *const bindings::path_t) {
     let p: &bindings::path_t = p.as_ref().unwrap();
-    let g: &mut DepGraph = g.as_mut().unwrap();
+    let ctx: &mut ReadContext = ctx.as_mut().unwrap();
     let drv = DepNode::new(p);
-    g.add_node(drv);
+    let is_gc_root = drv.kind().is_gc_root();
+    let idx = ctx.graph.add_node(drv);
+    if is_gc_root {
+        ctx.gc_roots.push(idx);
+    }
 }
 
 // symbol exported to libnix_adapter
 /// # Safety
-/// `g` must have been obtained by rust code, and not modified by C code.
+/// `ctx` must have been obtained by rust code, and not modified by C code.
 #[no_mangle]
-pub unsafe extern "C" fn register_edge(g: *mut DepGraph, from: u32, to: u32) {
+pub unsafe extern "C" fn register_edge(ctx: *mut ReadContext, from: u32, to: u32) {
     if from == to {
         return;
     }
-    let g: &mut DepGraph = g.as_mut().unwrap();
-    g.add_edge(NodeIndex::from(from), NodeIndex::from(to), ());
+    let ctx: &mut ReadContext = ctx.as_mut().unwrap();
+    ctx.graph
+        .add_edge(NodeIndex::from(from), NodeIndex::from(to), Edge::default());
+}
+
+/// Why reading the store failed.
+///
+/// By then, nix has already printed the details on stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError {
+    /// the store could not be opened, for example the daemon is not running
+    ConnectionFailed,
+    /// some file or socket could not be accessed
+    PermissionDenied,
+    /// any other error, with the exit status nix would have used
+    Unknown(i32),
+}
+
+impl StoreError {
+    /// interprets the return value of `populateGraph`, which must not be 0
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            c if c == bindings::NIX_DU_CONNECTION_FAILED as i32 => StoreError::ConnectionFailed,
+            c if c == bindings::NIX_DU_PERMISSION_DENIED as i32 => StoreError::PermissionDenied,
+            c => StoreError::Unknown(c),
+        }
+    }
+
+    /// returns the raw return value of `populateGraph`, suitable as an exit status
+    pub fn code(self) -> i32 {
+        match self {
+            StoreError::ConnectionFailed => bindings::NIX_DU_CONNECTION_FAILED as i32,
+            StoreError::PermissionDenied => bindings::NIX_DU_PERMISSION_DENIED as i32,
+            StoreError::Unknown(c) => c,
+        }
+    }
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::ConnectionFailed => write!(f, "could not open the store"),
+            StoreError::PermissionDenied => write!(f, "permission denied"),
+            StoreError::Unknown(c) => write!(f, "nix failed with status {}", c),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// an error for input which cannot be read as a graph: dumps, DOT or JSON
+pub(crate) fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
 }
 
 impl DepInfos {
+    /// returns the version of the nix libraries used to read the store, for bug
+    /// reports.
+    pub fn backend_version() -> String {
+        // a static string of nix
+        unsafe { CStr::from_ptr(bindings::backendVersion()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
     /// returns the dependency graph of the nix-store
     /// actual connection specifics are left to libnixstore
     /// (reading ourselves, connecting to a daemon...)
-    pub fn read_from_store(root: Option<OsString>) -> Result<Self, i32> {
-        let mut g = DepGraph::new();
-        let gptr = &mut g as *mut _ as *mut c_void;
+    ///
+    /// Without `root`, all the valid paths of the store are read, or only those
+    /// reachable from gc roots if `reachable_only` is set. The latter is faster on a
+    /// large store, but then the size of dead paths is not known.
+    ///
+    /// With `derivations`, the derivation of each path and its closure, the
+    /// build-time dependencies, are read too, as if `keep-derivations` was set in
+    /// `nix.conf`: paths keep their derivation alive. See `DepNode::is_derivation`.
+    pub fn read_from_store(
+        root: Option<OsString>,
+        reachable_only: bool,
+        derivations: bool,
+    ) -> Result<Self, StoreError> {
+        Self::read(root, None, reachable_only, derivations)
+    }
+
+    /// like `read_from_store`, but for the store at `store_uri` instead of the
+    /// default one, for example `daemon` or `local?root=/mnt`. See
+    /// `nix help-stores` for the possible values.
+    pub fn read_from_store_uri(
+        store_uri: OsString,
+        root: Option<OsString>,
+        reachable_only: bool,
+        derivations: bool,
+    ) -> Result<Self, StoreError> {
+        Self::read(root, Some(store_uri), reachable_only, derivations)
+    }
+
+    /// returns the dependency graph of the nix-store located in `store_dir` on the
+    /// filesystem, for example a store copied from another machine.
+    ///
+    /// `store_dir` is the directory containing `nix/store`, it is passed to libnixstore
+    /// as the `root` of a local store, without going through the daemon.
+    /// Store paths keep their logical location (typically `/nix/store/...`) and
+    /// `store_root` is set so that the functions accessing the filesystem look in
+    /// `store_dir` instead.
+    pub fn read_from_store_at(
+        store_dir: &std::path::Path,
+        root: Option<OsString>,
+        reachable_only: bool,
+        derivations: bool,
+    ) -> Result<Self, StoreError> {
+        let mut uri = OsString::from("local?root=");
+        uri.push(store_dir);
+        let mut di = Self::read(root, Some(uri), reachable_only, derivations)?;
+        di.store_root = Some(store_dir.to_owned());
+        Ok(di)
+    }
+
+    fn read(
+        root: Option<OsString>,
+        store_uri: Option<OsString>,
+        reachable_only: bool,
+        derivations: bool,
+    ) -> Result<Self, StoreError> {
+        Self::read_with(
+            bindings::populateGraph,
+            root,
+            store_uri,
+            reachable_only,
+            derivations,
+        )
+    }
+
+    /// does the work of `read`, with `populate` in place of `populateGraph` so that
+    /// tests can do without a store.
+    pub(crate) fn read_with(
+        populate: unsafe extern "C" fn(
+            *mut c_void,
+            *const c_char,
+            *const c_char,
+            c_int,
+            c_int,
+        ) -> c_int,
+        root: Option<OsString>,
+        store_uri: Option<OsString>,
+        reachable_only: bool,
+        derivations: bool,
+    ) -> Result<Self, StoreError> {
+        let mut ctx = ReadContext::default();
+        let ctxptr = &mut ctx as *mut _ as *mut c_void;
         let root_data = root.map(|path| {
             let mut bytes = path.into_vec();
             bytes.push(0);
@@ -400,18 +864,39 @@ impl DepInfos {
             None => std::ptr::null(),
             Some(path) => path.as_ptr(),
         };
-        let res = unsafe { bindings::populateGraph(gptr, rootptr as *const std::os::raw::c_char) };
+        let uri_data = store_uri.map(|uri| {
+            let mut bytes = uri.into_vec();
+            bytes.push(0);
+            bytes
+        });
+        let uriptr: *const u8 = match uri_data.as_ref() {
+            None => std::ptr::null(),
+            Some(uri) => uri.as_ptr(),
+        };
+        let res = unsafe {
+            populate(
+                ctxptr,
+                rootptr as *const c_char,
+                uriptr as *const c_char,
+                reachable_only as c_int,
+                derivations as c_int,
+            )
+        };
 
         if res != 0 {
-            return Err(res);
+            return Err(StoreError::from_code(res));
         }
+        let ReadContext {
+            graph: mut g,
+            gc_roots,
+        } = ctx;
         let root_idx = match &root_data {
             None => g.add_node(DepNode::dummy()),
             Some(_) => NodeIndex::from(0),
         };
         let reachable = match &root_data {
-            None => Reachability::Disconnected,
-            Some(_) => Reachability::Connected,
+            None if !reachable_only => Reachability::Disconnected,
+            _ => Reachability::Connected,
         };
         let metadata = SizeMetadata {
             reachable,
@@ -422,30 +907,171 @@ impl DepInfos {
             root: root_idx,
             graph: g,
             metadata,
+            store_root: None,
         };
         if root_data.is_none() {
-            let gc_roots: Vec<_> = di
-                .graph
-                .node_references()
-                .filter_map(|(idx, n)| {
-                    if n.kind().is_gc_root() {
-                        Some(idx)
-                    } else {
-                        None
-                    }
-                })
-                .collect();
             for root in gc_roots {
-                di.graph.add_edge(di.root, root, ());
+                di.graph.add_edge(di.root, root, Edge::default());
             }
         }
         di.record_metadata();
         Ok(di)
     }
 
+    /// builds a `DepInfos` from a graph of store paths without gc roots, for backends
+    /// which don't know them: the nodes which no other node references become the
+    /// roots, under a new dummy root.
+    pub fn rooted_at_unreferenced(mut graph: DepGraph) -> Self {
+        let mut referenced = fixedbitset::FixedBitSet::with_capacity(graph.node_count());
+        for edge in graph.raw_edges() {
+            referenced.insert(edge.target().index());
+        }
+        let n = graph.node_count();
+        let root = graph.add_node(DepNode::dummy());
+        for i in 0..n {
+            if !referenced.contains(i) {
+                graph.add_edge(root, NodeIndex::new(i), Edge::default());
+            }
+        }
+        let mut di = DepInfos {
+            graph,
+            root,
+            metadata: SizeMetadata {
+                // there could be cycles in forged input
+                reachable: Reachability::Disconnected,
+                dedup: DedupAwareness::Unaware,
+                size: enum_map! { _ => enum_map!{ _ => None }},
+            },
+            store_root: None,
+        };
+        di.record_metadata();
+        di
+    }
+
     /// returns the sum of the size of all the derivations reachable from the root
+    ///
+    /// Gc roots outside of the store have size 0, unless set by
+    /// `opt::RefineOptions::root_links`: only the store paths they point to count.
     pub fn reachable_size(&self) -> u64 {
-        let mut dfs = self.dfs();
+        self.closure_size(self.root)
+    }
+
+    /// like `reachable_size`, but visits the graph on rayon's thread pool, one
+    /// breadth first level at a time. Worth it on very large graphs only.
+    #[cfg(feature = "rayon")]
+    pub fn par_reachable_size(&self) -> u64 {
+        use rayon::prelude::*;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        let visited: Vec<AtomicU64> = (0..self.graph.node_count().div_ceil(64))
+            .map(|_| AtomicU64::new(0))
+            .collect();
+        // returns whether `idx` was not visited before
+        let visit = |idx: NodeIndex| {
+            let bit = 1 << (idx.index() % 64);
+            visited[idx.index() / 64].fetch_or(bit, Ordering::Relaxed) & bit == 0
+        };
+        visit(self.root);
+        let mut sum = self.graph[self.root].size;
+        let mut frontier = vec![self.root];
+        while !frontier.is_empty() {
+            let next: Vec<NodeIndex> = frontier
+                .par_iter()
+                .flat_map_iter(|&idx| self.graph.neighbors(idx).filter(|&child| visit(child)))
+                .collect();
+            sum += next
+                .par_iter()
+                .map(|&idx| self.graph[idx].size)
+                .sum::<u64>();
+            frontier = next;
+        }
+        sum
+    }
+
+    /// returns where the file at `path` in the store really is on the filesystem,
+    /// taking `store_root` into account.
+    pub fn on_disk(&self, path: &OsStr) -> PathBuf {
+        match &self.store_root {
+            None => PathBuf::from(path),
+            Some(root) => {
+                let path = std::path::Path::new(path);
+                root.join(path.strip_prefix("/").unwrap_or(path))
+            }
+        }
+    }
+
+    /// returns the store directory, typically `/nix/store`, inferred from the location
+    /// of the first store path.
+    pub fn store_dir(&self) -> Option<PathBuf> {
+        let drv = self
+            .graph
+            .raw_nodes()
+            .iter()
+            .find(|node| node.weight.kind() == NodeKind::Path)?;
+        let mut p = PathBuf::from(drv.weight.description.path_as_os_str()?);
+        if !p.pop() {
+            return None;
+        }
+        Some(p)
+    }
+
+    /// returns the directory where store optimisation keeps its hardlinks, inferred
+    /// from the location of the first store path, on the filesystem.
+    ///
+    /// There is no way in the nix api to get the linksDir field of a RemoteStore, so
+    /// we infer it from a store path instead.
+    pub fn links_dir(&self) -> Option<PathBuf> {
+        let mut p = self.on_disk(self.store_dir()?.as_os_str());
+        p.push(".links");
+        Some(p)
+    }
+
+    /// replaces the link of each gc root by the last link of its symlink chain, the
+    /// one pointing into the store, and keeps the former in `DepNode::link_path`.
+    ///
+    /// For example `/nix/var/nix/gcroots/auto/<hash>` usually points to a `result`
+    /// symlink or a profile generation, which is much more telling.
+    /// Links which cannot be read are left as is.
+    pub fn follow_root_links(&mut self) {
+        // symlinks are resolved by the kernel with this limit
+        const MAX_LINKS: usize = 40;
+        let store_dir = match self.store_dir() {
+            Some(p) => p,
+            None => return,
+        };
+        let roots: Vec<_> = self.roots().collect();
+        for idx in roots {
+            let original = match &self.graph[idx].description {
+                NodeDescription::Link(p) => PathBuf::from(OsStr::from_bytes(p)),
+                _ => continue,
+            };
+            let mut current = original.clone();
+            let mut resolved = false;
+            for _ in 0..MAX_LINKS {
+                let target = match std::fs::read_link(self.on_disk(current.as_os_str())) {
+                    Ok(target) => target,
+                    Err(_) => break,
+                };
+                let target = match current.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target,
+                };
+                if target.starts_with(&store_dir) {
+                    resolved = true;
+                    break;
+                }
+                current = target;
+            }
+            if resolved && current != original {
+                let node = &mut self.graph[idx];
+                node.description = NodeDescription::Link(current.into_os_string().into_vec());
+                node.link_path = Some(original.into_os_string().into_vec());
+            }
+        }
+    }
+
+    /// returns the sum of the size of all the derivations reachable from `node`
+    pub fn closure_size(&self, node: NodeIndex) -> u64 {
+        let mut dfs = Dfs::new(&self.graph, node);
         let mut sum = 0;
         while let Some(idx) = dfs.next(&self.graph) {
             sum += self.graph[idx].size;
@@ -453,11 +1079,66 @@ impl DepInfos {
         sum
     }
 
+    /// returns the closure size of each root.
+    ///
+    /// Derivations reachable from several roots are counted in each of them,
+    /// so the sum of these sizes may exceed `reachable_size()`.
+    pub fn closure_sizes(&self) -> Vec<(NodeIndex, u64)> {
+        self.roots()
+            .map(|idx| (idx, self.closure_size(idx)))
+            .collect()
+    }
+
+    /// returns the closure size of every node, like `closure_size` but sharing the
+    /// work between nodes, see `ClosureSizes`.
+    pub fn with_closure_sizes(&self) -> collections::HashMap<NodeIndex, u64> {
+        ClosureSizes::new(self).iter().collect()
+    }
+
+    /// returns the nodes which are not reachable from the root, in other words
+    /// what the garbage collector would delete.
+    pub fn unreachable(&self) -> Vec<NodeIndex> {
+        let mut dfs = self.dfs();
+        while dfs.next(&self.graph).is_some() {}
+        self.graph
+            .node_indices()
+            .filter(|idx| !dfs.discovered.contains(idx.index()))
+            .collect()
+    }
+
+    /// returns the sum of the size of the derivations not reachable from the root,
+    /// in other words an estimate of what the garbage collector would free.
+    pub fn unreachable_size(&self) -> u64 {
+        self.unreachable()
+            .into_iter()
+            .map(|idx| self.graph[idx].size)
+            .sum()
+    }
+
     /// returns the sum of the size of all the derivations
     pub fn size(&self) -> u64 {
         self.graph.raw_nodes().iter().map(|n| n.weight.size).sum()
     }
 
+    /// returns the total, reachable and unreachable sizes of the graph, along with
+    /// its number of nodes and edges.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            nodes: self.graph.node_count(),
+            edges: self.graph.edge_count(),
+            total_size: self.size(),
+            reachable_size: self.reachable_size(),
+            unreachable_size: self.unreachable_size(),
+        }
+    }
+
+    /// serializes the graph as JSON, see `json::render` for the format.
+    pub fn to_json(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        crate::json::render(self, &mut res).expect("writing to a Vec cannot fail");
+        res
+    }
+
     /// records the current size of the graph in its metadata field.
     pub fn record_metadata(&mut self) {
         let dedup = self.metadata.dedup;
@@ -476,47 +1157,1955 @@ impl DepInfos {
         }
     }
 
+    /// checks the invariants transforms are expected to maintain: the root and
+    /// the endpoints of edges are valid indices, there are no self-loops, the
+    /// total size fits in a `u64`, and the metadata matches the graph.
+    ///
+    /// Returns a description of each violated invariant.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let n = self.graph.node_count();
+        let mut errors = Vec::new();
+        if self.root.index() >= n {
+            errors.push(format!(
+                "root N{} is not a node of the graph ({} nodes)",
+                self.root.index(),
+                n
+            ));
+        }
+        for edge in self.graph.raw_edges() {
+            let (source, target) = (edge.source().index(), edge.target().index());
+            if source >= n || target >= n {
+                errors.push(format!(
+                    "edge N{} -> N{} has a dangling end",
+                    source, target
+                ));
+            } else if source == target {
+                errors.push(format!("self-loop on N{}", source));
+            }
+        }
+        let total = self
+            .graph
+            .raw_nodes()
+            .iter()
+            .try_fold(0u64, |acc, node| acc.checked_add(node.weight.size));
+        if total.is_none() {
+            errors.push("the total size overflows".to_owned());
+        }
+        if !errors.is_empty() {
+            // the checks below would panic
+            return Err(errors);
+        }
+        if self.metadata.reachable == Reachability::Connected {
+            let mut reached = 0;
+            let mut dfs = self.dfs();
+            while dfs.next(&self.graph).is_some() {
+                reached += 1;
+            }
+            if reached != n {
+                errors.push(format!(
+                    "metadata says the graph is connected, but {} nodes are unreachable",
+                    n - reached
+                ));
+            }
+        }
+        if let Some(size) = self.metadata.size[self.metadata.dedup][self.metadata.reachable] {
+            if Some(size) != total {
+                errors.push(format!(
+                    "metadata records a size of {} but the graph weighs {}",
+                    size,
+                    total.unwrap_or_default()
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// returns the paths of the store directory which are not valid, like the
+    /// outputs of interrupted builds, by index. They are only found when reading
+    /// all the paths of a local store.
+    pub fn invalid_paths(&self) -> Vec<NodeIndex> {
+        self.graph
+            .node_indices()
+            .filter(|&idx| self.graph[idx].kind() == NodeKind::Invalid)
+            .collect()
+    }
+
     /// returns a Dfs suitable to visit all reachable nodes.
     pub fn dfs(&self) -> Dfs<NodeIndex, fixedbitset::FixedBitSet> {
         petgraph::visit::Dfs::new(&self.graph, self.root)
     }
 
-    /// Returns the iterator of roots
-    pub fn roots(&self) -> petgraph::graph::Neighbors<(), u32> {
-        self.graph.neighbors(self.root)
+    /// returns all reachable nodes in depth first order, like `dfs`, but visiting
+    /// the children of each node sorted by description (so by path, mostly), so
+    /// that the order does not depend on how the graph was built.
+    ///
+    /// This is slower than `dfs`, use it only when the order matters.
+    pub fn dfs_sorted(&self) -> Vec<NodeIndex> {
+        let mut discovered = fixedbitset::FixedBitSet::with_capacity(self.graph.node_count());
+        let mut res = Vec::new();
+        let mut stack = vec![self.root];
+        let mut children = Vec::new();
+        while let Some(idx) = stack.pop() {
+            if discovered.put(idx.index()) {
+                continue;
+            }
+            res.push(idx);
+            children.extend(
+                self.graph
+                    .neighbors(idx)
+                    .filter(|child| !discovered.contains(child.index())),
+            );
+            children.sort_by(|&a, &b| {
+                (&self.graph[a].description, a).cmp(&(&self.graph[b].description, b))
+            });
+            // the smallest child is popped first
+            stack.extend(children.drain(..).rev());
+        }
+        res
     }
 
-    /// returns the set of paths of the roots
-    /// intended for testing mainly
-    #[cfg(test)]
-    pub fn roots_name(&self) -> collections::BTreeSet<String> {
-        self.roots()
-            .map(|idx| {
-                assert_ne!(idx, self.root);
-                String::from_utf8_lossy(&self.graph[idx].name()).into()
-            })
+    /// returns all the nodes in topological order, parents before their children,
+    /// even if the graph has cycles: strongly connected components are ordered
+    /// as a whole, and nodes within the same component have arbitrary relative
+    /// order.
+    pub fn topo_order(&self) -> Vec<NodeIndex> {
+        let indices = self.graph.map(|idx, _| idx, |_, _| ());
+        let condensed = petgraph::algo::condensation(indices, true);
+        let order = petgraph::algo::toposort(&condensed, None)
+            .expect("the condensation of a graph is acyclic");
+        order
+            .into_iter()
+            .flat_map(|component| condensed[component].iter().copied())
             .collect()
     }
 
-    /// checks metadata is consistent
-    #[cfg(test)]
-    pub fn check_metadata(&self) {
-        use self::Reachability::*;
-        if self.metadata.reachable == Connected {
-            let mut i = 0;
-            let mut dfs = self.dfs();
-            while let Some(_) = dfs.next(&self.graph) {
-                i += 1;
+    /// returns the strongly connected components of more than one node, ie. the
+    /// cycles of the graph, which nix should not allow. Each cycle is sorted, and
+    /// cycles are sorted by their first node.
+    pub fn cycles(&self) -> Vec<Vec<NodeIndex>> {
+        let mut res: Vec<_> = petgraph::algo::tarjan_scc(&self.graph)
+            .into_iter()
+            .filter(|scc| scc.len() > 1)
+            .map(|mut scc| {
+                scc.sort();
+                scc
+            })
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// visits the nodes reachable from the roots in breadth first order, and returns
+    /// them in this order, with for each node (by index) its depth and the node it
+    /// was first reached from. Roots have depth 0 and no parent, the root has
+    /// neither.
+    fn bfs_from_roots(&self) -> (Vec<NodeIndex>, Vec<Option<usize>>, Vec<Option<NodeIndex>>) {
+        let mut order = Vec::new();
+        let mut depth = vec![None; self.graph.node_count()];
+        let mut parent = vec![None; self.graph.node_count()];
+        for idx in self.roots() {
+            if depth[idx.index()].is_none() {
+                depth[idx.index()] = Some(0);
+                order.push(idx);
             }
-            assert_eq!(
-                i,
-                self.graph.node_count(),
-                "metadata indicates graph is connected, but it is not"
-            );
         }
-        let entry = &self.metadata.size[self.metadata.dedup];
-        if let Some(s) = entry[self.metadata.reachable] {
-            assert_eq!(s, self.size(), "wrong size in metadata");
+        let mut i = 0;
+        while i < order.len() {
+            let idx = order[i];
+            let d = depth[idx.index()].unwrap();
+            for child in self.graph.neighbors(idx) {
+                if depth[child.index()].is_none() && child != self.root {
+                    depth[child.index()] = Some(d + 1);
+                    parent[child.index()] = Some(idx);
+                    order.push(child);
+                }
+            }
+            i += 1;
+        }
+        (order, depth, parent)
+    }
+
+    /// returns the number of edges from the nearest root to each node, by index.
+    /// Roots have depth 0, and the root and unreachable nodes `None`.
+    pub fn depths(&self) -> Vec<Option<usize>> {
+        self.bfs_from_roots().1
+    }
+
+    /// removes the nodes of `removed` (by index) and their edges.
+    ///
+    /// Other nodes keep their relative order, but not their indices.
+    /// Removing the root is a bug.
+    pub fn remove_nodes(&mut self, removed: &fixedbitset::FixedBitSet) {
+        assert!(!removed.contains(self.root.index()), "removing the root");
+        let mut graph = DepGraph::with_capacity(
+            self.graph.node_count() - removed.count_ones(..),
+            self.graph.edge_count(),
+        );
+        let mut new_ids = vec![NodeIndex::end(); self.graph.node_count()];
+        for idx in self.graph.node_indices() {
+            if !removed.contains(idx.index()) {
+                let mut new_w = DepNode::dummy();
+                std::mem::swap(&mut self.graph[idx], &mut new_w);
+                new_ids[idx.index()] = graph.add_node(new_w);
+            }
+        }
+        for edge in self.graph.raw_edges() {
+            if !removed.contains(edge.source().index()) && !removed.contains(edge.target().index())
+            {
+                graph.add_edge(
+                    new_ids[edge.source().index()],
+                    new_ids[edge.target().index()],
+                    edge.weight,
+                );
+            }
+        }
+        self.root = new_ids[self.root.index()];
+        self.graph = graph;
+    }
+
+    /// merges the nodes with the same path, for example after combining the graphs
+    /// of two machines: the first one is kept, with the edges of the others, and
+    /// at most one edge between two nodes. Only store paths, gc roots and invalid
+    /// paths are merged, other nodes are left as is.
+    pub fn dedup_paths(&mut self) {
+        let mut first = collections::HashMap::new();
+        let mut kept: Vec<NodeIndex> = self.graph.node_indices().collect();
+        let mut removed = fixedbitset::FixedBitSet::with_capacity(self.graph.node_count());
+        for idx in self.graph.node_indices() {
+            if let Some(path) = self.graph[idx].description.identity() {
+                match first.entry(path.clone()) {
+                    collections::hash_map::Entry::Vacant(e) => {
+                        e.insert(idx);
+                    }
+                    collections::hash_map::Entry::Occupied(e) => {
+                        kept[idx.index()] = *e.get();
+                        removed.insert(idx.index());
+                    }
+                }
+            }
         }
+        if removed.count_ones(..) == 0 {
+            return;
+        }
+        let moved: Vec<_> = self
+            .graph
+            .raw_edges()
+            .iter()
+            .filter(|e| {
+                removed.contains(e.source().index()) || removed.contains(e.target().index())
+            })
+            .map(|e| (kept[e.source().index()], kept[e.target().index()], e.weight))
+            .collect();
+        for (from, to, weight) in moved {
+            if from != to {
+                self.graph.update_edge(from, to, weight);
+            }
+        }
+        self.remove_nodes(&removed);
+        self.metadata.size = enum_map! { _ => enum_map!{ _ => None }};
+        self.record_metadata();
+    }
+
+    /// returns the union of this graph and `other`, for example read from another
+    /// machine: nodes with the same path are merged (see `dedup_paths`), and so are
+    /// the dummy roots, so the roots of both graphs are roots of the result.
+    ///
+    /// A store path has the same size everywhere, so when sizes differ a warning is
+    /// printed and the size in `self` is kept, as well as its `store_root`.
+    pub fn merge(mut self, other: DepInfos) -> DepInfos {
+        let mut sizes: collections::HashMap<&Path, u64> = collections::HashMap::new();
+        for node in self.graph.node_weights() {
+            if let Some(path) = node.description.identity() {
+                sizes.entry(path).or_insert(node.size);
+            }
+        }
+        let mismatches = other
+            .graph
+            .node_weights()
+            .filter(|node| {
+                matches!(node.description.identity().and_then(|p| sizes.get(p)),
+                    Some(&size) if size != node.size)
+            })
+            .count();
+        if mismatches > 0 {
+            msg!(
+                "Warning: {} paths have different sizes in the merged graphs, keeping the first\n",
+                mismatches
+            );
+        }
+        let other_root = other.root;
+        let (nodes, edges) = other.graph.into_nodes_edges();
+        let new_ids: Vec<NodeIndex> = nodes
+            .into_iter()
+            .enumerate()
+            .map(|(i, node)| {
+                if i == other_root.index() {
+                    self.root
+                } else {
+                    self.graph.add_node(node.weight)
+                }
+            })
+            .collect();
+        for edge in edges {
+            self.graph.update_edge(
+                new_ids[edge.source().index()],
+                new_ids[edge.target().index()],
+                edge.weight,
+            );
+        }
+        self.dedup_paths();
+        self.metadata.size = enum_map! { _ => enum_map!{ _ => None }};
+        self.record_metadata();
+        self
+    }
+
+    /// keeps only nodes of size at least `min_size`, and all roots, see
+    /// `reduction::retain_above`.
+    ///
+    /// Requires that all nodes are reachable from the root.
+    /// `assert_eq!(di.metadata.reachable, Reachability::Connected);`
+    pub fn retain_above(&mut self, min_size: u64) {
+        let empty = DepInfos {
+            graph: DepGraph::new(),
+            root: self.root,
+            metadata: self.metadata.clone(),
+            store_root: None,
+        };
+        let di = std::mem::replace(self, empty);
+        *self = crate::reduction::retain_above(di, min_size);
+    }
+
+    /// removes the nodes more than `depth` edges away from the nearest root, and
+    /// adds instead below each node at depth `depth` a `{deeper}` node with the size
+    /// of what it leads to. A node reachable from several of them is counted in
+    /// the first one met in breadth first order only, so that the total size does
+    /// not change.
+    ///
+    /// Requires that all nodes are reachable from the root.
+    /// `assert_eq!(di.metadata.reachable, Reachability::Connected);`
+    pub fn truncate_depth(&mut self, depth: usize) {
+        assert_eq!(self.metadata.reachable, Reachability::Connected);
+        let (order, depths, parent) = self.bfs_from_roots();
+        // the node at depth `depth` each node comes from
+        let mut frontier = vec![NodeIndex::end(); self.graph.node_count()];
+        let mut deeper = collections::BTreeMap::new();
+        let mut removed = fixedbitset::FixedBitSet::with_capacity(self.graph.node_count());
+        for idx in order {
+            let d = depths[idx.index()].unwrap();
+            if d == depth {
+                frontier[idx.index()] = idx;
+            } else if d > depth {
+                let f = frontier[parent[idx.index()].unwrap().index()];
+                frontier[idx.index()] = f;
+                *deeper.entry(f).or_insert(0) += self.graph[idx].size;
+                removed.insert(idx.index());
+            }
+        }
+        if removed.count_ones(..) == 0 {
+            return;
+        }
+        for (f, size) in deeper {
+            let node = self.graph.add_node(DepNode {
+                description: NodeDescription::Deeper,
+                size,
+                link_path: None,
+            });
+            self.graph.add_edge(f, node, Edge::default());
+        }
+        removed.grow(self.graph.node_count());
+        self.remove_nodes(&removed);
+    }
+
+    /// Returns the iterator of roots
+    pub fn roots(&self) -> petgraph::graph::Neighbors<Edge, u32> {
+        self.graph.neighbors(self.root)
+    }
+
+    /// returns the roots of kind `kind`, see `NodeDescription::root_kind`
+    pub fn roots_of_kind(&self, kind: RootKind) -> Vec<NodeIndex> {
+        self.roots()
+            .filter(|&idx| self.graph[idx].root_kind() == Some(kind))
+            .collect()
+    }
+
+    /// returns the `n` largest nodes and their size, largest first. Ties are broken
+    /// by index.
+    ///
+    /// Unless `include_roots` is set, gc roots, including transient ones, are not
+    /// considered. The dummy root never is.
+    pub fn largest(&self, n: usize, include_roots: bool) -> Vec<(NodeIndex, u64)> {
+        let mut nodes: Vec<_> = self
+            .graph
+            .node_indices()
+            .filter(|&idx| idx != self.root)
+            .filter(|&idx| include_roots || !self.graph[idx].kind().is_gc_root())
+            .map(|idx| (idx, self.graph[idx].size))
+            .collect();
+        let key = |&(idx, size): &(NodeIndex, u64)| (std::cmp::Reverse(size), idx);
+        if n < nodes.len() {
+            // only sort what we return
+            nodes.select_nth_unstable_by_key(n, key);
+            nodes.truncate(n);
+        }
+        nodes.sort_unstable_by_key(key);
+        nodes
+    }
+
+    /// returns all nodes with their closure size, largest closure first, unlike
+    /// `largest` which compares the size of nodes themselves. Ties are broken by
+    /// index, and the dummy root is not returned.
+    ///
+    /// `closure_sizes` are those of this graph, see `ClosureSizes`.
+    pub fn by_closure_size_desc(
+        &self,
+        closure_sizes: &ClosureSizes,
+    ) -> impl Iterator<Item = (NodeIndex, u64)> {
+        let mut nodes: Vec<_> = closure_sizes
+            .iter()
+            .filter(|&(idx, _)| idx != self.root)
+            .collect();
+        nodes.sort_unstable_by_key(|&(idx, size)| (std::cmp::Reverse(size), idx));
+        nodes.into_iter()
+    }
+
+    /// returns the roots from which `node` is reachable, ie. the roots keeping it alive,
+    /// sorted by index.
+    pub fn roots_holding(&self, node: NodeIndex) -> Vec<NodeIndex> {
+        let ancestors = self.reaching(node);
+        let mut res: Vec<_> = self
+            .roots()
+            .filter(|idx| ancestors.contains(idx.index()))
+            .collect();
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    /// the nodes from which `node` is reachable, including itself, by index
+    fn reaching(&self, node: NodeIndex) -> fixedbitset::FixedBitSet {
+        let reversed = petgraph::visit::Reversed(&self.graph);
+        let mut dfs = Dfs::new(reversed, node);
+        let mut res = fixedbitset::FixedBitSet::with_capacity(self.graph.node_count());
+        while let Some(idx) = dfs.next(reversed) {
+            res.insert(idx.index());
+        }
+        res
+    }
+
+    /// returns the nodes with an edge to `node`, ie. which depend on it directly,
+    /// sorted by index.
+    pub fn parents(&self, node: NodeIndex) -> Vec<NodeIndex> {
+        let mut res: Vec<_> = self
+            .graph
+            .neighbors_directed(node, petgraph::Direction::Incoming)
+            .collect();
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    /// returns the nodes from which `node` is reachable, ie. which depend on it
+    /// directly or not, sorted by index. This includes the dummy root if `node` is
+    /// alive, but not `node` itself, even in a cycle.
+    pub fn ancestors(&self, node: NodeIndex) -> Vec<NodeIndex> {
+        let mut ancestors = self.reaching(node);
+        ancestors.set(node.index(), false);
+        ancestors.ones().map(NodeIndex::new).collect()
+    }
+
+    /// returns a small set of roots whose closures cover all the nodes reachable from
+    /// the roots, in the order they were chosen: greedily, the root adding the most
+    /// size not yet covered first. Roots whose closure is included in that of the
+    /// others are omitted.
+    ///
+    /// This is an approximation, the smallest such set is expensive to compute.
+    /// The closure of each root is kept in memory at once.
+    pub fn covering_roots(&self) -> Vec<NodeIndex> {
+        let n = self.graph.node_count();
+        let mut candidates: Vec<(NodeIndex, fixedbitset::FixedBitSet)> = self
+            .roots()
+            .map(|root| {
+                let mut dfs = Dfs::new(&self.graph, root);
+                while dfs.next(&self.graph).is_some() {}
+                (root, dfs.discovered)
+            })
+            .collect();
+        candidates.sort_by_key(|&(root, _)| root);
+        candidates.dedup_by_key(|&mut (root, _)| root);
+        let mut covered = fixedbitset::FixedBitSet::with_capacity(n);
+        let mut res = Vec::new();
+        loop {
+            // the gain of a root is the size it adds, then the number of nodes,
+            // for nodes of size 0
+            let best = candidates
+                .iter()
+                .enumerate()
+                .map(|(i, (_, closure))| {
+                    let (size, count) =
+                        closure
+                            .difference(&covered)
+                            .fold((0, 0), |(size, count), idx| {
+                                (size + self.graph[NodeIndex::new(idx)].size, count + 1)
+                            });
+                    (size, count, std::cmp::Reverse(i))
+                })
+                .max();
+            match best {
+                Some((size, count, std::cmp::Reverse(i))) if size > 0 || count > 0 => {
+                    let (root, closure) = candidates.remove(i);
+                    covered.union_with(&closure);
+                    res.push(root);
+                }
+                _ => return res,
+            }
+        }
+    }
+
+    /// returns, for each root, the size of its closure where each node counts for
+    /// its size divided by the number of roots keeping it alive. The remainder of
+    /// the division goes to the first of these roots, one byte each, so that the
+    /// sizes sum to that of the nodes reachable from the roots.
+    ///
+    /// This walks the closure of each root twice.
+    pub fn fair_closure_sizes(&self) -> collections::HashMap<NodeIndex, u64> {
+        let mut roots: Vec<_> = self.roots().collect();
+        roots.sort();
+        roots.dedup();
+        let mut holders = vec![0u64; self.graph.node_count()];
+        for &root in &roots {
+            let mut dfs = Dfs::new(&self.graph, root);
+            while let Some(idx) = dfs.next(&self.graph) {
+                holders[idx.index()] += 1;
+            }
+        }
+        // number of roots already given a share of each node
+        let mut served = vec![0u64; self.graph.node_count()];
+        let mut res = collections::HashMap::with_capacity(roots.len());
+        for root in roots {
+            let mut size = 0;
+            let mut dfs = Dfs::new(&self.graph, root);
+            while let Some(idx) = dfs.next(&self.graph) {
+                let i = idx.index();
+                let node_size = self.graph[idx].size;
+                size += node_size / holders[i];
+                if served[i] < node_size % holders[i] {
+                    size += 1;
+                }
+                served[i] += 1;
+            }
+            res.insert(root, size);
+        }
+        res
+    }
+
+    /// returns the size which would be freed by deleting the gc roots `roots`: the
+    /// sum of the sizes of the nodes reachable from `roots` but from no other root.
+    pub fn freed_by_deleting(&self, roots: &[NodeIndex]) -> u64 {
+        // nodes kept alive by other roots are marked as visited, so that the
+        // second walk does not enter them
+        let mut dfs = Dfs::empty(&self.graph);
+        for root in self.roots() {
+            if !roots.contains(&root) {
+                dfs.move_to(root);
+                while dfs.next(&self.graph).is_some() {}
+            }
+        }
+        let mut sum = 0;
+        for &root in roots {
+            dfs.move_to(root);
+            while let Some(idx) = dfs.next(&self.graph) {
+                sum += self.graph[idx].size;
+            }
+        }
+        sum
+    }
+
+    /// makes the node of `path` a root, as if it was a gc root, for example to see
+    /// what deleting other roots would free if it is kept. Nothing is written to
+    /// disk. Returns the node, or `None` if no node has this path.
+    ///
+    /// Pinned nodes are roots (see `roots`) as far as reachability is concerned,
+    /// but keep their kind, so `NodeKind::is_gc_root` is still false for them.
+    pub fn pin(&mut self, path: &[u8]) -> Option<NodeIndex> {
+        let idx = self.find_by_path(path)?;
+        if idx != self.root {
+            self.graph.update_edge(self.root, idx, Edge::default());
+            // what is alive may have grown
+            for entry in self.metadata.size.values_mut() {
+                entry[Reachability::Connected] = None;
+            }
+            self.record_metadata();
+        }
+        Some(idx)
+    }
+
+    /// returns the nodes reachable from `newer` but not from `older`, sorted by
+    /// index, and the sum of their sizes: for two generations of a profile, what
+    /// the newer one added.
+    pub fn closure_difference(&self, newer: NodeIndex, older: NodeIndex) -> (Vec<NodeIndex>, u64) {
+        // the closure of older is marked as visited, like in `freed_by_deleting`
+        let mut dfs = Dfs::new(&self.graph, older);
+        while dfs.next(&self.graph).is_some() {}
+        dfs.move_to(newer);
+        let mut res = Vec::new();
+        while let Some(idx) = dfs.next(&self.graph) {
+            res.push(idx);
+        }
+        res.sort();
+        let size = res.iter().map(|&idx| self.graph[idx].size).sum();
+        (res, size)
+    }
+
+    /// returns the sets of store paths which are outputs of the same package, ie.
+    /// which have the same `pname` and `version`, like `openssl-3.0.8` and
+    /// `openssl-3.0.8-dev`, along with the name of the package.
+    /// Only packages with at least two outputs are returned, sorted by name.
+    pub fn group_by_package(&self) -> Vec<(Vec<u8>, Vec<NodeIndex>)> {
+        self.group_by(|node| {
+            let mut name = node.pname()?.to_vec();
+            if let Some(version) = node.version() {
+                name.push(b'-');
+                name.extend_from_slice(version);
+            }
+            Some(name)
+        })
+    }
+
+    /// returns the sets of nodes with the same `pname`, whatever their version and
+    /// output, along with this name. Only names shared by at least two nodes are
+    /// returned, sorted by name.
+    pub fn group_by_pname(&self) -> Vec<(Vec<u8>, Vec<NodeIndex>)> {
+        self.group_by(|node| node.pname().map(<[u8]>::to_vec))
+    }
+
+    /// returns the sets of nodes with the same `key`, along with it, sorted by key.
+    /// Nodes whose key is `None` are not grouped, and nor are keys of a single node.
+    fn group_by(
+        &self,
+        key: impl Fn(&DepNode) -> Option<Vec<u8>>,
+    ) -> Vec<(Vec<u8>, Vec<NodeIndex>)> {
+        let mut groups = collections::BTreeMap::new();
+        for idx in self.graph.node_indices() {
+            if let Some(key) = key(&self.graph[idx]) {
+                groups.entry(key).or_insert_with(Vec::new).push(idx);
+            }
+        }
+        groups
+            .into_iter()
+            .filter(|(_, nodes)| nodes.len() > 1)
+            .collect()
+    }
+
+    /// returns the node whose path is exactly `path`, see `NodeDescription::path`.
+    ///
+    /// This is a linear scan, to query many paths build a `PathIndex` instead.
+    pub fn find_by_path(&self, path: &[u8]) -> Option<NodeIndex> {
+        self.graph
+            .node_indices()
+            .find(|&idx| self.graph[idx].description.path().map(Vec::as_slice) == Some(path))
+    }
+
+    /// returns the set of paths of the roots
+    /// intended for testing mainly
+    #[cfg(test)]
+    pub fn roots_name(&self) -> collections::BTreeSet<String> {
+        self.roots()
+            .map(|idx| {
+                assert_ne!(idx, self.root);
+                String::from_utf8_lossy(&self.graph[idx].name()).into()
+            })
+            .collect()
+    }
+
+    /// builds a graph whose root is a dummy node pointing to `roots`.
+    /// Nodes are `(name, size)` pairs and become store paths
+    /// `/nix/store/<fake hash>-name`, edges refer to the indices in `nodes`.
+    /// intended for testing mainly
+    #[cfg(test)]
+    pub fn from_spec(nodes: &[(&str, u64)], edges: &[(u32, u32)], roots: &[u32]) -> Self {
+        let mut graph = DepGraph::new();
+        for &(name, size) in nodes {
+            let path = format!("/nix/store/{:032}-{}", graph.node_count(), name);
+            graph.add_node(DepNode {
+                description: NodeDescription::Path(path.into_bytes()),
+                size,
+                link_path: None,
+            });
+        }
+        for &(from, to) in edges {
+            graph.add_edge(NodeIndex::from(from), NodeIndex::from(to), Edge::default());
+        }
+        let root = graph.add_node(DepNode::dummy());
+        for &idx in roots {
+            graph.add_edge(root, NodeIndex::from(idx), Edge::default());
+        }
+        let mut di = DepInfos {
+            graph,
+            root,
+            metadata: SizeMetadata {
+                reachable: Reachability::Disconnected,
+                dedup: DedupAwareness::Unaware,
+                size: enum_map! { _ => enum_map!{ _ => None }},
+            },
+            store_root: None,
+        };
+        let mut dfs = di.dfs();
+        let mut reached = 0;
+        while dfs.next(&di.graph).is_some() {
+            reached += 1;
+        }
+        if reached == di.graph.node_count() {
+            di.metadata.reachable = Reachability::Connected;
+        }
+        di.record_metadata();
+        di
+    }
+
+    /// a small graph exercising what output formats must escape, shared by their
+    /// tests: `a -> b -> c`, `a -> c`, a gc root link whose path is not valid
+    /// UTF-8 pointing to `c`, and a node for files of `c` shared with other paths.
+    /// `a` and the link are roots, the dummy root is node 3.
+    #[cfg(test)]
+    pub fn fixture() -> Self {
+        let mut di = DepInfos::from_spec(
+            &[("a", 1), ("b\"<&>,\\\n", 2), ("c", 4)],
+            &[(0, 1), (0, 2), (1, 2)],
+            &[0],
+        );
+        let link = di.graph.add_node(DepNode {
+            description: NodeDescription::Link(b"/roots/\xff\x01".to_vec()),
+            size: 8,
+            link_path: None,
+        });
+        di.graph.add_edge(di.root, link, Edge::default());
+        di.graph.add_edge(link, NodeIndex::new(2), Edge::default());
+        let shared = di.graph.add_node(DepNode {
+            description: NodeDescription::Shared(b"shared:c".to_vec()),
+            size: 16,
+            link_path: None,
+        });
+        di.graph.add_edge(
+            NodeIndex::new(2),
+            shared,
+            Edge {
+                shared_size: Some(16),
+            },
+        );
+        di.metadata.size = enum_map! { _ => enum_map!{ _ => None }};
+        di.record_metadata();
+        di
+    }
+
+    /// checks metadata is consistent
+    #[cfg(test)]
+    pub fn check_metadata(&self) {
+        use self::Reachability::*;
+        if self.metadata.reachable == Connected {
+            let mut i = 0;
+            let mut dfs = self.dfs();
+            while let Some(_) = dfs.next(&self.graph) {
+                i += 1;
+            }
+            assert_eq!(
+                i,
+                self.graph.node_count(),
+                "metadata indicates graph is connected, but it is not"
+            );
+        }
+        let entry = &self.metadata.size[self.metadata.dedup];
+        if let Some(s) = entry[self.metadata.reachable] {
+            assert_eq!(s, self.size(), "wrong size in metadata");
+        }
+    }
+}
+
+/// An index of the nodes of a graph by path, to look up many paths in one graph.
+///
+/// It is not updated when the graph is modified.
+#[derive(Debug, Clone, Default)]
+pub struct PathIndex(collections::BTreeMap<Path, NodeIndex>);
+
+impl PathIndex {
+    /// indexes the nodes of `di` which have a path. When several nodes have the
+    /// same path, which can only happen after merging graphs, the first one wins,
+    /// like for `DepInfos::find_by_path`.
+    pub fn new(di: &DepInfos) -> Self {
+        let mut map = collections::BTreeMap::new();
+        for idx in di.graph.node_indices() {
+            if let Some(path) = di.graph[idx].description.path() {
+                map.entry(path.clone()).or_insert(idx);
+            }
+        }
+        PathIndex(map)
+    }
+
+    /// returns the node whose path is exactly `path`
+    pub fn get(&self, path: &[u8]) -> Option<NodeIndex> {
+        self.0.get(path).copied()
+    }
+}
+
+/// The closure size of every node of a graph, computed once, to be looked up by
+/// several queries.
+///
+/// The reachable set of each strongly connected component is the union of
+/// those of its successors, which are computed first. This takes memory
+/// quadratic in the number of components, so it is meant for graphs already
+/// reduced for output, not the whole store. It is not updated when the graph is
+/// modified.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClosureSizes(Vec<u64>);
+
+impl ClosureSizes {
+    /// computes the closure size of all the nodes of `di`
+    pub fn new(di: &DepInfos) -> Self {
+        // in reverse topological order: successors of a component come before it
+        let sccs = petgraph::algo::tarjan_scc(&di.graph);
+        let mut component = vec![0; di.graph.node_count()];
+        for (i, scc) in sccs.iter().enumerate() {
+            for idx in scc {
+                component[idx.index()] = i;
+            }
+        }
+        let own_size: Vec<u64> = sccs
+            .iter()
+            .map(|scc| scc.iter().map(|&idx| di.graph[idx].size).sum())
+            .collect();
+        let mut reachable: Vec<fixedbitset::FixedBitSet> = Vec::with_capacity(sccs.len());
+        let mut res = vec![0; di.graph.node_count()];
+        for (i, scc) in sccs.iter().enumerate() {
+            let mut set = fixedbitset::FixedBitSet::with_capacity(sccs.len());
+            set.insert(i);
+            for &idx in scc {
+                for child in di.graph.neighbors(idx) {
+                    let j = component[child.index()];
+                    if j != i {
+                        set.union_with(&reachable[j]);
+                    }
+                }
+            }
+            let size = set.ones().map(|j| own_size[j]).sum();
+            for &idx in scc {
+                res[idx.index()] = size;
+            }
+            reachable.push(set);
+        }
+        ClosureSizes(res)
+    }
+
+    /// returns the closure size of `node`, like `DepInfos::closure_size`
+    pub fn get(&self, node: NodeIndex) -> u64 {
+        self.0[node.index()]
+    }
+
+    /// iterates over all nodes with their closure size, by index
+    pub fn iter(&self) -> impl Iterator<Item = (NodeIndex, u64)> + '_ {
+        self.0
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| (NodeIndex::new(i), size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+    use petgraph::visit::IntoNodeReferences;
+
+    /// two roots a and b depending on c
+    fn diamond() -> DepInfos {
+        DepInfos::from_spec(&[("a", 1), ("b", 2), ("c", 4)], &[(0, 2), (1, 2)], &[0, 1])
+    }
+
+    #[test]
+    fn closure_sizes() {
+        let di = diamond();
+        assert_eq!(di.closure_size(NodeIndex::from(0)), 5);
+        assert_eq!(di.closure_size(NodeIndex::from(1)), 6);
+        assert_eq!(di.closure_size(NodeIndex::from(2)), 4);
+        let mut sizes = di.closure_sizes();
+        sizes.sort();
+        assert_eq!(
+            sizes,
+            vec![(NodeIndex::from(0), 5), (NodeIndex::from(1), 6)]
+        );
+    }
+
+    #[test]
+    fn with_closure_sizes() {
+        let di = DepInfos::from_spec(&[("a", 1), ("b", 2), ("c", 4)], &[(0, 1), (1, 2)], &[0]);
+        let sizes = di.with_closure_sizes();
+        assert_eq!(sizes.len(), di.graph.node_count());
+        assert_eq!(sizes[&NodeIndex::from(0)], 7);
+        assert_eq!(sizes[&NodeIndex::from(1)], 6);
+        assert_eq!(sizes[&NodeIndex::from(2)], 4);
+        // shared dependencies are counted once, and cycles are fine
+        let mut di = diamond();
+        di.graph
+            .add_edge(NodeIndex::from(2), NodeIndex::from(1), Edge::default());
+        for (idx, size) in di.with_closure_sizes() {
+            assert_eq!(size, di.closure_size(idx), "{:?}", idx);
+        }
+    }
+
+    #[test]
+    fn closure_sizes_cache() {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            let n: usize = rng.gen_range(1..200);
+            let names: Vec<_> = (0..n).map(|i| i.to_string()).collect();
+            let nodes: Vec<_> = names
+                .iter()
+                .map(|name| (name.as_str(), rng.gen_range(0..1 << 40)))
+                .collect();
+            // edges only go to higher indices, so this is a DAG
+            let edges: Vec<_> = (0..2 * n)
+                .filter_map(|_| {
+                    let a = rng.gen_range(0..n);
+                    let b = rng.gen_range(0..n);
+                    (a < b).then_some((a as u32, b as u32))
+                })
+                .collect();
+            let di = DepInfos::from_spec(&nodes, &edges, &[0]);
+            let sizes = ClosureSizes::new(&di);
+            assert_eq!(sizes.iter().count(), di.graph.node_count());
+            for idx in di.graph.node_indices() {
+                assert_eq!(sizes.get(idx), di.closure_size(idx), "{:?}", idx);
+            }
+        }
+    }
+
+    #[test]
+    fn backend_version() {
+        let version = DepInfos::backend_version();
+        assert!(
+            version.starts_with(|c: char| c.is_ascii_digit()),
+            "{}",
+            version
+        );
+    }
+
+    #[test]
+    fn dedup_paths() {
+        // b and its copy b' are roots, a -> b -> d, c -> b' -> e
+        let mut di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("b'", 2), ("d", 8), ("e", 16)],
+            &[(0, 1), (1, 4), (2, 3), (3, 5)],
+            &[0, 1, 2, 3],
+        );
+        di.graph[NodeIndex::new(3)].description = di.graph[NodeIndex::new(1)].description.clone();
+        di.dedup_paths();
+        di.check_metadata();
+        assert_eq!(di.graph.node_count(), 6);
+        assert_eq!(di.reachable_size(), 1 + 2 + 4 + 8 + 16);
+        let b = di
+            .find_by_path(&format!("/nix/store/{:032}-b", 1).into_bytes())
+            .unwrap();
+        let names = |nodes: Vec<NodeIndex>| {
+            let mut names: Vec<_> = nodes
+                .into_iter()
+                .map(|idx| String::from_utf8(di.graph[idx].name().into_owned()).unwrap())
+                .collect();
+            names.sort();
+            names
+        };
+        let parents = di
+            .graph
+            .neighbors_directed(b, petgraph::Direction::Incoming)
+            .collect();
+        assert_eq!(names(parents), vec!["a", "c", "{dummy}"]);
+        assert_eq!(names(di.graph.neighbors(b).collect()), vec!["d", "e"]);
+        assert_eq!(di.roots().filter(|&idx| idx == b).count(), 1);
+
+        let before = di.clone();
+        di.dedup_paths();
+        assert_eq!(di.graph.node_count(), before.graph.node_count());
+        assert_eq!(di.graph.edge_count(), before.graph.edge_count());
+    }
+
+    #[test]
+    fn dedup_paths_keeps_shared() {
+        // a and b each share different files under the same name
+        let mut di = DepInfos::from_spec(&[("a", 1), ("b", 2)], &[], &[0, 1]);
+        for (owner, size) in [(0, 16), (1, 32)] {
+            let shared = di.graph.add_node(DepNode {
+                description: NodeDescription::Shared(b"shared:c".to_vec()),
+                size,
+                link_path: None,
+            });
+            di.graph.add_edge(
+                NodeIndex::new(owner),
+                shared,
+                Edge {
+                    shared_size: Some(size),
+                },
+            );
+        }
+        di.dedup_paths();
+        assert_eq!(di.graph.node_count(), 5);
+        assert_eq!(di.reachable_size(), 1 + 2 + 16 + 32);
+    }
+
+    #[test]
+    fn merge() {
+        // a -> b -> c here, d -> b -> c there
+        // a and d also share unrelated files under the same name
+        let mut here =
+            DepInfos::from_spec(&[("a", 1), ("b", 2), ("c", 4)], &[(0, 1), (1, 2)], &[0]);
+        let mut there =
+            DepInfos::from_spec(&[("d", 8), ("b", 2), ("c", 4)], &[(0, 1), (1, 2)], &[0]);
+        // paths depend on the index in from_spec
+        for i in 1..3 {
+            there.graph[NodeIndex::new(i)].description =
+                here.graph[NodeIndex::new(i)].description.clone();
+        }
+        for (di, size) in [(&mut here, 16), (&mut there, 32)] {
+            let shared = di.graph.add_node(DepNode {
+                description: NodeDescription::Shared(b"shared:x".to_vec()),
+                size,
+                link_path: None,
+            });
+            di.graph.add_edge(
+                NodeIndex::new(0),
+                shared,
+                Edge {
+                    shared_size: Some(size),
+                },
+            );
+        }
+        let merged = here.merge(there);
+        merged.check_metadata();
+        // one dummy root, a, b, c, d and both shared nodes
+        assert_eq!(merged.graph.node_count(), 1 + 4 + 2);
+        assert_eq!(merged.graph.edge_count(), 2 + 3 + 2);
+        assert_eq!(merged.reachable_size(), 1 + 2 + 4 + 8 + 16 + 32);
+        let mut roots: Vec<_> = merged
+            .roots()
+            .map(|idx| String::from_utf8(merged.graph[idx].name().into_owned()).unwrap())
+            .collect();
+        roots.sort();
+        assert_eq!(roots, vec!["a", "d"]);
+    }
+
+    #[test]
+    fn topo_order() {
+        // a -> b -> c -> d -> b is a cycle, e is dead
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 1), ("c", 1), ("d", 1), ("e", 1), ("f", 1)],
+            &[(0, 1), (1, 2), (2, 3), (3, 1), (2, 5), (4, 5)],
+            &[0],
+        );
+        let order = di.topo_order();
+        assert_eq!(order.len(), di.graph.node_count());
+        let mut position = vec![None; order.len()];
+        for (i, idx) in order.iter().enumerate() {
+            assert_eq!(position[idx.index()].replace(i), None, "{:?} twice", idx);
+        }
+        for edge in di.graph.raw_edges() {
+            let (from, to) = (edge.source().index(), edge.target().index());
+            // within the cycle, any order is valid
+            if ![1, 2, 3].contains(&from) || ![1, 2, 3].contains(&to) {
+                assert!(position[from] < position[to], "{} after {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn dfs_sorted() {
+        let spec: &[(&str, u64)] = &[("d", 1), ("b", 1), ("c", 1), ("a", 1), ("e", 1)];
+        let di = DepInfos::from_spec(spec, &[(0, 1), (0, 2), (2, 3), (1, 3)], &[0, 4]);
+        let name = |idx: NodeIndex| String::from_utf8(di.graph[idx].name().into_owned()).unwrap();
+        let order = di.dfs_sorted();
+        assert_eq!(order, di.dfs_sorted());
+        // all reachable nodes are visited once
+        assert_eq!(order.len(), di.graph.node_count());
+        let names: Vec<_> = order[1..].iter().map(|&idx| name(idx)).collect();
+        // paths are sorted by hash first, which from_spec derives from the index
+        assert_eq!(names, vec!["d", "b", "a", "c", "e"]);
+        // the same graph built in another order is visited in the same order
+        let mut reversed = DepInfos::from_spec(&[], &[], &[]);
+        let mut ids = std::collections::HashMap::new();
+        for &idx in order.iter().rev() {
+            if idx != di.root {
+                ids.insert(idx, reversed.graph.add_node(di.graph[idx].clone()));
+            }
+        }
+        ids.insert(di.root, reversed.root);
+        for edge in di.graph.raw_edges().iter().rev() {
+            reversed
+                .graph
+                .add_edge(ids[&edge.source()], ids[&edge.target()], Edge::default());
+        }
+        let names2: Vec<_> = reversed.dfs_sorted()[1..]
+            .iter()
+            .map(|&idx| String::from_utf8(reversed.graph[idx].name().into_owned()).unwrap())
+            .collect();
+        assert_eq!(names, names2);
+    }
+
+    #[test]
+    fn truncate_depth() {
+        // a -> b -> c -> d -> e, and a -> f -> d
+        let mut di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8), ("e", 16), ("f", 32)],
+            &[(0, 1), (1, 2), (2, 3), (3, 4), (0, 5), (5, 3)],
+            &[0],
+        );
+        let depths = di.depths();
+        assert_eq!(
+            depths,
+            vec![Some(0), Some(1), Some(2), Some(2), Some(3), Some(1), None]
+        );
+        let size = di.reachable_size();
+        di.truncate_depth(2);
+        di.check_metadata();
+        assert_eq!(di.reachable_size(), size);
+        let name = |idx: NodeIndex| String::from_utf8(di.graph[idx].name().into_owned()).unwrap();
+        let mut nodes: Vec<_> = di
+            .graph
+            .node_indices()
+            .map(|idx| (name(idx), di.graph[idx].size))
+            .collect();
+        nodes.sort();
+        // e is below d which is at depth 2
+        let expected: Vec<_> = [
+            ("a", 1),
+            ("b", 2),
+            ("c", 4),
+            ("d", 8),
+            ("f", 32),
+            ("{deeper}", 16),
+            ("{dummy}", 0),
+        ]
+        .iter()
+        .map(|&(n, s)| (n.to_string(), s))
+        .collect();
+        assert_eq!(nodes, expected);
+        let deeper = di
+            .graph
+            .node_indices()
+            .find(|&idx| di.graph[idx].kind() == NodeKind::Deeper)
+            .unwrap();
+        let parents: Vec<_> = di
+            .graph
+            .neighbors_directed(deeper, petgraph::Direction::Incoming)
+            .map(name)
+            .collect();
+        assert_eq!(parents, vec!["d"]);
+
+        // a chain of length 5
+        let mut chain = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8), ("e", 16)],
+            &[(0, 1), (1, 2), (2, 3), (3, 4)],
+            &[0],
+        );
+        chain.truncate_depth(2);
+        chain.check_metadata();
+        assert_eq!(chain.graph.node_count(), 3 + 1 + 1);
+        assert_eq!(chain.reachable_size(), 31);
+        let deeper = chain
+            .graph
+            .node_weights()
+            .find(|n| n.kind() == NodeKind::Deeper)
+            .unwrap();
+        assert_eq!(deeper.size, 8 + 16);
+        // nothing to truncate
+        let mut clone = chain.clone();
+        clone.truncate_depth(10);
+        assert_eq!(clone.graph.node_count(), chain.graph.node_count());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_reachable_size() {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let n: usize = rng.gen_range(1..2000);
+            let nodes: Vec<_> = (0..n)
+                .map(|i| (i.to_string(), rng.gen_range(0..1 << 40)))
+                .collect();
+            let nodes: Vec<_> = nodes
+                .iter()
+                .map(|(name, size)| (name.as_str(), *size))
+                .collect();
+            // with cycles, and some dead nodes
+            let edges: Vec<_> = (0..3 * n)
+                .map(|_| (rng.gen_range(0..n as u32), rng.gen_range(0..n as u32)))
+                .filter(|(a, b)| a != b)
+                .collect();
+            let roots: Vec<_> = (0..n as u32).filter(|_| rng.gen_ratio(1, 20)).collect();
+            let di = DepInfos::from_spec(&nodes, &edges, &roots);
+            assert_eq!(di.par_reachable_size(), di.reachable_size());
+        }
+    }
+
+    #[test]
+    fn summary() {
+        // d is not reachable
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8)],
+            &[(0, 1), (1, 2)],
+            &[0],
+        );
+        let summary = di.summary();
+        assert_eq!(
+            summary,
+            Summary {
+                // and the dummy root
+                nodes: 5,
+                edges: 3,
+                total_size: 15,
+                reachable_size: 7,
+                unreachable_size: 8,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "5 nodes, 3 edges: 15 B in total, 7 B alive, 8 B reclaimable"
+        );
+    }
+
+    #[test]
+    fn unreachable() {
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8)],
+            &[(0, 1), (2, 3)],
+            &[0],
+        );
+        assert_eq!(
+            di.unreachable(),
+            vec![NodeIndex::from(2), NodeIndex::from(3)]
+        );
+        assert_eq!(di.unreachable_size(), 12);
+        assert_eq!(di.unreachable_size() + di.reachable_size(), di.size());
+        assert!(diamond().unreachable().is_empty());
+    }
+
+    #[test]
+    fn links_dir() {
+        let mut di = diamond();
+        assert_eq!(di.links_dir(), Some(PathBuf::from("/nix/store/.links")));
+        di.store_root = Some(PathBuf::from("/mnt/other"));
+        assert_eq!(
+            di.links_dir(),
+            Some(PathBuf::from("/mnt/other/nix/store/.links"))
+        );
+        assert_eq!(
+            di.on_disk(OsStr::new("/nix/store/foo")),
+            PathBuf::from("/mnt/other/nix/store/foo")
+        );
+    }
+
+    #[test]
+    fn follow_root_links() {
+        use std::os::unix::fs::symlink;
+        let dir = std::env::temp_dir().join(format!(
+            "nix-du-test-follow_root_links-{}",
+            std::process::id()
+        ));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        for sub in &["store", "profiles", "gcroots/auto"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let drv = dir.join("store/0c0rv3yhhdbc4z4w0kwgl2ljn5j1yz8p-nixos-system");
+        std::fs::create_dir(&drv).unwrap();
+        symlink(&drv, dir.join("profiles/system-1-link")).unwrap();
+        // relative, like nix does for profiles
+        symlink("system-1-link", dir.join("profiles/system")).unwrap();
+        symlink(dir.join("profiles/system"), dir.join("gcroots/auto/abc")).unwrap();
+        symlink(dir.join("nowhere"), dir.join("gcroots/dangling")).unwrap();
+
+        let bytes = |p: PathBuf| p.into_os_string().into_vec();
+        let mut di = DepInfos::from_spec(&[("nixos-system", 1)], &[], &[]);
+        di.graph[NodeIndex::from(0)].description = NodeDescription::Path(bytes(drv));
+        let mut roots = Vec::new();
+        for link in &["gcroots/auto/abc", "gcroots/dangling"] {
+            let idx = di.graph.add_node(DepNode {
+                description: NodeDescription::Link(bytes(dir.join(link))),
+                size: 0,
+                link_path: None,
+            });
+            di.graph.add_edge(di.root, idx, Edge::default());
+            di.graph.add_edge(idx, NodeIndex::from(0), Edge::default());
+            roots.push(idx);
+        }
+        di.follow_root_links();
+        let resolved = &di.graph[roots[0]];
+        assert_eq!(
+            resolved.description,
+            NodeDescription::Link(bytes(dir.join("profiles/system-1-link")))
+        );
+        assert_eq!(
+            resolved.link_path,
+            Some(bytes(dir.join("gcroots/auto/abc")))
+        );
+        assert!(String::from_utf8_lossy(&resolved.name()).starts_with("NixOS generation 1"));
+        let dangling = &di.graph[roots[1]];
+        assert_eq!(
+            dangling.description,
+            NodeDescription::Link(bytes(dir.join("gcroots/dangling")))
+        );
+        assert_eq!(dangling.link_path, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash() {
+        let path = NodeDescription::Path(
+            b"/nix/store/0c0rv3yhhdbc4z4w0kwgl2ljn5j1yz8p-hello-2.12.1".to_vec(),
+        );
+        assert_eq!(path.hash(), Some(&b"0c0rv3yhhdbc4z4w0kwgl2ljn5j1yz8p"[..]));
+        assert_eq!(&*path.name(), b"hello-2.12.1");
+        let root = NodeDescription::Link(b"/nix/var/nix/profiles/system-12-link".to_vec());
+        assert_eq!(root.hash(), None);
+        let memory = NodeDescription::Memory(b"{memory:42}".to_vec());
+        assert_eq!(memory.hash(), None);
+        assert_eq!(NodeDescription::Transient.hash(), None);
+    }
+
+    #[test]
+    fn pname_version() {
+        let path = |name: &str| {
+            NodeDescription::Path(
+                format!("/nix/store/0c0rv3yhhdbc4z4w0kwgl2ljn5j1yz8p-{}", name).into_bytes(),
+            )
+        };
+        let glibc = path("glibc-2.37");
+        assert_eq!(glibc.pname(), Some(&b"glibc"[..]));
+        assert_eq!(glibc.version(), Some(&b"2.37"[..]));
+        let glibc = path("glibc-2.37-8");
+        assert_eq!(glibc.pname(), Some(&b"glibc"[..]));
+        assert_eq!(glibc.version(), Some(&b"2.37-8"[..]));
+        let openssl = path("openssl-3.0.8-dev");
+        assert_eq!(openssl.pname(), Some(&b"openssl"[..]));
+        assert_eq!(openssl.version(), Some(&b"3.0.8"[..]));
+        let hello = path("hello");
+        assert_eq!(hello.pname(), Some(&b"hello"[..]));
+        assert_eq!(hello.version(), None);
+        let python = path("python3.11-requests-2.31.0");
+        assert_eq!(python.pname(), Some(&b"python3.11-requests"[..]));
+        assert_eq!(python.version(), Some(&b"2.31.0"[..]));
+        let foo = path("foo-2-bar-1.0");
+        assert_eq!(foo.pname(), Some(&b"foo-2-bar"[..]));
+        assert_eq!(foo.version(), Some(&b"1.0"[..]));
+        let foo = path("foo-2-bar-1.0-lib");
+        assert_eq!(foo.pname(), Some(&b"foo-2-bar"[..]));
+        assert_eq!(foo.version(), Some(&b"1.0"[..]));
+        assert_eq!(foo.output_name(), Some(&b"lib"[..]));
+        let root = NodeDescription::Link(b"/nix/var/nix/profiles/system-12-link".to_vec());
+        assert_eq!(root.pname(), None);
+        assert_eq!(root.version(), None);
+        assert_eq!(root.output_name(), None);
+    }
+
+    #[test]
+    fn output_name() {
+        let path = |name: &str| {
+            NodeDescription::Path(
+                format!("/nix/store/0c0rv3yhhdbc4z4w0kwgl2ljn5j1yz8p-{}", name).into_bytes(),
+            )
+        };
+        assert_eq!(path("openssl-3.0.8-dev").output_name(), Some(&b"dev"[..]));
+        assert_eq!(path("openssl-3.0.8-bin").output_name(), Some(&b"bin"[..]));
+        assert_eq!(path("openssl-3.0.8").output_name(), None);
+        assert_eq!(path("glibc-2.37-8").output_name(), None);
+        assert_eq!(
+            path("xgcc-13.2.0-libgcc").output_name(),
+            Some(&b"libgcc"[..])
+        );
+        assert_eq!(path("hello").output_name(), None);
+    }
+
+    #[test]
+    fn looks_like_source() {
+        let path = |name: &str| {
+            NodeDescription::Path(
+                format!("/nix/store/0c0rv3yhhdbc4z4w0kwgl2ljn5j1yz8p-{}", name).into_bytes(),
+            )
+        };
+        assert!(path("nixpkgs-source").looks_like_source());
+        assert!(path("source").looks_like_source());
+        assert!(path("hello-2.12-src").looks_like_source());
+        assert!(path("hello-2.12.1.tar.gz").looks_like_source());
+        assert!(!path("hello-2.12.1").looks_like_source());
+        assert!(!path("resources").looks_like_source());
+        assert!(!NodeDescription::Link(b"/home/me/source".to_vec()).looks_like_source());
+    }
+
+    #[test]
+    fn group_by_package() {
+        let di = DepInfos::from_spec(
+            &[
+                ("openssl-3.0.8", 1),
+                ("openssl-3.0.8-dev", 1),
+                ("openssl-3.0.8-bin", 1),
+                ("openssl-1.1.1w", 1),
+                ("hello-2.12", 1),
+            ],
+            &[(0, 3), (1, 0), (2, 0)],
+            &[1, 2, 4],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(
+            di.group_by_package(),
+            vec![(b"openssl-3.0.8".to_vec(), vec![idx(0), idx(1), idx(2)])]
+        );
+    }
+
+    #[test]
+    fn group_by_pname() {
+        let di = DepInfos::from_spec(
+            &[
+                ("openssl-3.0.8", 1),
+                ("openssl-3.0.8-dev", 1),
+                ("openssl-1.1.1w", 1),
+                ("hello-2.12", 1),
+            ],
+            &[(1, 0)],
+            &[1, 2, 3],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(
+            di.group_by_pname(),
+            vec![(b"openssl".to_vec(), vec![idx(0), idx(1), idx(2)])]
+        );
+    }
+
+    #[test]
+    fn roots_holding() {
+        // roots a, b and c; a -> e -> d, b -> d; d is held by a and b
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 1), ("c", 1), ("d", 1), ("e", 1)],
+            &[(0, 4), (4, 3), (1, 3)],
+            &[0, 1, 2],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(di.roots_holding(idx(3)), vec![idx(0), idx(1)]);
+        assert_eq!(di.roots_holding(idx(4)), vec![idx(0)]);
+        assert_eq!(di.roots_holding(idx(2)), vec![idx(2)]);
+    }
+
+    #[test]
+    fn find_by_path() {
+        let mut di = diamond();
+        let c = format!("/nix/store/{:032}-c", 2).into_bytes();
+        let c = c.as_slice();
+        assert_eq!(di.graph[NodeIndex::from(2)].description.path().unwrap(), c);
+        // a duplicate, added last
+        di.graph.add_node(DepNode {
+            description: NodeDescription::Path(c.to_vec()),
+            size: 0,
+            link_path: None,
+        });
+        let index = PathIndex::new(&di);
+        assert_eq!(di.find_by_path(c), Some(NodeIndex::from(2)));
+        assert_eq!(index.get(c), Some(NodeIndex::from(2)));
+        let missing = &c[..c.len() - 1];
+        assert_eq!(di.find_by_path(missing), None);
+        assert_eq!(index.get(missing), None);
+    }
+
+    #[test]
+    fn closure_difference() {
+        // generations 1 and 2 share base -> lib, 1 has old and 2 has new
+        let di = DepInfos::from_spec(
+            &[
+                ("gen-1", 1),
+                ("gen-2", 2),
+                ("base", 4),
+                ("lib", 8),
+                ("old", 16),
+                ("new", 32),
+            ],
+            &[(0, 2), (1, 2), (2, 3), (0, 4), (1, 5)],
+            &[0, 1],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(
+            di.closure_difference(idx(1), idx(0)),
+            (vec![idx(1), idx(5)], 2 + 32)
+        );
+        assert_eq!(
+            di.closure_difference(idx(0), idx(1)),
+            (vec![idx(0), idx(4)], 1 + 16)
+        );
+        assert_eq!(di.closure_difference(idx(0), idx(0)), (vec![], 0));
+        // lib is below base
+        assert_eq!(di.closure_difference(idx(2), idx(3)), (vec![idx(2)], 4));
+    }
+
+    #[test]
+    fn freed_by_deleting() {
+        // roots a, b and c; a and c share d, b has its own e
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8), ("e", 16)],
+            &[(0, 3), (2, 3), (1, 4)],
+            &[0, 1, 2],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(di.freed_by_deleting(&[idx(0), idx(1)]), 1 + 2 + 16);
+        assert_eq!(di.freed_by_deleting(&[idx(0), idx(2)]), 1 + 4 + 8);
+        assert_eq!(di.freed_by_deleting(&[]), 0);
+        assert_eq!(
+            di.freed_by_deleting(&[idx(0), idx(1), idx(2)]),
+            di.reachable_size()
+        );
+    }
+
+    #[test]
+    fn pin() {
+        // root a -> b -> c, root d -> b, e is dead
+        let mut di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8), ("e", 16)],
+            &[(0, 1), (1, 2), (3, 1)],
+            &[0, 3],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(di.freed_by_deleting(&[idx(0), idx(3)]), 1 + 2 + 4 + 8);
+        let path = |di: &DepInfos, i| di.graph[idx(i)].description.path().unwrap().clone();
+
+        let b = path(&di, 1);
+        assert_eq!(di.pin(&b), Some(idx(1)));
+        di.check_metadata();
+        assert!(di.roots().any(|r| r == idx(1)));
+        // b and c are no longer freed
+        assert_eq!(di.freed_by_deleting(&[idx(0), idx(3)]), 1 + 8);
+        let keep: Vec<_> = di.roots().filter(|&r| r != idx(0) && r != idx(3)).collect();
+        let freed = crate::reduction::reclaimable(di.clone(), &keep);
+        assert_eq!(freed.reachable_size(), 1 + 8);
+        // pinning twice is harmless
+        assert_eq!(di.pin(&b), Some(idx(1)));
+        assert_eq!(di.roots().filter(|&r| r == idx(1)).count(), 1);
+
+        // pinning a dead path makes it alive
+        assert_eq!(di.reachable_size(), 15);
+        let e = path(&di, 4);
+        di.pin(&e);
+        di.check_metadata();
+        assert_eq!(di.reachable_size(), 31);
+        assert_eq!(di.unreachable_size(), 0);
+
+        assert_eq!(di.pin(b"/nix/store/missing"), None);
+    }
+
+    #[test]
+    fn by_closure_size_desc() {
+        // a -> b -> c, smallest first, and d alone
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 10), ("c", 100), ("d", 50)],
+            &[(0, 1), (1, 2)],
+            &[0, 3],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(di.largest(1, true), vec![(idx(2), 100)]);
+        assert_eq!(
+            di.by_closure_size_desc(&ClosureSizes::new(&di))
+                .collect::<Vec<_>>(),
+            vec![(idx(0), 111), (idx(1), 110), (idx(2), 100), (idx(3), 50)]
+        );
+    }
+
+    #[test]
+    fn ancestors() {
+        // a diamond a -> b, c -> d, and d -> e
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 1), ("c", 1), ("d", 1), ("e", 1), ("f", 1)],
+            &[(0, 1), (0, 2), (1, 3), (2, 3), (3, 4)],
+            &[0],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(di.parents(idx(3)), vec![idx(1), idx(2)]);
+        assert_eq!(di.parents(idx(0)), vec![di.root]);
+        assert_eq!(
+            di.ancestors(idx(4)),
+            vec![idx(0), idx(1), idx(2), idx(3), di.root]
+        );
+        assert_eq!(di.ancestors(idx(1)), vec![idx(0), di.root]);
+        // f is dead
+        assert_eq!(di.ancestors(idx(5)), vec![]);
+        assert_eq!(di.ancestors(di.root), vec![]);
+    }
+
+    #[test]
+    fn covering_roots() {
+        // roots a, b, c and d; a -> b -> e, c -> e, c -> f; d depends on nothing
+        // and has size 0
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 0), ("e", 8), ("f", 16)],
+            &[(0, 1), (1, 4), (2, 4), (2, 5)],
+            &[0, 1, 2, 3],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        // the closure of b is included in that of a
+        assert_eq!(di.covering_roots(), vec![idx(2), idx(0), idx(3)]);
+        let empty = DepInfos::from_spec(&[], &[], &[]);
+        assert_eq!(empty.covering_roots(), vec![]);
+    }
+
+    #[test]
+    fn fair_closure_sizes() {
+        // a, b and c share d, only c keeps e
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 31), ("e", 8)],
+            &[(0, 3), (1, 3), (2, 3), (2, 4)],
+            &[0, 1, 2],
+        );
+        let sizes = di.fair_closure_sizes();
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(sizes.len(), 3);
+        assert_eq!(sizes[&idx(0)], 1 + 10 + 1);
+        assert_eq!(sizes[&idx(1)], 2 + 10);
+        assert_eq!(sizes[&idx(2)], 4 + 10 + 8);
+        assert_eq!(sizes.values().sum::<u64>(), di.reachable_size());
+
+        // with d divisible by 3, each root gets exactly a third
+        let mut di = di;
+        di.graph[idx(3)].size = 30;
+        let sizes = di.fair_closure_sizes();
+        assert_eq!(sizes[&idx(0)], 1 + 10);
+        assert_eq!(sizes[&idx(1)], 2 + 10);
+        assert_eq!(sizes[&idx(2)], 4 + 10 + 8);
+    }
+
+    #[test]
+    fn validate() {
+        let di = DepInfos::from_spec(&[("a", 1), ("b", 2)], &[(0, 1), (1, 0)], &[0]);
+        assert_eq!(di.validate(), Ok(()));
+
+        let mut looped = di.clone();
+        looped
+            .graph
+            .add_edge(NodeIndex::new(1), NodeIndex::new(1), Edge::default());
+        assert_eq!(looped.validate(), Err(vec!["self-loop on N1".to_owned()]));
+
+        let mut dangling = di.clone();
+        dangling.root = NodeIndex::new(42);
+        assert_eq!(
+            dangling.validate(),
+            Err(vec![
+                "root N42 is not a node of the graph (3 nodes)".to_owned()
+            ])
+        );
+
+        let mut resized = di.clone();
+        resized.graph[NodeIndex::new(0)].size = u64::MAX;
+        let errors = resized.validate().unwrap_err();
+        assert_eq!(errors, vec!["the total size overflows".to_owned()]);
+
+        let mut resized = di.clone();
+        resized.graph[NodeIndex::new(0)].size = 5;
+        let errors = resized.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("metadata records a size of 3"),
+            "{:?}",
+            errors
+        );
+
+        let mut disconnected = di;
+        disconnected.graph.add_node(DepNode::dummy());
+        disconnected.metadata.reachable = Reachability::Connected;
+        disconnected.metadata.size = enum_map! { _ => enum_map!{ _ => None }};
+        let errors = disconnected.validate().unwrap_err();
+        assert_eq!(
+            errors,
+            vec!["metadata says the graph is connected, but 1 nodes are unreachable".to_owned()]
+        );
+    }
+
+    #[test]
+    fn cycles() {
+        // b -> c -> d -> b is a cycle, e -> e is not reported
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8), ("e", 16)],
+            &[(0, 1), (1, 2), (2, 3), (3, 1), (0, 4), (4, 4)],
+            &[0],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(di.cycles(), vec![vec![idx(1), idx(2), idx(3)]]);
+        assert!(diamond().cycles().is_empty());
+    }
+
+    #[test]
+    fn largest() {
+        // roots /home/alice/result (large) and {memory:1}; a -> c, memory -> d
+        let mut di = DepInfos::from_spec(
+            &[("a", 100), ("b", 50), ("c", 10), ("d", 20), ("e", 20)],
+            &[(0, 2), (1, 3)],
+            &[0, 1],
+        );
+        di.graph[NodeIndex::from(0)].description =
+            NodeDescription::Link(b"/home/alice/result".to_vec());
+        di.graph[NodeIndex::from(1)].description = NodeDescription::Memory(b"{memory:1}".to_vec());
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(di.largest(2, false), vec![(idx(3), 20), (idx(4), 20)]);
+        assert_eq!(
+            di.largest(10, false),
+            vec![(idx(3), 20), (idx(4), 20), (idx(2), 10)]
+        );
+        assert_eq!(di.largest(2, true), vec![(idx(0), 100), (idx(1), 50)]);
+        assert_eq!(di.largest(0, true), vec![]);
+    }
+
+    #[test]
+    fn root_kind() {
+        use self::NodeDescription::*;
+        let kind = |d: NodeDescription| d.root_kind();
+        assert_eq!(
+            kind(Link(
+                b"/nix/var/nix/profiles/per-user/alice/profile-3-link".to_vec()
+            )),
+            Some(RootKind::Profile)
+        );
+        assert_eq!(
+            kind(Link(b"/nix/var/nix/profiles/system-12-link".to_vec())),
+            Some(RootKind::Profile)
+        );
+        assert_eq!(
+            kind(Link(
+                b"/home/alice/.local/state/nix/profiles/profile-1-link".to_vec()
+            )),
+            Some(RootKind::Profile)
+        );
+        assert_eq!(
+            kind(Link(b"/home/alice/src/project/result".to_vec())),
+            Some(RootKind::Indirect)
+        );
+        assert_eq!(
+            kind(Link(b"/nix/var/nix/gcroots/booted-system".to_vec())),
+            Some(RootKind::Other)
+        );
+        assert_eq!(
+            kind(Memory(b"/proc/42/maps".to_vec())),
+            Some(RootKind::Memory)
+        );
+        assert_eq!(kind(Memory(b"{censored}".to_vec())), Some(RootKind::Memory));
+        assert_eq!(kind(Temporary(b"{temp:42}".to_vec())), Some(RootKind::Temp));
+        assert_eq!(kind(Transient), Some(RootKind::Other));
+        assert_eq!(kind(Path(b"/nix/store/aaaa-hello".to_vec())), None);
+        assert_eq!(kind(Dummy), None);
+    }
+
+    #[test]
+    fn roots_of_kind() {
+        let mut di = diamond();
+        let link = di.graph.add_node(DepNode {
+            description: NodeDescription::Link(b"/home/alice/result".to_vec()),
+            size: 0,
+            link_path: None,
+        });
+        di.graph.add_edge(di.root, link, Edge::default());
+        assert_eq!(di.roots_of_kind(RootKind::Indirect), vec![link]);
+        assert!(di.roots_of_kind(RootKind::Profile).is_empty());
+    }
+
+    #[test]
+    fn register_node_records_gc_roots() {
+        let paths: &[(&[u8], bool)] = &[
+            (b"/nix/store/aaaa-a", false),
+            (b"/nix/var/nix/profiles/system-1-link", true),
+            (b"{memory:12}", false),
+            (b"/nix/store/bbbb-b", false),
+            (b"{temp:3}", false),
+            (b"/proc/42/maps", false),
+        ];
+        let mut ctx = ReadContext::default();
+        for &(path, is_root) in paths {
+            let path = std::ffi::CString::new(path).unwrap();
+            let p = bindings::path_t {
+                path: path.as_ptr(),
+                size: 1,
+                is_root: is_root as std::os::raw::c_int,
+                is_valid: 1,
+            };
+            unsafe { register_node(&mut ctx, &p) };
+        }
+        unsafe { register_edge(&mut ctx, 1, 0) };
+        let filtered: Vec<_> = ctx
+            .graph
+            .node_references()
+            .filter(|(_, n)| n.kind().is_gc_root())
+            .map(|(idx, _)| idx)
+            .collect();
+        assert_eq!(ctx.gc_roots, filtered);
+        assert_eq!(ctx.gc_roots.len(), 4);
+        assert_eq!(ctx.graph.edge_count(), 1);
+    }
+
+    /// pretends to be `populateGraph` on a store with one live and one dead path,
+    /// and the derivation of the live one
+    unsafe extern "C" fn mock_populate(
+        graph: *mut c_void,
+        root_path: *const c_char,
+        _store_uri: *const c_char,
+        reachable_only: c_int,
+        with_derivations: c_int,
+    ) -> c_int {
+        assert!(root_path.is_null());
+        let ctx = graph as *mut ReadContext;
+        let register = |path: &[u8], is_root: bool| {
+            let path = std::ffi::CString::new(path).unwrap();
+            let p = bindings::path_t {
+                path: path.as_ptr(),
+                size: 1,
+                is_root: is_root as c_int,
+                is_valid: 1,
+            };
+            register_node(ctx, &p);
+        };
+        register(b"/nix/store/aaaa-live", false);
+        if reachable_only == 0 {
+            register(b"/nix/store/bbbb-dead", false);
+        }
+        if with_derivations != 0 {
+            let drv = (*ctx).graph.node_count() as u32;
+            register(b"/nix/store/cccc-live.drv", false);
+            register_edge(ctx, 0, drv);
+        }
+        let root = (*ctx).graph.node_count() as u32;
+        register(b"/nix/var/nix/profiles/default-1-link", true);
+        register_edge(ctx, root, 0);
+        0
+    }
+
+    #[test]
+    fn store_error() {
+        for (code, error) in &[
+            (69, StoreError::ConnectionFailed),
+            (77, StoreError::PermissionDenied),
+            (1, StoreError::Unknown(1)),
+            (100, StoreError::Unknown(100)),
+        ] {
+            assert_eq!(StoreError::from_code(*code), *error);
+            assert_eq!(error.code(), *code);
+        }
+        unsafe extern "C" fn fail(
+            _: *mut c_void,
+            _: *const c_char,
+            _: *const c_char,
+            _: c_int,
+            _: c_int,
+        ) -> c_int {
+            77
+        }
+        let err = DepInfos::read_with(fail, None, None, false, false).unwrap_err();
+        assert_eq!(err, StoreError::PermissionDenied);
+        let err: Box<dyn std::error::Error> = Box::new(err);
+        assert_eq!(err.to_string(), "permission denied");
+    }
+
+    #[test]
+    fn read_reachable_only() {
+        let all = DepInfos::read_with(mock_populate, None, None, false, false).unwrap();
+        all.check_metadata();
+        assert_eq!(all.metadata.reachable, Reachability::Disconnected);
+        // the dummy root, the gc root and both paths
+        assert_eq!(all.graph.node_count(), 4);
+        let live = DepInfos::read_with(mock_populate, None, None, true, false).unwrap();
+        live.check_metadata();
+        assert_eq!(live.metadata.reachable, Reachability::Connected);
+        assert_eq!(live.graph.node_count(), 3);
+        // dead paths are not reachable anyway
+        assert_eq!(live.reachable_size(), all.reachable_size());
+        assert!(all.graph.node_weights().all(|n| !n.is_derivation()));
+    }
+
+    /// pretends to be `populateGraph` on a store with a valid path and the output of
+    /// two interrupted builds
+    unsafe extern "C" fn mock_interrupted(
+        graph: *mut c_void,
+        _: *const c_char,
+        _: *const c_char,
+        _: c_int,
+        _: c_int,
+    ) -> c_int {
+        let ctx = graph as *mut ReadContext;
+        let register = |path: &[u8], size, is_root, is_valid| {
+            let path = std::ffi::CString::new(path).unwrap();
+            let p = bindings::path_t {
+                path: path.as_ptr(),
+                size,
+                is_root,
+                is_valid,
+            };
+            register_node(ctx, &p);
+        };
+        register(b"/nix/store/aaaa-hello", 10, 0, 1);
+        register(b"/nix/store/bbbb-firefox", 0, 0, 0);
+        register(b"/nix/store/cccc-chromium", 0, 0, 0);
+        register(b"/nix/var/nix/profiles/default-1-link", 0, 1, 1);
+        register_edge(ctx, 3, 0);
+        0
+    }
+
+    #[test]
+    fn invalid_paths() {
+        let di = DepInfos::read_with(mock_interrupted, None, None, false, false).unwrap();
+        di.check_metadata();
+        let invalid = di.invalid_paths();
+        let paths: Vec<_> = invalid
+            .iter()
+            .map(|&idx| di.graph[idx].description.path().unwrap().as_slice())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![&b"/nix/store/bbbb-firefox"[..], b"/nix/store/cccc-chromium"]
+        );
+        assert_eq!(&*di.graph[invalid[0]].name(), b"firefox");
+        assert!(invalid
+            .iter()
+            .all(|&idx| !di.graph[idx].kind().is_gc_root()));
+        // nothing keeps them alive
+        let mut unreachable = di.unreachable();
+        unreachable.sort();
+        assert_eq!(unreachable, invalid);
+        // other nodes are valid, for example those of other backends
+        assert_eq!(di.graph.node_weights().filter(|n| n.is_valid()).count(), 3);
+        let di = DepInfos::from_spec(&[("a", 1)], &[], &[0]);
+        assert!(di.invalid_paths().is_empty());
+    }
+
+    #[test]
+    fn read_derivations() {
+        let di = DepInfos::read_with(mock_populate, None, None, true, true).unwrap();
+        di.check_metadata();
+        let drvs: Vec<_> = di
+            .graph
+            .node_indices()
+            .filter(|&idx| di.graph[idx].is_derivation())
+            .collect();
+        assert_eq!(drvs.len(), 1);
+        assert_eq!(
+            di.graph[drvs[0]].description.path().unwrap(),
+            b"/nix/store/cccc-live.drv"
+        );
+        // kept alive by the live path
+        assert_eq!(di.reachable_size(), 3);
+    }
+
+    #[test]
+    fn is_derivation() {
+        let node = |description| DepNode {
+            description,
+            size: 0,
+            link_path: None,
+        };
+        let path = |name: &str| {
+            NodeDescription::Path(
+                format!("/nix/store/0c0rv3yhhdbc4z4w0kwgl2ljn5j1yz8p-{}", name).into_bytes(),
+            )
+        };
+        assert!(node(path("hello-2.12.drv")).is_derivation());
+        assert!(!node(path("hello-2.12")).is_derivation());
+        assert!(!node(path("hello-2.12.drv.patch")).is_derivation());
+        assert!(!node(NodeDescription::Link(b"/home/me/result.drv".to_vec())).is_derivation());
     }
 }
@@ -0,0 +1,244 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use crate::msg::format_size;
+use petgraph::prelude::NodeIndex;
+use petgraph::visit::{Dfs, NodeFiltered, Reversed};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// The differences between two graphs, see `DepInfos::diff`.
+///
+/// Indices refer to the current graph, except in `removed` which refers to the
+/// previous one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GraphDiff {
+    /// nodes whose path was not in the previous graph
+    pub added: Vec<NodeIndex>,
+    /// nodes of the previous graph whose path is not in the current one
+    pub removed: Vec<NodeIndex>,
+    /// `(current, previous)` nodes with the same path but different sizes
+    pub resized: Vec<(NodeIndex, NodeIndex)>,
+    /// how much the total size grew
+    pub size_delta: i64,
+    /// how much what the garbage collector would free grew
+    pub reclaimable_delta: i64,
+    /// for each root of the current graph whose closure contains added nodes, the
+    /// sum of their sizes, by decreasing size
+    pub growth_by_root: Vec<(NodeIndex, u64)>,
+}
+
+fn delta(current: u64, previous: u64) -> i64 {
+    current as i64 - previous as i64
+}
+
+/// formats a size difference with its sign
+fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { '-' } else { '+' };
+    format!("{}{}", sign, format_size(delta.unsigned_abs()))
+}
+
+impl DepInfos {
+    /// compares this graph to a `previous` one, typically loaded from an older dump,
+    /// matching nodes by path. Nodes without path, like the root, are ignored.
+    pub fn diff(&self, previous: &DepInfos) -> GraphDiff {
+        fn by_path(di: &DepInfos) -> HashMap<&Path, NodeIndex> {
+            let mut res = HashMap::new();
+            for idx in di.graph.node_indices() {
+                if let Some(path) = di.graph[idx].description.path() {
+                    res.entry(path).or_insert(idx);
+                }
+            }
+            res
+        }
+        let current_paths = by_path(self);
+        let previous_paths = by_path(previous);
+        let mut diff = GraphDiff::default();
+        for (path, &idx) in &current_paths {
+            match previous_paths.get(path) {
+                None => diff.added.push(idx),
+                Some(&old) if previous.graph[old].size != self.graph[idx].size => {
+                    diff.resized.push((idx, old))
+                }
+                Some(_) => (),
+            }
+        }
+        for (path, &old) in &previous_paths {
+            if !current_paths.contains_key(path) {
+                diff.removed.push(old);
+            }
+        }
+        diff.added.sort();
+        diff.removed.sort();
+        diff.resized.sort();
+        diff.size_delta = delta(self.size(), previous.size());
+        diff.reclaimable_delta = delta(self.unreachable_size(), previous.unreachable_size());
+
+        // the nodes holding added nodes, found in a single traversal from all of them
+        let reversed = Reversed(&self.graph);
+        let mut dfs = Dfs::empty(reversed);
+        dfs.stack.extend(diff.added.iter().copied());
+        while dfs.next(reversed).is_some() {}
+        let holding = dfs.discovered;
+        // the added nodes each of them holds, in reverse topological order so that
+        // the sets of children are known first
+        let bit: HashMap<NodeIndex, usize> = diff
+            .added
+            .iter()
+            .enumerate()
+            .map(|(i, &idx)| (idx, i))
+            .collect();
+        let sccs = petgraph::algo::tarjan_scc(&NodeFiltered::from_fn(&self.graph, |idx| {
+            holding.contains(idx.index())
+        }));
+        let mut component = vec![usize::MAX; self.graph.node_count()];
+        let mut held: Vec<fixedbitset::FixedBitSet> = Vec::with_capacity(sccs.len());
+        for (i, scc) in sccs.iter().enumerate() {
+            for idx in scc {
+                component[idx.index()] = i;
+            }
+            let mut set = fixedbitset::FixedBitSet::with_capacity(diff.added.len());
+            for &idx in scc {
+                if let Some(&b) = bit.get(&idx) {
+                    set.insert(b);
+                }
+                for child in self.graph.neighbors(idx) {
+                    let j = component[child.index()];
+                    // children which hold nothing are not in a component
+                    if j != usize::MAX && j != i {
+                        set.union_with(&held[j]);
+                    }
+                }
+            }
+            held.push(set);
+        }
+        for root in self.roots() {
+            if !holding.contains(root.index()) {
+                continue;
+            }
+            let growth = held[component[root.index()]]
+                .ones()
+                .map(|b| self.graph[diff.added[b]].size)
+                .sum();
+            if growth > 0 {
+                diff.growth_by_root.push((root, growth));
+            }
+        }
+        diff.growth_by_root
+            .sort_by_key(|&(idx, size)| (std::cmp::Reverse(size), idx));
+        diff
+    }
+}
+
+/// Outputs `diff` between `current` and `previous` as text: added paths prefixed
+/// by `+`, removed paths by `-`, resized paths by `~`, then the roots which grew
+/// the store and the totals.
+pub fn render<W: Write>(
+    diff: &GraphDiff,
+    current: &DepInfos,
+    previous: &DepInfos,
+    w: &mut W,
+) -> io::Result<()> {
+    fn path(di: &DepInfos, idx: NodeIndex) -> &Path {
+        di.graph[idx]
+            .description
+            .path()
+            .expect("diff of a node without path")
+    }
+    for &idx in &diff.added {
+        w.write_all(b"+ ")?;
+        w.write_all(path(current, idx))?;
+        writeln!(w, " ({})", format_size(current.graph[idx].size))?;
+    }
+    for &idx in &diff.removed {
+        w.write_all(b"- ")?;
+        w.write_all(path(previous, idx))?;
+        writeln!(w, " ({})", format_size(previous.graph[idx].size))?;
+    }
+    for &(idx, old) in &diff.resized {
+        w.write_all(b"~ ")?;
+        w.write_all(path(current, idx))?;
+        writeln!(
+            w,
+            " ({} -> {})",
+            format_size(previous.graph[old].size),
+            format_size(current.graph[idx].size)
+        )?;
+    }
+    if !diff.growth_by_root.is_empty() {
+        writeln!(w, "Roots holding new paths:")?;
+        for &(idx, size) in &diff.growth_by_root {
+            w.write_all(b"\t")?;
+            w.write_all(&current.graph[idx].name())?;
+            writeln!(w, ": {}", format_delta(size as i64))?;
+        }
+    }
+    writeln!(
+        w,
+        "Total: {}, reclaimable: {}",
+        format_delta(diff.size_delta),
+        format_delta(diff.reclaimable_delta)
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+    use petgraph::prelude::NodeIndex;
+
+    #[test]
+    fn diff() {
+        // yesterday: a -> b, c is dead
+        let previous = DepInfos::from_spec(&[("a", 1), ("b", 2), ("c", 4)], &[(0, 1)], &[0]);
+        // today: c was collected, b grew, d is a new root depending on e
+        let current = DepInfos::from_spec(
+            &[("a", 1), ("b", 3), ("d", 8), ("e", 16)],
+            &[(0, 1), (2, 3)],
+            &[0, 2],
+        );
+        let diff = current.diff(&previous);
+        let names = |di: &DepInfos, nodes: &[NodeIndex]| {
+            nodes
+                .iter()
+                .map(|&idx| String::from_utf8(di.graph[idx].name().into_owned()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&current, &diff.added), vec!["d", "e"]);
+        assert_eq!(names(&previous, &diff.removed), vec!["c"]);
+        assert_eq!(diff.resized, vec![(NodeIndex::new(1), NodeIndex::new(1))]);
+        assert_eq!(diff.size_delta, 28 - 7);
+        assert_eq!(diff.reclaimable_delta, -4);
+        assert_eq!(diff.growth_by_root, vec![(NodeIndex::new(2), 24)]);
+
+        let mut out = Vec::new();
+        super::render(&diff, &current, &previous, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("+ /nix/store/{:032}-e (16 B)\n", 3)));
+        assert!(out.contains(&format!("- /nix/store/{:032}-c (4 B)\n", 2)));
+        assert!(out.contains(&format!("~ /nix/store/{:032}-b (2 B -> 3 B)\n", 1)));
+        assert!(out.ends_with("Total: +21 B, reclaimable: -4 B\n"));
+
+        assert_eq!(current.diff(&current), super::GraphDiff::default());
+    }
+
+    #[test]
+    fn growth_by_root() {
+        let previous = DepInfos::from_spec(&[("a", 1)], &[], &[0]);
+        // new roots b and c share d, c -> e -> c is a cycle, a now holds d too
+        let current = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8), ("e", 16)],
+            &[(0, 3), (1, 3), (2, 3), (2, 4), (4, 2)],
+            &[0, 1, 2],
+        );
+        let diff = current.diff(&previous);
+        assert_eq!(
+            diff.growth_by_root,
+            vec![
+                (NodeIndex::new(2), 4 + 8 + 16),
+                (NodeIndex::new(1), 2 + 8),
+                (NodeIndex::new(0), 8)
+            ]
+        );
+    }
+}
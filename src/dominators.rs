@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use petgraph::algo::dominators::simple_fast;
+use petgraph::prelude::NodeIndex;
+
+/// Returns, for each node (by index), the sum of the sizes of the nodes it dominates,
+/// itself included.
+///
+/// A node `a` dominates `b` if all paths from the root to `b` go through `a`,
+/// so this is the size which would be freed if `a` was removed.
+/// A node shared by several branches only counts towards their nearest common
+/// dominator. Unreachable nodes have an exclusive size of 0.
+///
+/// The root of the graph plays the role of the super root connected to all gc roots.
+pub fn exclusive_sizes(di: &DepInfos) -> Vec<u64> {
+    let dominators = simple_fast(&di.graph, di.root);
+    let mut children = vec![Vec::new(); di.graph.node_count()];
+    for idx in di.graph.node_indices() {
+        if let Some(parent) = dominators.immediate_dominator(idx) {
+            children[parent.index()].push(idx);
+        }
+    }
+    // bfs order in the dominator tree: parents come before their children
+    let mut order = vec![di.root];
+    let mut i = 0;
+    while i < order.len() {
+        order.extend_from_slice(&children[order[i].index()]);
+        i += 1;
+    }
+    let mut res = vec![0; di.graph.node_count()];
+    for &idx in order.iter().rev() {
+        res[idx.index()] += di.graph[idx].size;
+        if let Some(parent) = dominators.immediate_dominator(idx) {
+            res[parent.index()] += res[idx.index()];
+        }
+    }
+    res
+}
+
+impl DepInfos {
+    /// returns the size which would be freed by removing `node`, see `exclusive_sizes`.
+    ///
+    /// This computes the dominators of the whole graph, so when querying several
+    /// nodes, prefer calling `exclusive_sizes` once.
+    pub fn exclusive_size(&self, node: NodeIndex) -> u64 {
+        exclusive_sizes(self)[node.index()]
+    }
+
+    /// returns the gc roots with the size which deleting each of them alone would
+    /// free, by decreasing size. Roots sharing most of their closure with others
+    /// free little, however large their closure.
+    pub fn roots_by_reclaimable(&self) -> Vec<(NodeIndex, u64)> {
+        let sizes = exclusive_sizes(self);
+        let mut res: Vec<_> = self.roots().map(|idx| (idx, sizes[idx.index()])).collect();
+        res.sort_by_key(|&(idx, size)| (std::cmp::Reverse(size), idx));
+        res.dedup();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dominators::*;
+
+    #[test]
+    fn diamond() {
+        // a -> b -> d, a -> c -> d
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8)],
+            &[(0, 1), (0, 2), (1, 3), (2, 3)],
+            &[0],
+        );
+        let sizes = exclusive_sizes(&di);
+        assert_eq!(sizes[0], 15);
+        assert_eq!(sizes[1], 2);
+        assert_eq!(sizes[2], 4);
+        assert_eq!(sizes[3], 8);
+        assert_eq!(sizes[di.root.index()], 15);
+        assert_eq!(di.exclusive_size(NodeIndex::from(0)), 15);
+    }
+
+    #[test]
+    fn shared_between_roots() {
+        // roots a and b share c, which only the root dominates
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8)],
+            &[(0, 2), (1, 2), (1, 3)],
+            &[0, 1],
+        );
+        let sizes = exclusive_sizes(&di);
+        assert_eq!(sizes[0], 1);
+        assert_eq!(sizes[1], 10);
+        assert_eq!(sizes[2], 4);
+        assert_eq!(sizes[di.root.index()], 15);
+    }
+
+    #[test]
+    fn roots_by_reclaimable() {
+        // roots a and b share c, the largest; b also has d, c is also below root e
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 64), ("d", 8), ("e", 4)],
+            &[(0, 2), (1, 2), (1, 3), (4, 2)],
+            &[0, 1, 4],
+        );
+        let idx = |i: u32| NodeIndex::from(i);
+        assert_eq!(
+            di.roots_by_reclaimable(),
+            vec![(idx(1), 10), (idx(4), 4), (idx(0), 1)]
+        );
+    }
+
+    #[test]
+    fn unreachable() {
+        let di = DepInfos::from_spec(&[("a", 1), ("b", 2)], &[], &[0]);
+        assert_eq!(di.exclusive_size(NodeIndex::from(1)), 0);
+        assert_eq!(di.exclusive_size(di.root), 1);
+    }
+}
@@ -1,38 +1,290 @@
 // SPDX-License-Identifier: LGPL-3.0
 
 use crate::depgraph;
-use bytesize::ByteSize;
+use crate::msg::format_size;
 use petgraph::visit::IntoNodeReferences;
 use scarlet::colormap::ColorMap;
 use scarlet::material_colors::MaterialPrimary;
 use scarlet::{colormap::ListedColorMap, prelude::*};
+use std::cell::Cell;
 use std::io::{self, Write};
 
-pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Result<()> {
-    // compute color gradient
-    // first, min and max
-    let mut min = dependencies.graph.raw_nodes()[0].weight.size;
-    let mut max = min;
-    for node in &dependencies.graph.raw_nodes()[1..] {
-        max = std::cmp::max(node.weight.size, max);
-        min = std::cmp::min(node.weight.size, min);
+/// Options for the DOT output
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// print sizes as a number of bytes instead of human readable sizes
+    pub bytes: bool,
+    /// fill nodes with a color depending on their size
+    pub color: bool,
+    /// draw the outputs of the same package in a cluster, see
+    /// `DepInfos::group_by_package`
+    pub cluster_outputs: bool,
+    /// draw the nodes with the same `pname` in a cluster, see
+    /// `DepInfos::group_by_pname`. Takes precedence over `cluster_outputs`.
+    pub cluster_by_name: bool,
+    /// label nodes with their closure size after their own size, see
+    /// `depgraph::ClosureSizes`
+    pub closure_sizes: bool,
+    /// with `color`, fill nodes which look like sources (see
+    /// `DepNode::looks_like_source`) with `SOURCE_FILL` instead of the color of
+    /// their size
+    pub color_sources: bool,
+    /// give nodes a `tooltip`, shown on hover in SVG, with their full path, their
+    /// size and their closure size
+    pub tooltips: bool,
+    /// name nodes after a hash of their path (or name), like `n_1234abcd`, instead
+    /// of their index, so that the same path has the same id in all renders. See
+    /// `node_ids`.
+    pub stable_ids: bool,
+    /// label nodes with their path, hash included, instead of their name. Nodes
+    /// without path keep their name.
+    pub full_paths: bool,
+    /// draw edges thicker when the closure of their target is larger, on a log scale
+    pub weighted_edges: bool,
+    /// direction of the layout, `rankdir` in graphviz: `LR`, `TB`, `RL` or `BT`
+    pub rankdir: String,
+    /// space between ranks, in inches (`ranksep` in graphviz)
+    pub ranksep: Option<f64>,
+    /// space between nodes of the same rank, in inches (`nodesep` in graphviz)
+    pub nodesep: Option<f64>,
+    /// wrap the name (or path) in labels of nodes to lines of about this many
+    /// bytes, see `wrap_label`
+    pub wrap_labels: Option<usize>,
+}
+
+impl Options {
+    /// whether `render` needs the closure sizes of the graph
+    pub fn needs_closure_sizes(&self) -> bool {
+        self.closure_sizes || self.weighted_edges || self.tooltips
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            bytes: false,
+            color: true,
+            cluster_outputs: false,
+            cluster_by_name: false,
+            closure_sizes: false,
+            color_sources: false,
+            tooltips: false,
+            stable_ids: false,
+            full_paths: false,
+            weighted_edges: false,
+            rankdir: "LR".to_owned(),
+            ranksep: None,
+            nodesep: None,
+            wrap_labels: None,
+        }
+    }
+}
+
+/// border of roots, distinct from the colors of the gradient
+const ROOT_BORDER: &str = "black";
+
+/// fill color of sources with `Options::color_sources`, distinct from the colors
+/// of the gradient
+const SOURCE_FILL: &str = "#c0c0c0";
+
+/// Maps sizes to a color, from the smallest to the largest node of the graph.
+struct Gradient {
+    min: u64,
+    span: f64,
+    colormap: ListedColorMap,
+}
+
+impl Gradient {
+    fn new(dependencies: &depgraph::DepInfos) -> Self {
+        let sizes = dependencies.graph.raw_nodes().iter().map(|n| n.weight.size);
+        let min = sizes.clone().min().unwrap_or(0);
+        let max = sizes.max().unwrap_or(0);
+        Gradient {
+            min,
+            // avoid dividing by 0 when all nodes have the same size
+            span: std::cmp::max(max - min, 1) as f64,
+            colormap: ListedColorMap::turbo(),
+        }
+    }
+
+    fn color(&self, size: u64) -> RGBColor {
+        let offset = ((size - self.min) as f64) / self.span;
+        // make large node more visible in the color map
+        self.colormap.transform_single(offset.sqrt())
+    }
+}
+
+/// the thinnest and thickest edges with `Options::weighted_edges`
+const PENWIDTH: (f64, f64) = (1., 5.);
+
+/// Maps closure sizes to a `penwidth`, logarithmically between `PENWIDTH.0` for the
+/// smallest closure and `PENWIDTH.1` for the largest one.
+struct EdgeWidth {
+    min: f64,
+    span: f64,
+}
+
+impl EdgeWidth {
+    fn new<I: Iterator<Item = u64>>(sizes: I) -> Self {
+        let (min, max) = sizes
+            .map(|size| (size as f64).ln_1p())
+            .fold((f64::INFINITY, 0f64), |(min, max), s| {
+                (min.min(s), max.max(s))
+            });
+        EdgeWidth {
+            min,
+            // avoid dividing by 0 when all closures have the same size
+            span: (max - min).max(f64::EPSILON),
+        }
+    }
+
+    fn width(&self, size: u64) -> f64 {
+        let offset = ((size as f64).ln_1p() - self.min) / self.span;
+        PENWIDTH.0 + offset * (PENWIDTH.1 - PENWIDTH.0)
+    }
+}
+
+/// the 32 bits FNV-1a hash of `bytes`, which unlike `DefaultHasher` is the same
+/// with all versions of rust
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x01000193)
+    })
+}
+
+/// Breaks `label` into lines of at most `width` bytes, by inserting the DOT escape
+/// `\n` after dashes and instead of spaces. Parts without such a boundary which
+/// are longer than `width` are not split, so lines can be longer.
+fn wrap_label(label: &[u8], width: usize) -> Vec<u8> {
+    let mut res = Vec::with_capacity(label.len() + 8);
+    let mut column = 0;
+    for word in label.split_inclusive(|&c| c == b'-' || c == b' ') {
+        let len = word.strip_suffix(b" ").unwrap_or(word).len();
+        if column > 0 && column + len > width {
+            if res.last() == Some(&b' ') {
+                res.pop();
+            }
+            res.extend_from_slice(b"\\n");
+            column = 0;
+        }
+        res.extend_from_slice(word);
+        column += word.len();
+    }
+    res
+}
+
+/// Returns the DOT id of each node, by index: `N{index}`, or with `stable` the
+/// hash of its path, or of its name for nodes without path, as `n_{8 hex digits}`.
+/// On collisions, all but the smallest path get a suffix `_{i}`, so ids only
+/// depend on the paths in the graph.
+fn node_ids(dependencies: &depgraph::DepInfos, stable: bool) -> Vec<String> {
+    let g = &dependencies.graph;
+    if !stable {
+        return g
+            .node_indices()
+            .map(|idx| format!("N{}", idx.index()))
+            .collect();
+    }
+    let keys: Vec<_> = g
+        .node_weights()
+        .map(|node| match node.description.path() {
+            Some(path) => std::borrow::Cow::Borrowed(&path[..]),
+            None => node.name(),
+        })
+        .collect();
+    let mut by_hash: std::collections::HashMap<u32, Vec<usize>> = Default::default();
+    for (i, key) in keys.iter().enumerate() {
+        by_hash.entry(fnv1a(key)).or_default().push(i);
+    }
+    let mut ids = vec![String::new(); keys.len()];
+    for (hash, mut nodes) in by_hash {
+        nodes.sort_by(|&a, &b| (&keys[a], a).cmp(&(&keys[b], b)));
+        for (n, i) in nodes.into_iter().enumerate() {
+            ids[i] = if n == 0 {
+                format!("n_{:08x}", hash)
+            } else {
+                format!("n_{:08x}_{}", hash, n)
+            };
+        }
     }
-    let span = (max - min) as f64;
+    ids
+}
 
-    let scale = move |size| (((size - min) as f64) / span);
+/// writes a subgraph with DOT id `id` and label `label` drawing `nodes` in a cluster,
+/// `ids` being the result of `node_ids`.
+fn write_cluster<W: Write>(
+    w: &mut W,
+    id: &[u8],
+    label: &[u8],
+    nodes: &[petgraph::prelude::NodeIndex],
+    ids: &[String],
+) -> io::Result<()> {
+    w.write_all(b"subgraph ")?;
+    w.write_all(id)?;
+    w.write_all(b" {\nlabel=\"")?;
+    w.write_all(label)?;
+    w.write_all(b"\";\n")?;
+    for idx in nodes {
+        write!(w, "{}; ", ids[idx.index()])?;
+    }
+    w.write_all(b"\n}\n")
+}
 
-    let gradient = ListedColorMap::turbo();
+/// Outputs the graph in the DOT format of graphviz.
+///
+/// Nodes and edges are written to `w` one by one, the output is never built in
+/// memory: give a buffered writer. Nodes whose name or path is not valid UTF-8
+/// are counted in `non_utf8`.
+///
+/// `closure_sizes` are those of `dependencies`, they are only used when
+/// `options.needs_closure_sizes()`. Without them, the options needing them are
+/// ignored.
+///
+/// `invalid` are paths which are not valid, see `DepInfos::invalid_paths`, taken
+/// before reductions dropped them as unreachable. They are drawn dashed, in a
+/// cluster of their own, with ids `I{i}`. Nodes of `dependencies` which are not
+/// valid are drawn dashed too.
+pub fn render<W: Write>(
+    dependencies: &depgraph::DepInfos,
+    options: &Options,
+    closure_sizes: Option<&depgraph::ClosureSizes>,
+    invalid: &[depgraph::DepNode],
+    non_utf8: &Cell<usize>,
+    w: &mut W,
+) -> io::Result<()> {
+    let gradient = Gradient::new(dependencies);
     let textcolors: Vec<RGBColor> = [MaterialPrimary::White, MaterialPrimary::Black]
         .iter()
         .map(|&c| RGBColor::from_material_palette(c))
         .collect();
+    let mut is_root = vec![false; dependencies.graph.node_count()];
+    for idx in dependencies.roots() {
+        is_root[idx.index()] = true;
+    }
+
+    let closure_sizes = closure_sizes.filter(|_| options.needs_closure_sizes());
+    let format = |size: u64| {
+        if options.bytes {
+            format!("{} B", size)
+        } else {
+            format_size(size)
+        }
+    };
 
+    let ids = node_ids(dependencies, options.stable_ids);
+    let style = if options.color { ", style=filled" } else { "" };
     w.write_all(b"digraph nixstore {\n")?;
-    w.write_all(b"rankdir=LR;\n")?;
-    w.write_all(b"node [shape = tripleoctagon, style=filled];\n")?;
+    writeln!(w, "rankdir={};", options.rankdir)?;
+    if let Some(ranksep) = options.ranksep {
+        writeln!(w, "ranksep={};", ranksep)?;
+    }
+    if let Some(nodesep) = options.nodesep {
+        writeln!(w, "nodesep={};", nodesep)?;
+    }
+    writeln!(w, "node [shape = tripleoctagon{}];", style)?;
     w.write_all(b"{ rank = same;\n")?;
     for idx in dependencies.roots() {
-        write!(w, "N{}; ", idx.index())?;
+        write!(w, "{}; ", ids[idx.index()])?;
     }
     w.write_all(b"\n};\n")?;
     w.write_all(b"node [shape = box];\n")?;
@@ -40,37 +292,491 @@ pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Res
         if idx == dependencies.root {
             continue;
         };
-        let size = ByteSize::b(node.size);
-        let offset = scale(node.size);
-        // make large node more visible in the color map
-        let offset = offset.sqrt();
-        let color: RGBColor = gradient.transform_single(offset);
-        let textcolor = textcolors
-            .iter()
-            .max_by_key(|c| (c.distance(&color) * 1000.) as u64)
-            .expect("no possible textcolor")
-            .to_string();
-        write!(
-            w,
-            "N{}[color=\"{}\",fontcolor=\"{}\",label=\"",
-            idx.index(),
-            color.to_string(),
-            textcolor
-        )?;
-        w.write_all(&node.name())?;
+        let size = match &closure_sizes {
+            Some(sizes) if options.closure_sizes => {
+                format!("{} / {}", format(node.size), format(sizes.get(idx)))
+            }
+            _ => format(node.size),
+        };
+        if !node.is_utf8() {
+            non_utf8.set(non_utf8.get() + 1);
+        }
+        write!(w, "{}[", ids[idx.index()])?;
+        if options.color {
+            let color = if options.color_sources && node.looks_like_source() {
+                RGBColor::from_hex_code(SOURCE_FILL).expect("invalid SOURCE_FILL")
+            } else {
+                gradient.color(node.size)
+            };
+            let textcolor = textcolors
+                .iter()
+                .max_by_key(|c| (c.distance(&color) * 1000.) as u64)
+                .expect("no possible textcolor")
+                .to_string();
+            let border = if is_root[idx.index()] {
+                ROOT_BORDER.to_owned()
+            } else {
+                color.to_string()
+            };
+            write!(
+                w,
+                "fillcolor=\"{}\",color=\"{}\",fontcolor=\"{}\",",
+                color.to_string(),
+                border,
+                textcolor
+            )?;
+        }
+        if !node.is_valid() {
+            let style: &[u8] = if options.color {
+                b"style=\"filled,dashed\","
+            } else {
+                b"style=dashed,"
+            };
+            w.write_all(style)?;
+        }
+        if let Some(sizes) = closure_sizes.as_ref().filter(|_| options.tooltips) {
+            w.write_all(b"tooltip=\"")?;
+            match node.description.path() {
+                Some(path) => w.write_all(String::from_utf8_lossy(path).as_bytes())?,
+                None => w.write_all(&node.name())?,
+            }
+            write!(
+                w,
+                "\\nsize: {}\\nclosure size: {}\",",
+                format(node.size),
+                format(sizes.get(idx))
+            )?;
+        }
+        w.write_all(b"label=\"")?;
+        let label = match node.description.path() {
+            Some(path) if options.full_paths => String::from_utf8_lossy(path)
+                .into_owned()
+                .into_bytes()
+                .into(),
+            _ => node.name(),
+        };
+        match options.wrap_labels {
+            Some(width) => w.write_all(&wrap_label(&label, width))?,
+            None => w.write_all(&label)?,
+        }
         writeln!(w, " ({})\"];", size)?;
     }
+    if !invalid.is_empty() {
+        w.write_all(b"subgraph cluster_invalid {\nlabel=\"invalid paths\";\nstyle=dashed;\n")?;
+        for (i, node) in invalid.iter().enumerate() {
+            if !node.is_utf8() {
+                non_utf8.set(non_utf8.get() + 1);
+            }
+            write!(w, "I{}[style=dashed,label=\"", i)?;
+            match options.wrap_labels {
+                Some(width) => w.write_all(&wrap_label(&node.name(), width))?,
+                None => w.write_all(&node.name())?,
+            }
+            writeln!(w, "\"];")?;
+        }
+        w.write_all(b"}\n")?;
+    }
+    if options.cluster_by_name {
+        for (name, nodes) in dependencies.group_by_pname() {
+            // store path names contain no quote, but may contain characters which
+            // are not allowed in unquoted identifiers
+            let id = [&b"\"cluster_"[..], &name, b"\""].concat();
+            write_cluster(w, &id, &name, &nodes, &ids)?;
+        }
+    } else if options.cluster_outputs {
+        for (i, (name, outputs)) in dependencies.group_by_package().into_iter().enumerate() {
+            let id = format!("cluster_{}", i);
+            write_cluster(w, id.as_bytes(), &name, &outputs, &ids)?;
+        }
+    }
+    let edge_width = match &closure_sizes {
+        Some(sizes) if options.weighted_edges => Some(EdgeWidth::new(
+            // the dummy root is never the target of an edge drawn
+            sizes
+                .iter()
+                .filter(|&(idx, _)| idx != dependencies.root)
+                .map(|(_, size)| size),
+        )),
+        _ => None,
+    };
     for edge in dependencies.graph.raw_edges() {
         if edge.source() == dependencies.root {
             continue;
         }
-        writeln!(
+        write!(
             w,
-            "N{} -> N{};",
-            edge.source().index(),
-            edge.target().index()
+            "{} -> {}",
+            ids[edge.source().index()],
+            ids[edge.target().index()]
         )?;
+        if let (Some(edge_width), Some(sizes)) = (&edge_width, &closure_sizes) {
+            write!(
+                w,
+                "[penwidth={:.2}]",
+                edge_width.width(sizes.get(edge.target()))
+            )?;
+        }
+        w.write_all(b";\n")?;
     }
     w.write_all(b"}\n")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+    use crate::dot::*;
+    use std::cell::Cell;
+
+    fn render(di: &DepInfos, options: &Options) -> String {
+        let mut out = Vec::new();
+        let closure_sizes = ClosureSizes::new(di);
+        crate::dot::render(
+            di,
+            options,
+            Some(&closure_sizes),
+            &[],
+            &Cell::new(0),
+            &mut out,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// A writer which records the size of each write
+    #[derive(Default)]
+    struct Writes(Vec<usize>, Vec<u8>);
+
+    impl std::io::Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buf.len());
+            self.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streaming() {
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8)],
+            &[(0, 1), (1, 2), (0, 3)],
+            &[0],
+        );
+        let mut w = Writes::default();
+        crate::dot::render(&di, &Options::default(), None, &[], &Cell::new(0), &mut w).unwrap();
+        let out = String::from_utf8(w.1).unwrap();
+        assert!(out.starts_with("digraph nixstore {\n"));
+        assert!(out.ends_with("}\n"));
+        assert_eq!(out.matches('{').count(), out.matches('}').count());
+        assert_eq!(out.matches(" -> ").count(), 3);
+        // no write holds a node and the next one
+        assert!(w.0.iter().all(|&len| len < out.find("\nN2[").unwrap()));
+    }
+
+    #[test]
+    fn gradient() {
+        let di = DepInfos::from_spec(&[("a", 1), ("b", 100), ("c", 50)], &[(0, 1), (0, 2)], &[0]);
+        let out = render(&di, &Options::default());
+        let extreme: RGBColor = ListedColorMap::turbo().transform_single(1.);
+        let extreme = extreme.to_string();
+        // b is the largest
+        assert!(out.contains(&format!(
+            "N1[fillcolor=\"{}\",color=\"{}\"",
+            extreme, extreme
+        )));
+        // a is a root
+        assert!(out.contains(&format!(",color=\"{}\",", ROOT_BORDER)));
+        let out = render(
+            &di,
+            &Options {
+                color: false,
+                ..Default::default()
+            },
+        );
+        assert!(!out.contains("color"));
+        assert!(out.contains("N1[label=\"b (100 B)\"];"));
+    }
+
+    #[test]
+    fn cluster_outputs() {
+        let di = DepInfos::from_spec(
+            &[("openssl-3.0.8", 1), ("openssl-3.0.8-dev", 1), ("hello", 1)],
+            &[(1, 0)],
+            &[1, 2],
+        );
+        let options = Options {
+            cluster_outputs: true,
+            ..Default::default()
+        };
+        let out = render(&di, &options);
+        assert!(out.contains("subgraph cluster_0 {\nlabel=\"openssl-3.0.8\";\nN0; N1; \n}\n"));
+        assert!(!out.contains("cluster_1"));
+        assert!(!render(&di, &Options::default()).contains("subgraph cluster"));
+    }
+
+    #[test]
+    fn cluster_by_name() {
+        let di = DepInfos::from_spec(
+            &[
+                ("openssl-3.0.8", 1),
+                ("openssl-1.1.1w-dev", 1),
+                ("gtk+-3.24", 1),
+                ("gtk+-2.24", 1),
+                ("hello", 1),
+            ],
+            &[(1, 0), (2, 3)],
+            &[1, 2, 4],
+        );
+        let options = Options {
+            cluster_by_name: true,
+            cluster_outputs: true,
+            ..Default::default()
+        };
+        let out = render(&di, &options);
+        assert!(out.contains("subgraph \"cluster_gtk+\" {\nlabel=\"gtk+\";\nN2; N3; \n}\n"));
+        assert!(out.contains("subgraph \"cluster_openssl\" {\nlabel=\"openssl\";\nN0; N1; \n}\n"));
+        assert_eq!(out.matches("subgraph").count(), 2);
+        // edges across clusters are still there
+        assert!(out.contains("N1 -> N0;"));
+    }
+
+    #[test]
+    fn closure_sizes() {
+        let di = DepInfos::from_spec(&[("a", 1), ("b", 2), ("c", 4)], &[(0, 1), (1, 2)], &[0]);
+        let options = Options {
+            bytes: true,
+            color: false,
+            closure_sizes: true,
+            ..Default::default()
+        };
+        let out = render(&di, &options);
+        assert!(out.contains("N0[label=\"a (1 B / 7 B)\"];"));
+        assert!(out.contains("N2[label=\"c (4 B / 4 B)\"];"));
+        // without closure sizes, like for --dump, only sizes are shown
+        let mut out = Vec::new();
+        crate::dot::render(&di, &options, None, &[], &Cell::new(0), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("N0[label=\"a (1 B)\"];"), "{}", out);
+    }
+
+    #[test]
+    fn color_sources() {
+        let di = DepInfos::from_spec(
+            &[("hello-2.12", 1), ("hello-2.12.tar.gz", 2)],
+            &[(0, 1)],
+            &[0],
+        );
+        let source = format!("N1[fillcolor=\"{}\"", SOURCE_FILL.to_uppercase());
+        let options = Options {
+            color_sources: true,
+            ..Default::default()
+        };
+        let out = render(&di, &options);
+        assert!(out.contains(&source), "{}", out);
+        assert!(!out.contains(&format!("N0[fillcolor=\"{}\"", SOURCE_FILL.to_uppercase())));
+        assert!(!render(&di, &Options::default()).contains(&source));
+    }
+
+    #[test]
+    fn invalid_paths() {
+        let mut di = DepInfos::from_spec(&[("a", 1)], &[], &[0]);
+        let invalid = di.graph.add_node(DepNode {
+            description: NodeDescription::Invalid(format!("/nix/store/{:032}-b", 1).into_bytes()),
+            size: 0,
+            link_path: None,
+        });
+        let out = render(&di, &Options::default());
+        let id = format!("N{}[", invalid.index());
+        assert!(out.contains(&format!("{}fillcolor=", id)), "{}", out);
+        assert_eq!(out.matches("style=\"filled,dashed\",").count(), 1);
+        assert!(!out.contains("N0[style"));
+        let options = Options {
+            color: false,
+            ..Default::default()
+        };
+        assert!(render(&di, &options).contains(&format!("{}style=dashed,label=\"b (0 B)\"]", id)));
+
+        // once reductions dropped it, it is drawn from the list of invalid paths
+        let listed = di.graph[invalid].clone();
+        let di = crate::reduction::keep_reachable(di);
+        assert!(!render(&di, &Options::default()).contains("dashed"));
+        let mut out = Vec::new();
+        let options = Options::default();
+        crate::dot::render(&di, &options, None, &[listed], &Cell::new(0), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("subgraph cluster_invalid {"), "{}", out);
+        assert!(out.contains("I0[style=dashed,label=\"b\"];"), "{}", out);
+        assert!(!out.contains("-> I0"));
+    }
+
+    #[test]
+    fn tooltips() {
+        let di = DepInfos::from_spec(&[("a", 1), ("b", 2)], &[(0, 1)], &[0]);
+        let options = Options {
+            tooltips: true,
+            ..Default::default()
+        };
+        let out = render(&di, &options);
+        assert_eq!(out.matches("tooltip=").count(), 2);
+        assert!(out.contains(&format!(
+            "tooltip=\"/nix/store/{:032}-a\\nsize: 1 B\\nclosure size: 3 B\",",
+            0
+        )));
+        assert!(out.contains("label=\"a (1 B)\""));
+        assert!(!render(&di, &Options::default()).contains("tooltip="));
+    }
+
+    #[test]
+    fn non_utf8() {
+        let mut di = DepInfos::from_spec(&[("a", 1)], &[], &[0]);
+        di.graph[petgraph::prelude::NodeIndex::new(0)].description =
+            NodeDescription::Path(b"/nix/store/00000000000000000000000000000000-\xff".to_vec());
+        let non_utf8 = Cell::new(0);
+        crate::dot::render(
+            &di,
+            &Options::default(),
+            None,
+            &[],
+            &non_utf8,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(non_utf8.get(), 1);
+    }
+
+    #[test]
+    fn stable_ids() {
+        let options = Options {
+            stable_ids: true,
+            ..Default::default()
+        };
+        let di = DepInfos::from_spec(&[("a", 1), ("b", 2), ("c", 4)], &[(0, 1), (1, 2)], &[0]);
+        let out = render(&di, &options);
+        assert_eq!(out, render(&di, &options));
+        let id = |name: &str, i: usize| {
+            format!(
+                "n_{:08x}",
+                fnv1a(format!("/nix/store/{:032}-{}", i, name).as_bytes())
+            )
+        };
+        assert!(out.contains(&format!("{} -> {};", id("a", 0), id("b", 1))));
+        assert!(!out.contains("N0"));
+
+        // the same graph built in another order has the same ids
+        let mut reversed =
+            DepInfos::from_spec(&[("c", 4), ("b", 2), ("a", 1)], &[(2, 1), (1, 0)], &[2]);
+        for (i, name) in ["c", "b", "a"].iter().enumerate() {
+            reversed.graph[petgraph::prelude::NodeIndex::new(i)].description =
+                NodeDescription::Path(format!("/nix/store/{:032}-{}", 2 - i, name).into_bytes());
+        }
+        let lines = |out: &str| {
+            let mut lines: Vec<_> = out
+                .lines()
+                .map(|l| l.trim_end_matches(' ').to_owned())
+                .collect();
+            lines.sort();
+            lines
+        };
+        assert_eq!(lines(&render(&reversed, &options)), lines(&out));
+
+        // collisions are told apart
+        let mut same = DepInfos::from_spec(&[("a", 1), ("b", 2)], &[], &[0, 1]);
+        let shared = same.graph[petgraph::prelude::NodeIndex::new(0)]
+            .description
+            .clone();
+        same.graph[petgraph::prelude::NodeIndex::new(1)].description = shared;
+        let ids = node_ids(&same, true);
+        assert_eq!(ids[1], format!("{}_1", ids[0]));
+    }
+
+    #[test]
+    fn full_paths() {
+        let di = DepInfos::from_spec(&[("a", 1), ("b", 2)], &[(0, 1)], &[0]);
+        let options = Options {
+            full_paths: true,
+            ..Default::default()
+        };
+        let out = render(&di, &options);
+        assert!(out.contains(&format!("label=\"/nix/store/{:032}-b (2 B)\"", 1)));
+        let out = render(&di, &Options::default());
+        assert!(out.contains("label=\"b (2 B)\""));
+        assert!(!out.contains("/nix/store"));
+    }
+
+    #[test]
+    fn layout() {
+        let di = DepInfos::from_spec(&[("a", 1), ("b", 2)], &[(0, 1)], &[0]);
+        let out = render(&di, &Options::default());
+        assert!(out.contains("\nrankdir=LR;\n"));
+        assert!(!out.contains("sep="));
+        let options = Options {
+            rankdir: "TB".to_owned(),
+            ranksep: Some(1.5),
+            nodesep: Some(0.1),
+            ..Default::default()
+        };
+        let out = render(&di, &options);
+        assert!(out.starts_with("digraph nixstore {\nrankdir=TB;\nranksep=1.5;\nnodesep=0.1;\n"));
+    }
+
+    #[test]
+    fn weighted_edges() {
+        // a -> b -> c and a -> d, d is heavier than c but lighter than b and c
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 1000), ("d", 100)],
+            &[(0, 1), (1, 2), (0, 3)],
+            &[0],
+        );
+        let options = Options {
+            weighted_edges: true,
+            ..Default::default()
+        };
+        let out = render(&di, &options);
+        let penwidth = |edge: &str| -> f64 {
+            let start = out.find(&format!("{}[penwidth=", edge)).unwrap() + edge.len() + 10;
+            let end = start + out[start..].find(']').unwrap();
+            out[start..end].parse().unwrap()
+        };
+        assert!(penwidth("N0 -> N1") > penwidth("N0 -> N3"));
+        assert!(penwidth("N1 -> N2") > penwidth("N0 -> N3"));
+        // d is the smallest closure, a the largest one
+        assert_eq!(penwidth("N0 -> N3"), PENWIDTH.0);
+        assert!(penwidth("N0 -> N1") < PENWIDTH.1);
+        // labels are unchanged
+        assert!(out.contains(" (1 B)\"];"));
+
+        let out = render(&di, &Options::default());
+        assert!(!out.contains("penwidth"));
+    }
+
+    #[test]
+    fn wrap_label() {
+        let wrap = |label: &str, width| {
+            String::from_utf8(super::wrap_label(label.as_bytes(), width)).unwrap()
+        };
+        assert_eq!(
+            wrap("python3.11-setuptools-69.0.2-dist", 12),
+            "python3.11-\\nsetuptools-\\n69.0.2-dist"
+        );
+        assert_eq!(wrap("gcc-wrapper-13.2.0", 100), "gcc-wrapper-13.2.0");
+        // words longer than the width are not split
+        assert_eq!(
+            wrap("texlive-combined-full", 4),
+            "texlive-\\ncombined-\\nfull"
+        );
+        assert_eq!(wrap("{memory} of 2 nodes", 9), "{memory}\\nof 2\\nnodes");
+
+        let di = DepInfos::from_spec(&[("python3.11-setuptools-69.0.2-dist", 1)], &[], &[0]);
+        let options = Options {
+            wrap_labels: Some(12),
+            ..Default::default()
+        };
+        let out = render(&di, &options);
+        assert!(out.contains("label=\"python3.11-\\nsetuptools-\\n69.0.2-dist (1 B)\""));
+        let out = render(&di, &Options::default());
+        assert!(out.contains("label=\"python3.11-setuptools-69.0.2-dist (1 B)\""));
+    }
+}
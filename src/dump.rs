@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use enum_map::enum_map;
+use petgraph::prelude::NodeIndex;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+
+const MAGIC: &[u8] = b"nix-du graph v4\n";
+
+fn write_u64<W: Write>(w: &mut W, n: u64) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u64(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+fn write_option<W: Write>(w: &mut W, n: Option<u64>) -> io::Result<()> {
+    match n {
+        None => w.write_all(&[0]),
+        Some(n) => {
+            w.write_all(&[1])?;
+            write_u64(w, n)
+        }
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut res = Vec::new();
+    // don't trust len for the allocation, a truncated file would make us allocate
+    // a lot before failing
+    r.take(len).read_to_end(&mut res)?;
+    if res.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(res)
+}
+
+fn read_option<R: Read>(r: &mut R) -> io::Result<Option<u64>> {
+    match read_u8(r)? {
+        0 => Ok(None),
+        1 => read_u64(r).map(Some),
+        n => Err(invalid_data(format!("bad option tag {}", n))),
+    }
+}
+
+fn read_index<R: Read>(r: &mut R, node_count: usize) -> io::Result<NodeIndex> {
+    let i = read_u32(r)?;
+    if i as usize >= node_count {
+        return Err(invalid_data(format!("node index {} out of bounds", i)));
+    }
+    Ok(NodeIndex::from(i))
+}
+
+impl DepInfos {
+    /// saves the graph in a compact binary format, to be read back with `load`,
+    /// so that the store needs not be read again for each analysis.
+    ///
+    /// Integers are little endian `u64`, except edge endpoints which are `u32`, and
+    /// byte strings are prefixed by their length. The file is:
+    /// - `MAGIC`
+    /// - the number of nodes, then for each node a one byte tag for the variant of
+    ///   its description, followed by its path if it has one, its size, and its
+    ///   `link_path` as a one byte flag followed by the path when present
+    /// - the index of the root
+    /// - the number of edges, then the source and target of each edge, and its
+    ///   `shared_size` as a one byte flag followed by the size when present
+    /// - the metadata: one byte for reachability, one for dedup awareness, and the
+    ///   recorded sizes, each as a one byte flag followed by the size when present
+    /// - the store root, as a one byte flag followed by the path when present
+    pub fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        use self::NodeDescription::*;
+        w.write_all(MAGIC)?;
+        write_u64(w, self.graph.node_count() as u64)?;
+        for node in self.graph.node_weights() {
+            let (tag, path) = match &node.description {
+                Path(p) => (0, Some(p)),
+                Link(p) => (1, Some(p)),
+                Dummy => (2, None),
+                FilteredOut => (3, None),
+                Others => (4, None),
+                Transient => (5, None),
+                Memory(p) => (6, Some(p)),
+                Temporary(p) => (7, Some(p)),
+                Shared(p) => (8, Some(p)),
+                Cycle(p) => (9, Some(p)),
+                Deeper => (10, None),
+                Dedup => (11, None),
+                Invalid(p) => (12, Some(p)),
+            };
+            w.write_all(&[tag])?;
+            if let Some(p) = path {
+                write_bytes(w, p)?;
+            }
+            write_u64(w, node.size)?;
+            match &node.link_path {
+                None => w.write_all(&[0])?,
+                Some(p) => {
+                    w.write_all(&[1])?;
+                    write_bytes(w, p)?;
+                }
+            }
+        }
+        write_u64(w, self.root.index() as u64)?;
+        write_u64(w, self.graph.edge_count() as u64)?;
+        for edge in self.graph.raw_edges() {
+            w.write_all(&(edge.source().index() as u32).to_le_bytes())?;
+            w.write_all(&(edge.target().index() as u32).to_le_bytes())?;
+            write_option(w, edge.weight.shared_size)?;
+        }
+        let reachable = match self.metadata.reachable {
+            Reachability::Connected => 0,
+            Reachability::Disconnected => 1,
+        };
+        let dedup = match self.metadata.dedup {
+            DedupAwareness::Aware => 0,
+            DedupAwareness::Unaware => 1,
+        };
+        w.write_all(&[reachable, dedup])?;
+        for sizes in self.metadata.size.values() {
+            for &size in sizes.values() {
+                write_option(w, size)?;
+            }
+        }
+        match &self.store_root {
+            None => w.write_all(&[0])?,
+            Some(p) => {
+                w.write_all(&[1])?;
+                write_bytes(w, p.as_os_str().as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// reads back a graph saved by `dump`
+    pub fn load<R: Read>(r: &mut R) -> io::Result<Self> {
+        use self::NodeDescription::*;
+        let mut magic = vec![0; MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("not a nix-du graph dump".into()));
+        }
+        let mut graph = DepGraph::new();
+        let node_count = read_u64(r)?;
+        if node_count > u32::MAX as u64 {
+            return Err(invalid_data(format!("too many nodes: {}", node_count)));
+        }
+        let node_count = node_count as usize;
+        for _ in 0..node_count {
+            let description = match read_u8(r)? {
+                0 => Path(read_bytes(r)?),
+                1 => Link(read_bytes(r)?),
+                2 => Dummy,
+                3 => FilteredOut,
+                4 => Others,
+                5 => Transient,
+                6 => Memory(read_bytes(r)?),
+                7 => Temporary(read_bytes(r)?),
+                8 => Shared(read_bytes(r)?),
+                9 => Cycle(read_bytes(r)?),
+                10 => Deeper,
+                11 => Dedup,
+                12 => Invalid(read_bytes(r)?),
+                n => return Err(invalid_data(format!("bad node tag {}", n))),
+            };
+            let size = read_u64(r)?;
+            let link_path = match read_u8(r)? {
+                0 => None,
+                1 => Some(read_bytes(r)?),
+                n => return Err(invalid_data(format!("bad option tag {}", n))),
+            };
+            graph.add_node(DepNode {
+                description,
+                size,
+                link_path,
+            });
+        }
+        let root = read_u64(r)?;
+        if root >= node_count as u64 {
+            return Err(invalid_data(format!("root {} out of bounds", root)));
+        }
+        let root = NodeIndex::new(root as usize);
+        for _ in 0..read_u64(r)? {
+            let from = read_index(r, node_count)?;
+            let to = read_index(r, node_count)?;
+            let shared_size = read_option(r)?;
+            graph.add_edge(from, to, Edge { shared_size });
+        }
+        let reachable = match read_u8(r)? {
+            0 => Reachability::Connected,
+            1 => Reachability::Disconnected,
+            n => return Err(invalid_data(format!("bad reachability {}", n))),
+        };
+        let dedup = match read_u8(r)? {
+            0 => DedupAwareness::Aware,
+            1 => DedupAwareness::Unaware,
+            n => return Err(invalid_data(format!("bad dedup awareness {}", n))),
+        };
+        let mut metadata = SizeMetadata {
+            reachable,
+            dedup,
+            size: enum_map! { _ => enum_map!{ _ => None }},
+        };
+        for sizes in metadata.size.values_mut() {
+            for size in sizes.values_mut() {
+                *size = read_option(r)?;
+            }
+        }
+        let store_root = match read_u8(r)? {
+            0 => None,
+            1 => Some(PathBuf::from(OsString::from_vec(read_bytes(r)?))),
+            n => return Err(invalid_data(format!("bad option tag {}", n))),
+        };
+        Ok(DepInfos {
+            graph,
+            root,
+            metadata,
+            store_root,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+    use petgraph::prelude::NodeIndex;
+
+    fn roundtrip(di: &DepInfos) {
+        let mut buf = Vec::new();
+        di.dump(&mut buf).unwrap();
+        let new = DepInfos::load(&mut buf.as_slice()).unwrap();
+        new.check_metadata();
+        assert_eq!(new.root, di.root);
+        assert_eq!(new.store_root, di.store_root);
+        assert_eq!(new.metadata.reachable, di.metadata.reachable);
+        assert_eq!(new.metadata.dedup, di.metadata.dedup);
+        assert_eq!(new.metadata.size, di.metadata.size);
+        assert_eq!(
+            new.graph.node_weights().collect::<Vec<_>>(),
+            di.graph.node_weights().collect::<Vec<_>>()
+        );
+        let edges = |di: &DepInfos| {
+            di.graph
+                .raw_edges()
+                .iter()
+                .map(|e| (e.source(), e.target(), e.weight))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(edges(&new), edges(di));
+        // a truncated dump is an error
+        for len in 0..buf.len() {
+            assert!(DepInfos::load(&mut &buf[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn dump_load() {
+        let mut di = DepInfos::fixture();
+        di.graph[NodeIndex::new(4)].link_path = Some(b"/nix/var/nix/gcroots/auto/\xff".to_vec());
+        let transient = di.graph.add_node(DepNode {
+            description: NodeDescription::Transient,
+            size: 0,
+            link_path: None,
+        });
+        di.graph.add_edge(di.root, transient, Edge::default());
+        di.store_root = Some("/mnt".into());
+        roundtrip(&di);
+    }
+
+    #[test]
+    fn load_garbage() {
+        assert!(DepInfos::load(&mut &b"digraph G {}"[..]).is_err());
+    }
+}
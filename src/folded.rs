@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph;
+use petgraph::prelude::NodeIndex;
+use std::io::{self, Write};
+
+/// writes `name` as a frame: `;` separates frames and a newline ends the line, so
+/// both are replaced by `_`.
+fn write_frame<W: Write>(w: &mut W, name: &[u8]) -> io::Result<()> {
+    let frame: Vec<u8> = name
+        .iter()
+        .map(|&c| if c == b';' || c == b'\n' { b'_' } else { c })
+        .collect();
+    w.write_all(&frame)
+}
+
+/// Outputs the graph in the folded stack format of Brendan Gregg's FlameGraph, as
+/// read by `flamegraph.pl`.
+///
+/// There is one line for each path from a root to a node, with the names along the
+/// path separated by `;`, and the size of the node in bytes. Nodes reachable by
+/// several paths are output under each of them, so the sizes of lines do not sum
+/// to the size of the store. Nodes of size 0 have no line of their own. The dummy
+/// root is not output.
+pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Result<()> {
+    let g = &dependencies.graph;
+    // nodes of the current path, to stop at cycles
+    let mut on_path = vec![false; g.node_count()];
+    let mut path = Vec::new();
+    fn visit<W: Write>(
+        g: &depgraph::DepGraph,
+        idx: NodeIndex,
+        path: &mut Vec<NodeIndex>,
+        on_path: &mut [bool],
+        w: &mut W,
+    ) -> io::Result<()> {
+        if on_path[idx.index()] {
+            return Ok(());
+        }
+        on_path[idx.index()] = true;
+        path.push(idx);
+        if g[idx].size > 0 {
+            for (i, &frame) in path.iter().enumerate() {
+                if i > 0 {
+                    w.write_all(b";")?;
+                }
+                write_frame(w, &g[frame].name())?;
+            }
+            writeln!(w, " {}", g[idx].size)?;
+        }
+        let mut children: Vec<_> = g.neighbors(idx).collect();
+        children.sort();
+        for child in children {
+            visit(g, child, path, on_path, w)?;
+        }
+        path.pop();
+        on_path[idx.index()] = false;
+        Ok(())
+    }
+    let mut roots: Vec<_> = dependencies.roots().collect();
+    roots.sort();
+    for root in roots {
+        visit(g, root, &mut path, &mut on_path, w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+
+    fn render(di: &DepInfos) -> String {
+        let mut out = Vec::new();
+        super::render(di, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn chain() {
+        let di = DepInfos::from_spec(&[("root", 1), ("a", 2), ("b", 4)], &[(0, 1), (1, 2)], &[0]);
+        assert_eq!(render(&di), "root 1\nroot;a 2\nroot;a;b 4\n");
+    }
+
+    #[test]
+    fn shared() {
+        // roots a and b share c, d has size 0 and no line of its own
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b;\n", 2), ("c", 4), ("d", 0)],
+            &[(0, 2), (1, 2), (1, 3)],
+            &[0, 1],
+        );
+        assert_eq!(render(&di), "a 1\na;c 4\nb__ 2\nb__;c 4\n");
+    }
+}
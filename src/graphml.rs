@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph;
+use petgraph::visit::IntoNodeReferences;
+use std::cell::Cell;
+use std::io::{self, Write};
+
+/// writes `s` escaped for XML text and attribute values. Invalid UTF-8 and
+/// characters not allowed in XML 1.0 are replaced by U+FFFD.
+fn write_escaped<W: Write>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '&' => w.write_all(b"&amp;")?,
+            '<' => w.write_all(b"&lt;")?,
+            '>' => w.write_all(b"&gt;")?,
+            '"' => w.write_all(b"&quot;")?,
+            '\'' => w.write_all(b"&apos;")?,
+            '\t' | '\n' | '\r' => write!(w, "&#{};", c as u32)?,
+            c if (c as u32) < 0x20 || c == '\u{fffe}' || c == '\u{ffff}' => {
+                w.write_all("\u{fffd}".as_bytes())?
+            }
+            c => write!(w, "{}", c)?,
+        }
+    }
+    Ok(())
+}
+
+/// Outputs the graph in the GraphML format, as read by Gephi or yEd.
+///
+/// Nodes have the attributes `name` (a string), `size` (in bytes, as a `long`)
+/// and `is_root` (whether they are gc roots). The dummy root is not output, like
+/// for DOT output. Node `N{i}` is the node of `NodeIndex` `i`. Names which are not
+/// valid UTF-8 are approximated, and counted in `non_utf8`.
+pub fn render<W: Write>(
+    dependencies: &depgraph::DepInfos,
+    non_utf8: &Cell<usize>,
+    w: &mut W,
+) -> io::Result<()> {
+    let mut is_root = vec![false; dependencies.graph.node_count()];
+    for idx in dependencies.roots() {
+        is_root[idx.index()] = true;
+    }
+    w.write_all(
+        br#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+<key id="name" for="node" attr.name="name" attr.type="string"/>
+<key id="size" for="node" attr.name="size" attr.type="long"/>
+<key id="is_root" for="node" attr.name="is_root" attr.type="boolean"><default>false</default></key>
+<graph id="nixstore" edgedefault="directed">
+"#,
+    )?;
+    for (idx, node) in dependencies.graph.node_references() {
+        if idx == dependencies.root {
+            continue;
+        }
+        if !node.is_utf8() {
+            non_utf8.set(non_utf8.get() + 1);
+        }
+        write!(w, "<node id=\"N{}\"><data key=\"name\">", idx.index())?;
+        write_escaped(w, &node.name())?;
+        write!(w, "</data><data key=\"size\">{}</data>", node.size)?;
+        if is_root[idx.index()] {
+            w.write_all(b"<data key=\"is_root\">true</data>")?;
+        }
+        w.write_all(b"</node>\n")?;
+    }
+    for edge in dependencies.graph.raw_edges() {
+        if edge.source() == dependencies.root {
+            continue;
+        }
+        writeln!(
+            w,
+            "<edge source=\"N{}\" target=\"N{}\"/>",
+            edge.source().index(),
+            edge.target().index()
+        )?;
+    }
+    w.write_all(b"</graph>\n</graphml>\n")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+    use std::collections::BTreeSet;
+
+    /// the tags of `xml`, as (name, attributes), ignoring text, for a document where
+    /// `<` and `>` only occur as delimiters of tags
+    fn tags(xml: &str) -> Vec<(&str, Vec<(&str, &str)>)> {
+        let mut result = Vec::new();
+        for chunk in xml.split('<').skip(1) {
+            let tag = chunk.split('>').next().unwrap().trim_end_matches('/');
+            let mut parts = tag.splitn(2, ' ');
+            let name = parts.next().unwrap();
+            let mut attrs = Vec::new();
+            let mut rest = parts.next().unwrap_or("").trim();
+            while let Some(eq) = rest.find("=\"") {
+                let key = &rest[..eq];
+                let value_len = rest[eq + 2..].find('"').unwrap();
+                attrs.push((key, &rest[eq + 2..eq + 2 + value_len]));
+                rest = rest[eq + 2 + value_len + 1..].trim_start();
+            }
+            result.push((name, attrs));
+        }
+        result
+    }
+
+    #[test]
+    fn parse() {
+        let di = DepInfos::fixture();
+        let mut out = Vec::new();
+        let non_utf8 = std::cell::Cell::new(0);
+        super::render(&di, &non_utf8, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let tags = tags(&out);
+        let attr = |attrs: &[(&str, &str)], key: &str| {
+            attrs
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        };
+        let nodes: BTreeSet<_> = tags
+            .iter()
+            .filter(|(name, _)| *name == "node")
+            .map(|(_, attrs)| attr(attrs, "id").unwrap())
+            .collect();
+        // the dummy root and its edges are not output
+        assert_eq!(nodes.len(), di.graph.node_count() - 1);
+        assert!(!nodes.contains(&format!("N{}", di.root.index())));
+        let edges: Vec<_> = tags.iter().filter(|(name, _)| *name == "edge").collect();
+        assert_eq!(edges.len(), di.graph.edge_count() - di.roots().count());
+        for (_, attrs) in edges {
+            assert!(nodes.contains(&attr(attrs, "source").unwrap()));
+            assert!(nodes.contains(&attr(attrs, "target").unwrap()));
+        }
+        // every tag is closed
+        let opened = tags.iter().filter(|(name, _)| *name == "data").count();
+        let closed = tags.iter().filter(|(name, _)| *name == "/data").count();
+        assert_eq!(opened, closed);
+        assert_eq!(opened, 2 * nodes.len() + di.roots().count());
+    }
+
+    #[test]
+    fn render() {
+        let mut out = Vec::new();
+        let non_utf8 = std::cell::Cell::new(0);
+        super::render(&DepInfos::fixture(), &non_utf8, &mut out).unwrap();
+        assert_eq!(non_utf8.get(), 1);
+        let expected = r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+<key id="name" for="node" attr.name="name" attr.type="string"/>
+<key id="size" for="node" attr.name="size" attr.type="long"/>
+<key id="is_root" for="node" attr.name="is_root" attr.type="boolean"><default>false</default></key>
+<graph id="nixstore" edgedefault="directed">
+<node id="N0"><data key="name">a</data><data key="size">1</data><data key="is_root">true</data></node>
+<node id="N1"><data key="name">b&quot;&lt;&amp;&gt;,\&#10;</data><data key="size">2</data></node>
+<node id="N2"><data key="name">c</data><data key="size">4</data></node>
+<node id="N4"><data key="name">/roots/��</data><data key="size">8</data><data key="is_root">true</data></node>
+<node id="N5"><data key="name">shared:c</data><data key="size">16</data></node>
+<edge source="N0" target="N1"/>
+<edge source="N0" target="N2"/>
+<edge source="N1" target="N2"/>
+<edge source="N4" target="N2"/>
+<edge source="N2" target="N5"/>
+</graph>
+</graphml>
+"#;
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
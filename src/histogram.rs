@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph;
+use crate::msg::format_size;
+use std::io::{self, Write};
+
+/// Options for the histogram output
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// the ratio between the bounds of consecutive buckets, at least 2
+    pub base: u64,
+    /// print sizes as a number of bytes instead of human readable sizes
+    pub bytes: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            base: 2,
+            bytes: false,
+        }
+    }
+}
+
+/// The nodes whose size is in `lower..lower * base`, or of size 0 when `lower` is 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bucket {
+    pub lower: u64,
+    /// the number of nodes in this bucket
+    pub count: usize,
+    /// the sum of their sizes
+    pub total: u64,
+}
+
+/// the index of the bucket of `size`: 0 for size 0, and then 1 + the integer
+/// logarithm of `size` in base `base`.
+fn bucket_of(size: u64, base: u64) -> u32 {
+    let mut res = 0;
+    let mut bound = 1u64;
+    while size >= bound {
+        res += 1;
+        bound = match bound.checked_mul(base) {
+            Some(b) => b,
+            None => break,
+        };
+    }
+    res
+}
+
+/// the lower bound of bucket `i`, see `bucket_of`
+fn lower_bound(i: u32, base: u64) -> u64 {
+    match i {
+        0 => 0,
+        i => base.pow(i - 1),
+    }
+}
+
+/// Puts the nodes of the graph but the dummy root in buckets by size, in one pass.
+/// Returns the buckets from the one of the smallest node to the one of the largest
+/// node, including empty ones in between.
+///
+/// Panics if `base` is less than 2.
+pub fn buckets(dependencies: &depgraph::DepInfos, base: u64) -> Vec<Bucket> {
+    assert!(base >= 2, "histogram base must be at least 2");
+    let mut res: Vec<Bucket> = Vec::new();
+    // index in res of bucket 0
+    let mut first = 0;
+    for idx in dependencies.graph.node_indices() {
+        if idx == dependencies.root {
+            continue;
+        }
+        let size = dependencies.graph[idx].size;
+        let i = bucket_of(size, base);
+        if res.is_empty() {
+            first = i;
+        }
+        while i < first {
+            first -= 1;
+            res.insert(
+                0,
+                Bucket {
+                    lower: lower_bound(first, base),
+                    count: 0,
+                    total: 0,
+                },
+            );
+        }
+        while (i - first) as usize >= res.len() {
+            res.push(Bucket {
+                lower: lower_bound(first + res.len() as u32, base),
+                count: 0,
+                total: 0,
+            });
+        }
+        let bucket = &mut res[(i - first) as usize];
+        bucket.count += 1;
+        bucket.total += size;
+    }
+    res
+}
+
+/// Outputs how many nodes there are by size, a line per bucket (see `buckets`)
+/// with its bounds, the number of nodes, their total size and a bar proportional
+/// to this number.
+pub fn render<W: Write>(
+    dependencies: &depgraph::DepInfos,
+    options: &Options,
+    w: &mut W,
+) -> io::Result<()> {
+    const WIDTH: usize = 40;
+    let format = |size: u64| {
+        if options.bytes {
+            format!("{} B", size)
+        } else {
+            format_size(size)
+        }
+    };
+    let buckets = buckets(dependencies, options.base);
+    let max = buckets.iter().map(|b| b.count).max().unwrap_or(0);
+    for bucket in buckets {
+        let range = match bucket.lower {
+            0 => format(0),
+            lower => match lower.checked_mul(options.base) {
+                Some(upper) => format!("{} - {}", format(lower), format(upper)),
+                None => format!("{} -", format(lower)),
+            },
+        };
+        // a non empty bucket has at least one character
+        let bar = (bucket.count * WIDTH).div_ceil(max);
+        writeln!(
+            w,
+            "{:>24} {:>8} {:>10} {}",
+            range,
+            bucket.count,
+            format(bucket.total),
+            "#".repeat(bar)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::depgraph::*;
+
+    #[test]
+    fn bucket_of() {
+        assert_eq!(super::bucket_of(0, 2), 0);
+        assert_eq!(super::bucket_of(1, 2), 1);
+        assert_eq!(super::bucket_of(2, 2), 2);
+        assert_eq!(super::bucket_of(3, 2), 2);
+        assert_eq!(super::bucket_of(4, 2), 3);
+        assert_eq!(super::bucket_of(999, 10), 3);
+        assert_eq!(super::bucket_of(1000, 10), 4);
+        assert_eq!(super::bucket_of(u64::MAX, 2), 64);
+        for i in 0..=40 {
+            assert_eq!(super::bucket_of(lower_bound(i, 3), 3), i);
+        }
+    }
+
+    #[test]
+    fn buckets() {
+        let di = DepInfos::from_spec(
+            &[("a", 5), ("b", 6), ("c", 7), ("d", 40), ("e", 4), ("f", 63)],
+            &[],
+            &[0],
+        );
+        let bucket = |lower, count, total| Bucket {
+            lower,
+            count,
+            total,
+        };
+        assert_eq!(
+            super::buckets(&di, 2),
+            vec![
+                bucket(4, 4, 4 + 5 + 6 + 7),
+                bucket(8, 0, 0),
+                bucket(16, 0, 0),
+                bucket(32, 2, 40 + 63),
+            ]
+        );
+        assert_eq!(
+            super::buckets(&di, 10),
+            vec![bucket(1, 4, 4 + 5 + 6 + 7), bucket(10, 2, 40 + 63)]
+        );
+
+        let options = Options {
+            bytes: true,
+            ..Options::default()
+        };
+        let mut out = Vec::new();
+        render(&di, &options, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out
+            .lines()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0][..8], ["4", "B", "-", "8", "B", "4", "22", "B"]);
+        assert_eq!(lines[0][8].len(), 40);
+        assert_eq!(lines[1].len(), 8);
+        assert_eq!(lines[3][8].len(), 20);
+
+        let empty = DepInfos::from_spec(&[], &[], &[]);
+        assert!(super::buckets(&empty, 2).is_empty());
+    }
+}
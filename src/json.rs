@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph;
+use petgraph::visit::{EdgeRef, IntoNodeReferences};
+use std::io::{self, Write};
+
+/// writes `s` as a JSON string literal
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    w.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => w.write_all(b"\\\"")?,
+            '\\' => w.write_all(b"\\\\")?,
+            '\n' => w.write_all(b"\\n")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    w.write_all(b"\"")
+}
+
+/// writes `s` as a JSON string if it is valid UTF-8, and as an array of
+/// bytes otherwise, so that no information is lost.
+fn write_bytes<W: Write>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    match std::str::from_utf8(s) {
+        Ok(s) => write_str(w, s),
+        Err(_) => {
+            w.write_all(b"[")?;
+            for (i, byte) in s.iter().enumerate() {
+                if i > 0 {
+                    w.write_all(b",")?;
+                }
+                write!(w, "{}", byte)?;
+            }
+            w.write_all(b"]")
+        }
+    }
+}
+
+/// Outputs the graph as a JSON object `{"root": i, "nodes": [...]}`.
+///
+/// The node at position `i` in `nodes` is the node of `NodeIndex` `i`, and is an
+/// object with fields `kind`, `path` (`null` for nodes without path), `name`,
+/// `size`, `is_root` (whether it is a child of the root), `edges` (the indices
+/// of its children) and `shared_sizes` (for each of these edges, its
+/// `Edge::shared_size`, or `null`).
+/// `path` and `name` are strings when they are valid UTF-8, and arrays of bytes
+/// otherwise.
+pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Result<()> {
+    let mut is_root = vec![false; dependencies.graph.node_count()];
+    for idx in dependencies.roots() {
+        is_root[idx.index()] = true;
+    }
+    writeln!(w, "{{\"root\":{},\"nodes\":[", dependencies.root.index())?;
+    for (idx, node) in dependencies.graph.node_references() {
+        if idx.index() > 0 {
+            w.write_all(b",\n")?;
+        }
+        write!(w, "{{\"kind\":\"{:?}\",\"path\":", node.kind())?;
+        match node.description.path() {
+            Some(p) => write_bytes(w, p)?,
+            None => w.write_all(b"null")?,
+        }
+        w.write_all(b",\"name\":")?;
+        write_bytes(w, &node.name())?;
+        write!(
+            w,
+            ",\"size\":{},\"is_root\":{},\"edges\":[",
+            node.size,
+            is_root[idx.index()]
+        )?;
+        // edges are iterated in reverse insertion order
+        let mut edges: Vec<_> = dependencies.graph.edges(idx).collect();
+        edges.reverse();
+        for (i, edge) in edges.iter().enumerate() {
+            if i > 0 {
+                w.write_all(b",")?;
+            }
+            write!(w, "{}", edge.target().index())?;
+        }
+        w.write_all(b"],\"shared_sizes\":[")?;
+        for (i, edge) in edges.iter().enumerate() {
+            if i > 0 {
+                w.write_all(b",")?;
+            }
+            match edge.weight().shared_size {
+                Some(size) => write!(w, "{}", size)?,
+                None => w.write_all(b"null")?,
+            }
+        }
+        w.write_all(b"]}")?;
+    }
+    w.write_all(b"\n]}\n")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+    use petgraph::prelude::NodeIndex;
+
+    /// whether the `is_root` field of each node of `json` says whether it is a
+    /// child of the root, in the graph rebuilt from the `edges` indices
+    fn roots_attr_coherent(json: &serde_json::Value) -> bool {
+        let nodes = json["nodes"].as_array().unwrap();
+        let mut graph = petgraph::graph::DiGraph::<(), ()>::new();
+        for _ in nodes {
+            graph.add_node(());
+        }
+        for (i, node) in nodes.iter().enumerate() {
+            for child in node["edges"].as_array().unwrap() {
+                let child = child.as_u64().unwrap() as usize;
+                graph.add_edge(NodeIndex::new(i), NodeIndex::new(child), ());
+            }
+        }
+        let root = NodeIndex::new(json["root"].as_u64().unwrap() as usize);
+        nodes.iter().enumerate().all(|(i, node)| {
+            node["is_root"].as_bool().unwrap() == graph.contains_edge(root, NodeIndex::new(i))
+        })
+    }
+
+    #[test]
+    fn roundtrip() {
+        let di = DepInfos::fixture();
+        let json: serde_json::Value = serde_json::from_slice(&di.to_json()).unwrap();
+        assert!(roots_attr_coherent(&json));
+        let nodes = json["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len(), di.graph.node_count());
+        let edges: usize = nodes
+            .iter()
+            .map(|node| node["edges"].as_array().unwrap().len())
+            .sum();
+        assert_eq!(edges, di.graph.edge_count());
+        assert_eq!(nodes[2]["shared_sizes"], serde_json::json!([16]));
+        assert_eq!(nodes[0]["shared_sizes"], serde_json::json!([null, null]));
+        for (idx, node) in di.graph.node_indices().zip(nodes) {
+            assert_eq!(node["size"].as_u64(), Some(di.graph[idx].size));
+        }
+        // the non UTF-8 link is kept as bytes
+        assert_eq!(
+            nodes[4]["path"],
+            serde_json::json!([47, 114, 111, 111, 116, 115, 47, 255, 1])
+        );
+        assert_eq!(nodes[1]["name"], "b\"<&>,\\\n");
+    }
+
+    #[test]
+    fn render() {
+        let json = DepInfos::fixture().to_json();
+        let expected = r#"{"root":3,"nodes":[
+{"kind":"Path","path":"/nix/store/00000000000000000000000000000000-a","name":"a","size":1,"is_root":true,"edges":[1,2],"shared_sizes":[null,null]},
+{"kind":"Path","path":"/nix/store/00000000000000000000000000000001-b\"<&>,\\\n","name":"b\"<&>,\\\n","size":2,"is_root":false,"edges":[2],"shared_sizes":[null]},
+{"kind":"Path","path":"/nix/store/00000000000000000000000000000002-c","name":"c","size":4,"is_root":false,"edges":[5],"shared_sizes":[16]},
+{"kind":"Dummy","path":null,"name":"{dummy}","size":0,"is_root":false,"edges":[0,4],"shared_sizes":[null,null]},
+{"kind":"Link","path":[47,114,111,111,116,115,47,255,1],"name":[47,114,111,111,116,115,47,255,1],"size":8,"is_root":true,"edges":[2],"shared_sizes":[null]},
+{"kind":"Shared","path":"shared:c","name":"shared:c","size":16,"is_root":false,"edges":[],"shared_sizes":[]}
+]}
+"#;
+        assert_eq!(String::from_utf8(json).unwrap(), expected);
+    }
+}
@@ -1,32 +1,44 @@
 // SPDX-License-Identifier: LGPL-3.0
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use enum_map::enum_map;
 
 #[macro_use]
 pub mod msg;
+pub mod analyze;
+pub mod annotations;
 pub mod bindings;
+pub mod csv;
 pub mod depgraph;
+pub mod diff;
+pub mod dominators;
 pub mod dot;
+pub mod dump;
+pub mod folded;
+pub mod graphml;
+pub mod histogram;
+pub mod json;
+pub mod nix_graph;
 pub mod opt;
+pub mod output;
+pub mod path_info;
+pub mod paths;
 pub mod reduction;
+#[cfg(feature = "serde")]
+pub mod serialization;
+pub mod transform;
+pub mod tree;
 use crate::msg::*;
 use bytesize::ByteSize;
-use std::ffi::OsString;
-use std::io;
+use std::cell::Cell;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 
 /* so that these functions are available in libnix_adepter.a */
 pub use crate::depgraph::{register_edge, register_node};
 
-#[derive(Debug, Eq, PartialEq)]
-enum StatOpts {
-    Full,
-    Alive,
-}
-
-type OptLevel = Option<StatOpts>;
-
 fn print_stats<W: io::Write>(w: &mut W, g: &depgraph::DepInfos) -> io::Result<()> {
     use crate::depgraph::DedupAwareness::*;
     use crate::depgraph::Reachability::*;
@@ -64,6 +76,37 @@ fn print_stats<W: io::Write>(w: &mut W, g: &depgraph::DepInfos) -> io::Result<()
     Ok(())
 }
 
+fn print_cycles<W: io::Write>(w: &mut W, g: &depgraph::DepInfos) -> io::Result<()> {
+    let cycles = g.cycles();
+    writeln!(w, "{} dependency cycles", cycles.len())?;
+    for cycle in cycles {
+        writeln!(w, "Cycle of {} paths:", cycle.len())?;
+        for idx in cycle {
+            w.write_all(b"\t")?;
+            let node = &g.graph[idx];
+            w.write_all(node.description.path().map_or(&node.name(), |p| p))?;
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+/// returns the gc root of `g` whose link is `path`, relative to the current directory,
+/// or dies. Gc roots are symlinks, so `path` is not canonicalized.
+fn find_root(g: &depgraph::DepInfos, path: &std::path::Path) -> petgraph::prelude::NodeIndex {
+    let cwd = std::env::current_dir()
+        .unwrap_or_else(|e| die!(1, "Could not get the current directory: {}", e));
+    let path = cwd.join(path);
+    let path = path.as_os_str().as_bytes();
+    g.roots()
+        .find(|&idx| {
+            let node = &g.graph[idx];
+            node.description.path().map(Vec::as_slice) == Some(path)
+                || node.link_path.as_deref() == Some(path)
+        })
+        .unwrap_or_else(|| die!(1, "{} is not a gc root", path.escape_ascii()))
+}
+
 const LONG_ABOUT: &'static str = "
 This program outputs a graph on stdout in the dot format which may help you figuring out which \
 gc-roots should be removed in order to reclaim space in the nix store.
@@ -98,6 +141,14 @@ struct Args {
     #[clap(short = 's', long, value_name = "SIZE")]
     min_size: Option<ByteSize>,
 
+    /// Before hiding nodes, hide the dependencies whose closure is smaller than SIZE, adding their size to their nearest remaining parent
+    #[clap(long, value_name = "SIZE")]
+    min_subtree_size: Option<ByteSize>,
+
+    /// Before hiding nodes, fold the leaves below SIZE into their parents, repeatedly, keeping their size
+    #[clap(long, value_name = "SIZE")]
+    fold_leaves_below: Option<ByteSize>,
+
     /// Only keep the approximately N biggest nodes
     #[clap(short = 'n', long, value_name = "N", conflicts_with = "min_size")]
     nodes: Option<u32>,
@@ -106,30 +157,264 @@ struct Args {
     #[clap(short = 'r', long, value_name = "PATH")]
     root: Option<PathBuf>,
 
-    /// Dump the unaltered graph read from store to the file passed as argument. Intended for debugging.
+    /// Only read the paths reachable from gc roots instead of the whole store: faster, but the size of dead paths is not reported
+    #[clap(long, conflicts_with_all = &["root", "load_from"])]
+    live_only: bool,
+
+    /// Also read the derivations (.drv files) of store paths and their build-time dependencies, as if keep-derivations was set in nix.conf
+    #[clap(long, conflicts_with = "load_from")]
+    derivations: bool,
+
+    /// Read the store at URI instead of the default one. For ssh://[USER@]HOST, nix path-info is run on HOST with ssh, and gc roots are not known
+    #[clap(long, value_name = "URI", conflicts_with = "load_from")]
+    store: Option<String>,
+
+    /// Dump the unaltered graph read from store to the file passed as argument, without closure sizes. Intended for debugging.
     #[clap(long, value_name = "FILE")]
     dump: Option<PathBuf>,
 
+    /// Save the graph read from store to FILE in a compact binary format, to be read back with --load-from
+    #[clap(long, value_name = "FILE")]
+    dump_to: Option<PathBuf>,
+
+    /// Read the graph from FILE, saved with --dump-to, instead of the store
+    #[clap(long, value_name = "FILE")]
+    load_from: Option<PathBuf>,
+
+    /// Print the N gc roots whose deletion alone would free the most space, with this space, instead of the graph
+    #[clap(long, value_name = "N", conflicts_with_all = &["why", "diff_from"])]
+    top_roots: Option<usize>,
+
+    /// Print the store paths in the closure of gc root NEW but not in that of gc root OLD, like two generations of a profile, instead of the graph
+    #[clap(long, num_args = 2, value_names = &["OLD", "NEW"], conflicts_with_all = &["why", "diff_from", "top_roots"])]
+    compare_roots: Option<Vec<PathBuf>>,
+
+    /// Print what changed since the graph saved with --dump-to in FILE, instead of the graph
+    #[clap(long, value_name = "FILE", conflicts_with = "why")]
+    diff_from: Option<PathBuf>,
+
     /// whether to take store optimisation into account: 0: no, 1: live paths, 2: all paths (default autodetect)
     #[clap(short='O', long, value_name="N", value_parser = ["0", "1", "2", "auto"])]
     opt_level: Option<String>,
 
+    /// When detecting store optimisation, without -O, check N files of /nix/store/.links chosen at random
+    #[clap(long, value_name = "N", default_value_t = opt::DEFAULT_PROBES, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    optimisation_probes: usize,
+
+    /// Count the size of the symlinks of gc roots outside of the store, which nix reports as 0. Has no effect with -O0
+    #[clap(long)]
+    root_links: bool,
+
+    /// Count the size of directories in store paths, like du does. Has no effect with -O0
+    #[clap(long)]
+    directory_sizes: bool,
+
+    /// Round the size of each file up to a multiple of SIZE, the block size of the filesystem (typically 4KiB), to match du. Has no effect with -O0
+    #[clap(long, value_name = "SIZE")]
+    block_size: Option<ByteSize>,
+
+    /// Walk N store paths at once, by default as many as CPUs; lower it on spinning disks. Has no effect with -O0
+    #[clap(short = 'j', long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    jobs: Option<usize>,
+
+    /// Print the paths of each dependency cycle of the store on stderr, which could indicate corruption
+    #[clap(long)]
+    report_cycles: bool,
+
+    /// Treat store path PATH as a gc root, for example to see what deleting other roots would free while keeping it; can be repeated
+    #[clap(long, value_name = "PATH")]
+    pin: Vec<PathBuf>,
+
+    /// Print the gc roots keeping PATH alive, instead of the graph
+    #[clap(long, value_name = "PATH", conflicts_with = "root")]
+    why: Option<PathBuf>,
+
+    /// Label indirect gc roots with the last link of their symlink chain, for example a
+    /// profile generation instead of /nix/var/nix/gcroots/auto/<hash>
+    #[clap(long)]
+    resolve_roots: bool,
+
+    /// Prefix of the names of nodes for files shared by several store paths because of store optimisation. If it starts with {, names are closed by }
+    #[clap(long, value_name = "PREFIX", default_value = "shared:")]
+    shared_prefix: String,
+
+    /// Ignore in memory and temporary roots, ie. running processes and builds
+    #[clap(long)]
+    no_transient: bool,
+
+    /// Only show what deleting the gc root ROOT would free, ie. what no other gc root keeps alive; can be repeated
+    #[clap(long, value_name = "ROOT", conflicts_with = "root")]
+    reclaimable_from: Vec<PathBuf>,
+
+    /// Remove the store paths whose whole name matches REGEX, like .*-doc, except gc roots; can be repeated
+    #[clap(long, value_name = "REGEX")]
+    exclude: Vec<String>,
+
+    /// What to do with the size of paths removed by --exclude: drop it, divide it among their parents, or give it to their nearest kept ancestor
+    #[clap(long, value_name = "POLICY", value_parser = ["drop", "parents", "nearest"], default_value = "drop")]
+    fold_excluded: String,
+
+    /// Merge store paths of the same package and output, whatever their version or hash
+    #[clap(long)]
+    group_by_name: bool,
+
+    /// Gather the files shared because of store optimisation in a single {dedup} root instead of one node per set of owners
+    #[clap(long)]
+    merge_shared: bool,
+
+    /// In the dot output, draw the outputs of a package (like foo and foo-dev) together
+    #[clap(long)]
+    cluster_outputs: bool,
+
+    /// In the dot output, draw the store paths with the same name but different versions or outputs together
+    #[clap(long, conflicts_with = "cluster_outputs")]
+    cluster_by_name: bool,
+
+    /// In the dot output, show the closure size of each node after its own size
+    #[clap(long)]
+    closure_sizes: bool,
+
+    /// In the dot output, fill the store paths which look like sources (source, *-src, tarballs...) in gray, whatever their size
+    #[clap(long)]
+    color_sources: bool,
+
+    /// In the dot output, show the full path, size and closure size of nodes when hovering them in SVG
+    #[clap(long)]
+    tooltips: bool,
+
+    /// In the dot output, name nodes after a hash of their path instead of their index, so that renders of different graphs are easier to diff
+    #[clap(long)]
+    dot_stable_ids: bool,
+
+    /// In the dot output, label nodes with their full store path instead of their name
+    #[clap(long)]
+    full_paths: bool,
+
+    /// In the dot output, draw edges thicker when what they lead to is larger
+    #[clap(long)]
+    weighted_edges: bool,
+
+    /// In the dot output, the direction of the layout: from left to right, top to bottom...
+    #[clap(long, value_name = "DIR", value_parser = ["LR", "TB", "RL", "BT"], default_value = "LR")]
+    dot_rankdir: String,
+
+    /// In the dot output, the space between ranks of nodes, in inches (ranksep in graphviz)
+    #[clap(long, value_name = "INCHES")]
+    dot_ranksep: Option<f64>,
+
+    /// In the dot output, the space between nodes of the same rank, in inches (nodesep in graphviz)
+    #[clap(long, value_name = "INCHES")]
+    dot_nodesep: Option<f64>,
+
+    /// In the dot output, wrap the names of nodes to lines of about N characters, after dashes and at spaces
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    wrap_labels: Option<u64>,
+
+    /// Write the graph to FILE instead of stdout; FILE is only replaced once the graph is completely written
+    #[clap(short = 'o', long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Output format
+    #[clap(long, value_name = "FORMAT", value_parser = ["dot", "json", "graphml", "csv", "tree", "folded", "paths", "histogram"], default_value = "dot")]
+    format: String,
+
+    /// With --format histogram, the ratio between the sizes bounding consecutive buckets
+    #[clap(long, value_name = "N", default_value = "2", value_parser = clap::value_parser!(u64).range(2..))]
+    histogram_base: u64,
+
+    /// With --format tree, do not show nodes deeper than N below the roots
+    #[clap(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Merge the nodes deeper than N below the roots into one {deeper} node below each node at depth N
+    #[clap(long, value_name = "N")]
+    truncate_depth: Option<usize>,
+
+    /// Whether to color nodes according to their size in the dot output
+    #[clap(long, value_name = "WHEN", value_parser = ["always", "never"], default_value = "always")]
+    color: String,
+
+    /// Print sizes in bytes instead of human readable sizes
+    #[clap(long)]
+    bytes: bool,
+
     /// Don't print informationnal messages on stderr
     #[clap(short = 'q', long)]
     quiet: bool,
+
+    /// Print more details on stderr, like the store paths walked with -O
+    #[clap(short = 'v', long, conflicts_with = "quiet")]
+    verbose: bool,
 }
 
 fn main() {
-    let args = Args::parse();
-
-    let optlevel: Option<OptLevel> = match args.opt_level.as_ref().map(String::as_str) {
-        Some("0") => Some(None),
-        Some("1") => Some(Some(StatOpts::Alive)),
-        Some("2") => Some(Some(StatOpts::Full)),
-        Some("auto") | None => None,
+    // the version of nix matters for bug reports
+    let long_version = format!(
+        "{}, using nix {}",
+        env!("CARGO_PKG_VERSION"),
+        depgraph::DepInfos::backend_version()
+    );
+    let args = Args::command()
+        .long_version(&*Box::leak(long_version.into_boxed_str()))
+        .get_matches();
+    let args = Args::from_arg_matches(&args).unwrap_or_else(|e| e.exit());
+
+    let optimisation = match args.opt_level.as_ref().map(String::as_str) {
+        Some("0") => analyze::Optimisation::Ignore,
+        Some("1") => analyze::Optimisation::Alive,
+        Some("2") => analyze::Optimisation::All,
+        Some("auto") | None => analyze::Optimisation::Auto,
         _ => unreachable!(),
     };
+    let dot_options = dot::Options {
+        bytes: args.bytes,
+        color: match args.color.as_str() {
+            "always" => true,
+            "never" => false,
+            _ => unreachable!(),
+        },
+        cluster_outputs: args.cluster_outputs,
+        cluster_by_name: args.cluster_by_name,
+        closure_sizes: args.closure_sizes,
+        color_sources: args.color_sources,
+        tooltips: args.tooltips,
+        stable_ids: args.dot_stable_ids,
+        full_paths: args.full_paths,
+        weighted_edges: args.weighted_edges,
+        rankdir: args.dot_rankdir.clone(),
+        ranksep: args.dot_ranksep,
+        nodesep: args.dot_nodesep,
+        wrap_labels: args.wrap_labels.map(|n| n as usize),
+    };
+    // the host of a ssh store
+    let remote: Option<&str> = args.store.as_deref().and_then(|uri| {
+        let host = uri
+            .strip_prefix("ssh://")
+            .or_else(|| uri.strip_prefix("ssh-ng://"))?;
+        if host.contains('?') {
+            die!(
+                1,
+                "Options of ssh stores are not supported, set them in ~/.ssh/config instead"
+            );
+        }
+        Some(host)
+    });
+    if remote.is_some() {
+        if args.live_only {
+            die!(1, "--live-only is not supported with ssh stores");
+        }
+        if args.derivations {
+            die!(1, "--derivations is not supported with ssh stores");
+        }
+        if matches!(args.opt_level.as_deref(), Some("1") | Some("2")) {
+            die!(1, "-O is not supported with ssh stores");
+        }
+    }
     let root: Option<OsString> = args.root.as_ref().map(|path| {
+        if remote.is_some() {
+            // the path is on the remote machine
+            return path.clone().into_os_string();
+        }
         let path_buf = PathBuf::from(path).canonicalize().unwrap_or_else(|err| {
             die!(
                 1,
@@ -147,59 +432,221 @@ fn main() {
         (f, path)
     });
 
-    set_quiet(args.quiet);
+    let dump_to: Option<(std::fs::File, &PathBuf)> = args.dump_to.as_ref().map(|path| {
+        let f = std::fs::File::create(path).unwrap_or_else(|err| {
+            die!(1, "Could not open dump file «{}»: {}", path.display(), err)
+        });
+        (f, path)
+    });
+
+    set_verbosity(if args.quiet {
+        Verbosity::Quiet
+    } else if args.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    });
 
     /**************************************
      * end argument parsing               *
      **************************************/
 
-    msg!("Reading dependency graph from store... ");
-    let mut g = depgraph::DepInfos::read_from_store(root)
-        .unwrap_or_else(|res| die!(res, "Could not read from store"));
-    msg!(
-        "{} nodes, {} edges read.\n",
-        g.graph.node_count(),
-        g.graph.edge_count()
-    );
+    let mut g = match args.load_from.as_ref() {
+        Some(path) => {
+            msg!("Reading dependency graph from {}... ", path.display());
+            let g = std::fs::File::open(path)
+                .and_then(|f| depgraph::DepInfos::load(&mut io::BufReader::new(f)))
+                .unwrap_or_else(|err| die!(1, "Could not load «{}»: {}", path.display(), err));
+            match root {
+                // the store is not read, so restrict the graph ourselves
+                Some(root) => {
+                    let idx = g.find_by_path(root.as_bytes()).unwrap_or_else(|| {
+                        die!(
+                            1,
+                            "{} is not in {}",
+                            root.as_bytes().escape_ascii(),
+                            path.display()
+                        )
+                    });
+                    reduction::restrict_to_roots(g, &[idx])
+                }
+                None => g,
+            }
+        }
+        None => match (remote, args.store.as_ref()) {
+            (Some(host), _) => {
+                msg!("Reading dependency graph from {} over ssh... ", host);
+                depgraph::DepInfos::read_over_ssh(host, root.as_deref())
+                    .unwrap_or_else(|e| die!(1, "Could not read from {}: {}", host, e))
+            }
+            (None, Some(uri)) => {
+                msg!("Reading dependency graph from store {}... ", uri);
+                let mut g = depgraph::DepInfos::read_from_store_uri(
+                    uri.into(),
+                    root,
+                    args.live_only,
+                    args.derivations,
+                )
+                .unwrap_or_else(|e| die!(e.code(), "Could not read from store: {}", e));
+                if let analyze::StoreLocation::Local(store_root) =
+                    analyze::store_location(Some(OsStr::new(uri)))
+                {
+                    g.store_root = store_root;
+                }
+                g
+            }
+            (None, None) => {
+                msg!("Reading dependency graph from store... ");
+                depgraph::DepInfos::read_from_store(root, args.live_only, args.derivations)
+                    .unwrap_or_else(|e| die!(e.code(), "Could not read from store: {}", e))
+            }
+        },
+    };
+    msg!("{}\n", g.summary());
+    // reductions drop them as unreachable, but they are still drawn
+    let invalid: Vec<_> = g
+        .invalid_paths()
+        .into_iter()
+        .map(|idx| g.graph[idx].clone())
+        .collect();
+    if !invalid.is_empty() {
+        msg!(
+            "Warning: {} paths of the store are not valid, for example because a build was interrupted\n",
+            invalid.len()
+        );
+    }
 
     /*************************************
      * handling of --dump
      * **********************************/
 
-    if let Some((mut f, path)) = dumpfile {
+    if let Some((f, path)) = dumpfile {
         msg!("Dumping dependency graph to {}...", path.display());
-        dot::render(&g, &mut f)
+        let mut w = io::BufWriter::new(f);
+        let non_utf8 = Cell::new(0);
+        // closure sizes are quadratic in the size of the unreduced graph, they are
+        // only computed once it is reduced
+        if dot_options.needs_closure_sizes() {
+            msg!(" (without closure sizes)");
+        }
+        // the invalid paths are still in the graph
+        dot::render(&g, &dot_options, None, &[], &non_utf8, &mut w)
+            .and_then(|_| w.flush())
             .unwrap_or_else(|err| die!(1, "Could not dump dependency graph: {}", err));
-        drop(f);
         msg!(" done\n");
+        warn_non_utf8(non_utf8.get());
     }
 
-    /******************
-     * handling or -O *
-     ******************/
+    if let Some((f, path)) = dump_to {
+        msg!("Saving dependency graph to {}...", path.display());
+        let mut w = io::BufWriter::new(f);
+        g.dump(&mut w)
+            .and_then(|_| w.flush())
+            .unwrap_or_else(|err| die!(1, "Could not save dependency graph: {}", err));
+        msg!(" done\n");
+    }
 
-    let default_optlevel = Some(StatOpts::Alive);
-    let optlevel = optlevel.unwrap_or_else(|| match opt::store_is_optimised(&g) {
-        Err(e) => {
-            eprintln!("Could not auto detect store optimisation: {}", e);
-            default_optlevel
+    for path in &args.pin {
+        let path = path.canonicalize().unwrap_or_else(|err| {
+            die!(
+                1,
+                "Could not canonicalize path «{}»: {}",
+                path.display(),
+                err
+            )
+        });
+        let path = path.as_os_str().as_bytes();
+        if g.pin(path).is_none() {
+            die!(1, "{} is not a valid store path", path.escape_ascii());
         }
-        Ok(None) => default_optlevel,
-        Ok(Some(true)) => Some(StatOpts::Alive),
-        Ok(Some(false)) => None,
-    });
+    }
+
+    if args.report_cycles {
+        let stderr = io::stderr();
+        let mut handle = stderr.lock();
+        print_cycles(&mut handle, &g).expect("could not write to stderr");
+    }
 
-    if let Some(statopts) = optlevel {
-        if statopts == StatOpts::Alive {
-            // drop dead paths
-            g = reduction::keep_reachable(g);
+    if args.resolve_roots {
+        g.follow_root_links();
+    }
+
+    /*********************
+     * handling of --why *
+     *********************/
+
+    if let Some(path) = args.why.as_ref() {
+        let path = path.canonicalize().unwrap_or_else(|err| {
+            die!(
+                1,
+                "Could not canonicalize path «{}»: {}",
+                path.display(),
+                err
+            )
+        });
+        let path = path.as_os_str().as_bytes();
+        let node = g
+            .find_by_path(path)
+            .unwrap_or_else(|| die!(1, "{} is not a valid store path", path.escape_ascii()));
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        for root in g.roots_holding(node) {
+            let desc = &g.graph[root].description;
+            let res = match desc.path() {
+                Some(p) => handle.write_all(p),
+                None => handle.write_all(&desc.name()),
+            }
+            .and_then(|_| handle.write_all(b"\n"));
+            match res {
+                Ok(_) => (),
+                Err(ref x) if x.kind() == io::ErrorKind::BrokenPipe => return,
+                Err(x) => die!(3, "While writing to stdout: {}", x),
+            }
         }
+        return;
+    }
 
-        msg!(
-            "Looking for optimized paths... (this could take a long time, pass option -O0 to skip)\n"
+    /***************************
+     * handling of --diff-from *
+     ***************************/
+
+    if let Some(path) = args.diff_from.as_ref() {
+        let previous = std::fs::File::open(path)
+            .and_then(|f| depgraph::DepInfos::load(&mut io::BufReader::new(f)))
+            .unwrap_or_else(|err| die!(1, "Could not load «{}»: {}", path.display(), err));
+        let diff = g.diff(&previous);
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        match diff::render(&diff, &g, &previous, &mut handle) {
+            Ok(_) => (),
+            Err(ref x) if x.kind() == io::ErrorKind::BrokenPipe => (),
+            Err(x) => die!(3, "While writing to stdout: {}", x),
+        }
+        return;
+    }
+
+    /******************
+     * handling or -O *
+     ******************/
+
+    // the files of a remote store cannot be stat'ed
+    let location = analyze::store_location(args.store.as_deref().map(OsStr::new));
+    if location != analyze::StoreLocation::Remote {
+        let options = opt::RefineOptions {
+            verbosity: verbosity(),
+            root_links: args.root_links,
+            directory_sizes: args.directory_sizes,
+            block_size: args.block_size.map(|s| s.as_u64()),
+            jobs: args.jobs,
+            shared_prefix: Some(args.shared_prefix.clone().into_bytes()),
+            ..Default::default()
+        };
+        g = analyze::take_optimisation_into_account(
+            g,
+            optimisation,
+            args.optimisation_probes,
+            &options,
         );
-        opt::refine_optimized_store(&mut g)
-            .unwrap_or_else(|e| eprintln!("Could not unoptimize {:?}", e));
     }
 
     noisy!({
@@ -208,13 +655,119 @@ fn main() {
         print_stats(&mut handle, &g).expect("could not write to stderr");
     });
 
+    /***************************
+     * handling of --top-roots *
+     ***************************/
+
+    if let Some(n) = args.top_roots {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        for (root, size) in g.roots_by_reclaimable().into_iter().take(n) {
+            let size = if args.bytes {
+                format!("{} B", size)
+            } else {
+                format_size(size)
+            };
+            let desc = &g.graph[root].description;
+            let res = write!(handle, "{:>10}  ", size)
+                .and_then(|_| match desc.path() {
+                    Some(p) => handle.write_all(p),
+                    None => handle.write_all(&desc.name()),
+                })
+                .and_then(|_| handle.write_all(b"\n"));
+            match res {
+                Ok(_) => (),
+                Err(ref x) if x.kind() == io::ErrorKind::BrokenPipe => return,
+                Err(x) => die!(3, "While writing to stdout: {}", x),
+            }
+        }
+        return;
+    }
+
+    /*******************************
+     * handling of --compare-roots *
+     *******************************/
+
+    if let Some(paths) = args.compare_roots.as_ref() {
+        let (old, new) = (find_root(&g, &paths[0]), find_root(&g, &paths[1]));
+        let (added, size) = g.closure_difference(new, old);
+        let format = |size: u64| {
+            if args.bytes {
+                format!("{} B", size)
+            } else {
+                format_size(size)
+            }
+        };
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let res = added
+            .iter()
+            .try_for_each(|&idx| {
+                let node = &g.graph[idx];
+                handle.write_all(b"+ ")?;
+                match node.description.path() {
+                    Some(p) => handle.write_all(p)?,
+                    None => handle.write_all(&node.name())?,
+                }
+                writeln!(handle, " ({})", format(node.size))
+            })
+            .and_then(|_| writeln!(handle, "Total: +{} in {} paths", format(size), added.len()));
+        match res {
+            Ok(_) => (),
+            Err(ref x) if x.kind() == io::ErrorKind::BrokenPipe => (),
+            Err(x) => die!(3, "While writing to stdout: {}", x),
+        }
+        return;
+    }
+
     /*******************
      * graph reduction *
      *******************/
 
-    g = reduction::merge_transient_roots(g);
-    msg!("Computing quotient graph... ");
-    g = reduction::condense(g);
+    if !args.reclaimable_from.is_empty() {
+        let deleted: Vec<_> = args
+            .reclaimable_from
+            .iter()
+            .map(|path| find_root(&g, path))
+            .collect();
+        let keep: Vec<_> = g.roots().filter(|idx| !deleted.contains(idx)).collect();
+        g = reduction::reclaimable(g, &keep);
+    }
+    if args.merge_shared {
+        g = reduction::merge_shared(g);
+    }
+    if args.group_by_name {
+        g = reduction::merge_by_name(reduction::keep_reachable(g));
+    }
+    if !args.exclude.is_empty() {
+        let excluded =
+            regex::bytes::RegexSet::new(args.exclude.iter().map(|re| format!("^(?:{})$", re)))
+                .unwrap_or_else(|e| die!(1, "Invalid --exclude: {}", e));
+        let policy = match args.fold_excluded.as_str() {
+            "drop" => reduction::FoldPolicy::Drop,
+            "parents" => reduction::FoldPolicy::ToParents,
+            "nearest" => reduction::FoldPolicy::ToNearestKept,
+            _ => unreachable!(),
+        };
+        g = reduction::exclude_folding(g, |node| excluded.is_match(&node.name()), policy);
+    }
+    g = analyze::transient_roots(g, args.no_transient);
+    if args.format != "paths" {
+        // the paths of merged nodes would be lost
+        msg!("Computing quotient graph... ");
+        g = reduction::condense(g);
+    }
+    if let Some(depth) = args.truncate_depth {
+        g.truncate_depth(depth);
+    }
+
+    if let Some(size) = args.min_subtree_size {
+        let closure_sizes = depgraph::ClosureSizes::new(&g);
+        g = reduction::prune_light_subtrees(g, size.as_u64(), &closure_sizes);
+    }
+    if let Some(size) = args.fold_leaves_below {
+        g = reduction::fold_small_leaves(g, size.as_u64());
+    }
 
     let mut min_size = args.min_size.map(|s| s.as_u64()).unwrap_or(0);
     if let Some(n_nodes) = args.nodes {
@@ -230,7 +783,7 @@ fn main() {
      *******************/
 
     if min_size > 0 {
-        g = reduction::keep(g, |d: &depgraph::DepNode| d.size >= min_size);
+        g = reduction::retain_above(g, min_size);
     }
     msg!(
         "{} nodes, {} edges.\n",
@@ -244,13 +797,58 @@ fn main() {
      * output handling *
      *******************/
 
+    let non_utf8 = Cell::new(0);
+    // the closure sizes of the graph rendered, for the formats showing them
+    let closure_sizes = match args.format.as_str() {
+        "csv" => depgraph::ClosureSizes::new(&g),
+        "dot" if dot_options.needs_closure_sizes() => depgraph::ClosureSizes::new(&g),
+        _ => depgraph::ClosureSizes::default(),
+    };
     {
-        let stdout = io::stdout();
-        let mut handle = stdout.lock();
-        match dot::render(&g, &mut handle) {
-            Ok(_) => (),
-            Err(ref x) if x.kind() == io::ErrorKind::BrokenPipe => (),
-            Err(x) => die!(3, "While writing to stdout: {}", x),
+        let render = |mut handle: &mut dyn Write| match args.format.as_str() {
+            "dot" => dot::render(
+                &g,
+                &dot_options,
+                Some(&closure_sizes),
+                &invalid,
+                &non_utf8,
+                &mut handle,
+            ),
+            "json" => json::render(&g, &mut handle),
+            "graphml" => graphml::render(&g, &non_utf8, &mut handle),
+            "csv" => csv::render(&g, &closure_sizes, &non_utf8, &mut handle),
+            "folded" => folded::render(&g, &mut handle),
+            "paths" => paths::render(&g, &mut handle),
+            "histogram" => {
+                let options = histogram::Options {
+                    base: args.histogram_base,
+                    bytes: args.bytes,
+                };
+                histogram::render(&g, &options, &mut handle)
+            }
+            "tree" => {
+                let options = tree::Options {
+                    max_depth: args.max_depth,
+                    bytes: args.bytes,
+                };
+                tree::render(&g, &options, &mut handle)
+            }
+            _ => unreachable!(),
+        };
+        match args.output.as_ref() {
+            Some(path) => output::write_atomically(path, render)
+                .unwrap_or_else(|x| die!(3, "While writing to «{}»: {}", path.display(), x)),
+            None => {
+                let stdout = io::stdout();
+                // stdout is line buffered, and renderers write small pieces as they go
+                let mut handle = io::BufWriter::new(stdout.lock());
+                match render(&mut handle).and_then(|_| handle.flush()) {
+                    Ok(_) => (),
+                    Err(ref x) if x.kind() == io::ErrorKind::BrokenPipe => (),
+                    Err(x) => die!(3, "While writing to stdout: {}", x),
+                }
+            }
         }
     }
+    warn_non_utf8(non_utf8.get());
 }
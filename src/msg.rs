@@ -1,13 +1,33 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicU8, Ordering};
 
-static QUIET: AtomicBool = AtomicBool::new(false);
+/// How much is printed on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    /// nothing but errors
+    Quiet,
+    /// informational messages and progress bars
+    #[default]
+    Normal,
+    /// also log what is being done in detail
+    Verbose,
+}
 
-pub fn quiet() -> bool {
-    QUIET.load(Ordering::Relaxed)
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+pub fn set_verbosity(v: Verbosity) {
+    VERBOSITY.store(v as u8, Ordering::Relaxed);
 }
 
-pub fn set_quiet(x: bool) {
-    QUIET.store(x, Ordering::Relaxed);
+pub fn quiet() -> bool {
+    verbosity() == Verbosity::Quiet
 }
 
 /// only executes its argument if quiet mode is disabled.
@@ -41,3 +61,54 @@ macro_rules! die {
         }
     }
 }
+
+/// warns once if `n` nodes were output with a name or path which is not valid
+/// UTF-8, and which is therefore approximated in most formats. Renderers count
+/// them in the `Cell` they are given.
+pub fn warn_non_utf8(n: usize) {
+    if n > 0 {
+        msg!(
+            "Warning: {} nodes have a name or path which is not valid UTF-8, their labels may be mangled\n",
+            n
+        );
+    }
+}
+
+/// formats a size in bytes for humans, with binary prefixes,
+/// like `1.2 GiB` or `340 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.;
+    let mut unit = 0;
+    // 1023.5 KiB would be displayed as 1024 KiB
+    while value >= 1023.5 && unit + 1 < UNITS.len() {
+        value /= 1024.;
+        unit += 1;
+    }
+    if value < 9.95 {
+        format!("{:.1} {}", value, UNITS[unit])
+    } else {
+        format!("{:.0} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::msg::format_size;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(10 * 1024 - 1), "10 KiB");
+        assert_eq!(format_size(1024 * 1024 - 1), "1.0 MiB");
+        assert_eq!(format_size(340 * 1024 * 1024), "340 MiB");
+        assert_eq!(format_size(1288490189), "1.2 GiB");
+        assert_eq!(format_size(u64::MAX), "16 EiB");
+    }
+}
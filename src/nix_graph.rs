@@ -0,0 +1,281 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use crate::msg::*;
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+/// A token of the DOT language, as far as `nix-store --query --graph` uses it
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    /// an identifier, quoted or not
+    Id(Vec<u8>),
+    /// `->`
+    Arrow,
+    /// any other punctuation
+    Punct(u8),
+}
+
+fn tokenize(input: &[u8]) -> io::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            c if c.is_ascii_whitespace() => i += 1,
+            b'-' if input.get(i + 1) == Some(&b'>') => {
+                tokens.push(Token::Arrow);
+                i += 2;
+            }
+            b'"' => {
+                let mut id = Vec::new();
+                i += 1;
+                loop {
+                    match input.get(i) {
+                        None => return Err(invalid_data("unterminated string".into())),
+                        Some(b'"') => break,
+                        // only \" is an escape, other backslashes are kept as is
+                        Some(b'\\') if input.get(i + 1) == Some(&b'"') => {
+                            id.push(b'"');
+                            i += 1;
+                        }
+                        Some(&c) => id.push(c),
+                    }
+                    i += 1;
+                }
+                tokens.push(Token::Id(id));
+                i += 1;
+            }
+            c if c.is_ascii_alphanumeric() || c == b'_' || c == b'.' || c >= 0x80 => {
+                let start = i;
+                while i < input.len()
+                    && (input[i].is_ascii_alphanumeric()
+                        || matches!(input[i], b'_' | b'.' | b'-' | 0x80..=0xff))
+                    && !(input[i] == b'-' && input.get(i + 1) == Some(&b'>'))
+                {
+                    i += 1;
+                }
+                tokens.push(Token::Id(input[start..i].to_vec()));
+            }
+            c @ (b'{' | b'}' | b'[' | b']' | b'=' | b',' | b';') => {
+                tokens.push(Token::Punct(c));
+                i += 1;
+            }
+            c => {
+                return Err(invalid_data(format!(
+                    "unexpected character {:?}",
+                    c as char
+                )))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+impl DepInfos {
+    /// reads the dependency graph from the DOT output of `nix-store --query --graph`,
+    /// as a fallback for when libnixstore cannot be used and `nix path-info --json`
+    /// is not available.
+    ///
+    /// Node identifiers are store paths, possibly without the `/nix/store/` prefix
+    /// which is then added. Edges go from a reference to the path referencing it,
+    /// they are reversed. Attributes, in particular labels, are ignored.
+    ///
+    /// This output contains no sizes: they are looked up with `size`, and when it
+    /// returns `None` the size is 0, with a warning.
+    /// Paths which are not referenced by other paths are considered roots.
+    pub fn parse_nix_graph<R: Read, F: Fn(&[u8]) -> Option<u64>>(
+        mut reader: R,
+        size: F,
+    ) -> io::Result<Self> {
+        let mut input = Vec::new();
+        reader.read_to_end(&mut input)?;
+        let tokens = tokenize(&input)?;
+
+        let mut graph = DepGraph::new();
+        let mut index = HashMap::new();
+        let mut unknown_size = 0;
+        let mut node = |id: &[u8]| {
+            let path = if id.starts_with(b"/") {
+                id.to_vec()
+            } else {
+                [b"/nix/store/", id].concat()
+            };
+            *index.entry(path.clone()).or_insert_with(|| {
+                let s = size(&path).unwrap_or_else(|| {
+                    unknown_size += 1;
+                    0
+                });
+                graph.add_node(DepNode {
+                    description: NodeDescription::Path(path),
+                    size: s,
+                    link_path: None,
+                })
+            })
+        };
+        let mut edges = Vec::new();
+
+        let mut tokens = tokens.iter().peekable();
+        match (tokens.next(), tokens.next()) {
+            (Some(Token::Id(digraph)), Some(Token::Id(_))) if digraph == b"digraph" => {
+                if tokens.next() != Some(&Token::Punct(b'{')) {
+                    return Err(invalid_data("expected {".into()));
+                }
+            }
+            (Some(Token::Id(digraph)), Some(Token::Punct(b'{'))) if digraph == b"digraph" => (),
+            _ => return Err(invalid_data("expected digraph".into())),
+        }
+        loop {
+            match tokens.next() {
+                None => return Err(invalid_data("unexpected end of input".into())),
+                Some(Token::Punct(b'}')) => break,
+                Some(Token::Punct(b';')) => continue,
+                Some(Token::Id(first)) => {
+                    // a chain of edges a -> b -> c, or a lone node
+                    let mut chain = vec![first];
+                    while tokens.peek() == Some(&&Token::Arrow) {
+                        tokens.next();
+                        match tokens.next() {
+                            Some(Token::Id(id)) => chain.push(id),
+                            t => {
+                                return Err(invalid_data(format!(
+                                    "expected node after ->, got {:?}",
+                                    t
+                                )))
+                            }
+                        }
+                    }
+                    if tokens.peek() == Some(&&Token::Punct(b'=')) {
+                        // graph attribute
+                        tokens.next();
+                        tokens.next();
+                        continue;
+                    }
+                    if tokens.peek() == Some(&&Token::Punct(b'[')) {
+                        loop {
+                            match tokens.next() {
+                                None => return Err(invalid_data("unterminated attributes".into())),
+                                Some(Token::Punct(b']')) => break,
+                                Some(_) => (),
+                            }
+                        }
+                    }
+                    if chain.len() == 1 && matches!(first.as_slice(), b"graph" | b"node" | b"edge")
+                    {
+                        // default attributes
+                        continue;
+                    }
+                    let chain: Vec<_> = chain.into_iter().map(|id| node(id)).collect();
+                    for pair in chain.windows(2) {
+                        // from reference to referrer
+                        if pair[0] != pair[1] {
+                            edges.push((pair[1], pair[0]));
+                        }
+                    }
+                }
+                Some(t) => return Err(invalid_data(format!("unexpected {:?}", t))),
+            }
+        }
+        if unknown_size > 0 {
+            msg!(
+                "Warning: the size of {} store paths is unknown, they are counted as empty\n",
+                unknown_size
+            );
+        }
+        for (from, to) in edges {
+            graph.update_edge(from, to, Edge::default());
+        }
+        Ok(DepInfos::rooted_at_unreferenced(graph))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+    use petgraph::prelude::NodeIndex;
+
+    /// captured from `nix-store --query --graph` on nix 2.18, trimmed, plus some
+    /// oddities allowed by the DOT syntax.
+    const SAMPLE: &[u8] = br##"digraph G {
+"0aqmw36wh3vkby9c5gaqsyvqms7ai7ph-hello-2.12.1" [label = "hello-2.12.1", shape = box, style = filled, fillcolor = "#ff0000"];
+"ddwyrxif62r8n6xclvskjyy6szdhvj60-glibc-2.39-5" -> "0aqmw36wh3vkby9c5gaqsyvqms7ai7ph-hello-2.12.1" [color = "black"];
+"ddwyrxif62r8n6xclvskjyy6szdhvj60-glibc-2.39-5" [label = "glibc -> \"2.39\"; [5]", shape = box, style = filled, fillcolor = "#ff0000"];
+"fdfrczpjzklj6rxyx2ghrmic02bmrbzl-xgcc-13.2.0-libgcc" -> "ddwyrxif62r8n6xclvskjyy6szdhvj60-glibc-2.39-5" [color = "red"];
+"skjdnms4gqpjyr4rq98cakm9wkb5md1j-libidn2-2.3.7" -> "ddwyrxif62r8n6xclvskjyy6szdhvj60-glibc-2.39-5" [color = "green"];
+node [shape = box]; "/nix/store/vky8hh1hr0s8c6rag4f7c80p1gvnlv2v-libunistring-1.1" -> "skjdnms4gqpjyr4rq98cakm9wkb5md1j-libidn2-2.3.7"
+"vky8hh1hr0s8c6rag4f7c80p1gvnlv2v-libunistring-1.1"
+}
+"##;
+
+    #[test]
+    fn parse() {
+        let di = DepInfos::parse_nix_graph(SAMPLE, |path| {
+            if path.ends_with(b"glibc-2.39-5") {
+                Some(1000)
+            } else {
+                None
+            }
+        })
+        .unwrap();
+        di.check_metadata();
+        let names = |idx: NodeIndex| String::from_utf8(di.graph[idx].name().into_owned()).unwrap();
+        let mut nodes: Vec<_> = di
+            .graph
+            .node_indices()
+            .filter(|&idx| idx != di.root)
+            .map(|idx| {
+                let path = di.graph[idx].description.path().unwrap();
+                assert!(path.starts_with(b"/nix/store/"));
+                (names(idx), di.graph[idx].size)
+            })
+            .collect();
+        nodes.sort();
+        assert_eq!(
+            nodes,
+            vec![
+                ("glibc-2.39-5".to_string(), 1000),
+                ("hello-2.12.1".to_string(), 0),
+                ("libidn2-2.3.7".to_string(), 0),
+                ("libunistring-1.1".to_string(), 0),
+                ("xgcc-13.2.0-libgcc".to_string(), 0),
+            ]
+        );
+        let mut edges: Vec<_> = di
+            .graph
+            .raw_edges()
+            .iter()
+            .filter(|e| e.source() != di.root)
+            .map(|e| (names(e.source()), names(e.target())))
+            .collect();
+        edges.sort();
+        assert_eq!(
+            edges,
+            vec![
+                ("glibc-2.39-5".to_string(), "libidn2-2.3.7".to_string()),
+                ("glibc-2.39-5".to_string(), "xgcc-13.2.0-libgcc".to_string()),
+                ("hello-2.12.1".to_string(), "glibc-2.39-5".to_string()),
+                ("libidn2-2.3.7".to_string(), "libunistring-1.1".to_string()),
+            ]
+        );
+        assert_eq!(
+            di.roots_name(),
+            vec!["hello-2.12.1".to_string()].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for input in &[
+            &b""[..],
+            b"graph G {}",
+            b"digraph G { \"a",
+            b"digraph G { a -> }",
+            b"digraph G { a [label = b",
+        ] {
+            assert!(
+                DepInfos::parse_nix_graph(*input, |_| None).is_err(),
+                "{:?} should be invalid",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+}
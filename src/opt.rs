@@ -1,26 +1,595 @@
 use crate::depgraph::*;
 use crate::msg::*;
 
-use dashmap::mapref::entry::Entry;
 use petgraph::prelude::NodeIndex;
+#[cfg(feature = "rayon")]
 use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
 use std::io::Result;
-use std::iter::once;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::sync::RwLock;
 use walkdir::{DirEntryExt, WalkDir};
 
+/// Inode numbers are only unique on a given device, so files are identified by
+/// `(st_dev, st_ino)`.
+type FileId = (u64, u64);
+
+/// A hasher for `FileId`s, much faster than the default one. Inode numbers are not
+/// chosen by an attacker, so resistance to collisions does not matter.
+///
+/// This is the hash function of `FxHasher`, used by rustc.
+#[derive(Debug, Clone, Copy, Default)]
+struct FileIdHasher(u64);
+
+impl Hasher for FileIdHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.write_u64(u64::from_ne_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = (self.0.rotate_left(5) ^ i).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A map indexed by inodes, with a fast hasher
+type FastMap<V> = HashMap<FileId, V, BuildHasherDefault<FileIdHasher>>;
+
+/// What `account` needs of a map indexed by inodes, so that the implementation
+/// can be chosen: `FastMap` avoids the cost of the default hasher on the tens of
+/// millions of inodes of a large store.
+trait InodeMap<V>: Default + IntoIterator<Item = (FileId, V)> {
+    /// returns the value of `id`, inserting `value` if there is none, and whether
+    /// it was inserted.
+    fn get_or_insert(&mut self, id: FileId, value: V) -> (&mut V, bool);
+    fn get(&self, id: &FileId) -> Option<&V>;
+}
+
+impl<V, S: BuildHasher + Default> InodeMap<V> for HashMap<FileId, V, S> {
+    fn get_or_insert(&mut self, id: FileId, value: V) -> (&mut V, bool) {
+        use std::collections::hash_map::Entry;
+        match self.entry(id) {
+            Entry::Vacant(e) => (e.insert(value), true),
+            Entry::Occupied(e) => (e.into_mut(), false),
+        }
+    }
+
+    fn get(&self, id: &FileId) -> Option<&V> {
+        HashMap::get(self, id)
+    }
+}
+
+impl<V> InodeMap<V> for BTreeMap<FileId, V> {
+    fn get_or_insert(&mut self, id: FileId, value: V) -> (&mut V, bool) {
+        use std::collections::btree_map::Entry;
+        match self.entry(id) {
+            Entry::Vacant(e) => (e.insert(value), true),
+            Entry::Occupied(e) => (e.into_mut(), false),
+        }
+    }
+
+    fn get(&self, id: &FileId) -> Option<&V> {
+        BTreeMap::get(self, id)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 enum Owner {
     One(NodeIndex),
     Several(NodeIndex),
 }
 
+/// Options for `refine_optimized_store`
+#[derive(Debug, Clone, Default)]
+pub struct RefineOptions {
+    /// Whether an error while walking a store path aborts the whole analysis.
+    /// Otherwise, a warning is printed and the problematic file is skipped.
+    pub fatal_errors: bool,
+    /// At `Verbosity::Verbose`, store paths are logged as they are walked, and
+    /// shared inodes as they are found. The progress bar is only shown at
+    /// `Verbosity::Normal`.
+    pub verbosity: Verbosity,
+    /// Count the size of files as the space allocated for them on disk
+    /// (`st_blocks * 512`) rather than their apparent size. This differs for sparse
+    /// files and because of block rounding.
+    pub disk_usage: bool,
+    /// When a file has hardlinks outside of the store paths being analysed (other
+    /// than the one in `.links`), only count the share of its size corresponding to
+    /// the hardlinks which are in them: `size * links seen / nlink`. The rest of the
+    /// size is owned by the outside.
+    ///
+    /// This requires stat'ing all files, not only those in `.links`.
+    pub fractional_links: bool,
+    /// Set the size of gc roots outside of the store (`NodeKind::Link`) to the size
+    /// of the symlink itself, which nix reports as 0. The store path it points to
+    /// is its child and is still counted once, as before.
+    pub root_links: bool,
+    /// Add the size of the directories of store paths to their size, like `du`
+    /// does. The NAR size reported by nix only counts the content of files.
+    pub directory_sizes: bool,
+    /// Round the size of each file and directory up to a multiple of this block
+    /// size, like the space they take on most filesystems. This requires stat'ing
+    /// all files, and is combined with `disk_usage` when both are set.
+    pub block_size: Option<u64>,
+    /// The number of threads walking store paths, by default the number of CPUs.
+    /// `Some(1)` walks them one after the other, as without the `rayon` feature,
+    /// where this is ignored. Must not be `Some(0)`.
+    pub jobs: Option<usize>,
+    /// The prefix of the names of the `Shared` nodes created, `shared:` when `None`.
+    /// If it starts with `{`, names are closed by `}`, like the names of transient
+    /// roots.
+    pub shared_prefix: Option<Vec<u8>>,
+}
+
+/// the name of a `Shared` node for the files of `name`
+fn shared_name(prefix: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(prefix.len() + name.len() + 1);
+    res.extend(prefix);
+    res.extend(name);
+    if prefix.starts_with(b"{") {
+        res.push(b'}');
+    }
+    res
+}
+
+/// Returns the value of `res`, or `None` if it is an error which should be skipped
+/// according to `options`.
+///
+/// Permission errors are always skipped, without warning: they only set `denied`,
+/// so that the caller warns once per store path.
+fn skip_error<T, E: Into<std::io::Error>>(
+    res: std::result::Result<T, E>,
+    options: &RefineOptions,
+    denied: &mut bool,
+) -> Result<Option<T>> {
+    match res {
+        Ok(x) => Ok(Some(x)),
+        Err(e) => {
+            let e: std::io::Error = e.into();
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                *denied = true;
+                Ok(None)
+            } else if options.fatal_errors {
+                Err(e)
+            } else {
+                msg!("Warning: skipping unreadable file: {}\n", e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// A file of a store path, as returned by `files_of`
+#[derive(Debug, Clone, Copy)]
+struct File {
+    id: FileId,
+    size: u64,
+    /// the number of hardlinks to this file on the filesystem
+    nlink: u64,
+    /// the number of these hardlinks in the store path
+    links: u64,
+}
+
+/// What `files_of` found in a store path
+#[derive(Debug, Default)]
+struct Walk {
+    /// the files which may be hardlinked to other store paths
+    files: Vec<File>,
+    /// when walking without stat'ing files, the inodes of the files which would be
+    /// in `files`, read from directory entries
+    ids: Vec<FileId>,
+    /// the size not counted by nix: directories with `RefineOptions::directory_sizes`,
+    /// and the rounding of files with `RefineOptions::block_size`
+    extra: u64,
+    /// whether some files could not be read because of permissions
+    denied: bool,
+}
+
+/// the size of a file or directory, according to `options.disk_usage`
+fn size_of(metadata: &std::fs::Metadata, options: &RefineOptions) -> u64 {
+    if options.disk_usage {
+        metadata.blocks() * 512
+    } else {
+        metadata.len()
+    }
+}
+
+/// `size` rounded up according to `options.block_size`
+fn rounded(size: u64, options: &RefineOptions) -> u64 {
+    match options.block_size {
+        Some(block) if block > 0 => size.div_ceil(block) * block,
+        _ => size,
+    }
+}
+
+/// Whether `path` is part of the metadata nix keeps in the store rather than in a
+/// store path: `.links`, with `links_dir` its canonical path, or a file at the top
+/// of the store like its lock. Store paths start with their hash, never with a dot.
+fn is_store_metadata(path: &std::path::Path, links_dir: Option<&std::path::Path>) -> bool {
+    if path.components().any(|c| c.as_os_str() == ".links") {
+        return true;
+    }
+    if matches!(path.file_name(), Some(name) if name.as_bytes().starts_with(b".")) {
+        return true;
+    }
+    // path may lead there through symlinks
+    match (links_dir, path.canonicalize()) {
+        (Some(links_dir), Ok(canonical)) => canonical.starts_with(links_dir),
+        _ => false,
+    }
+}
+
+/// Returns all the files in the store path of node `idx`.
+///
+/// When `linked` is set, only the files in it are returned, and the others are not
+/// even stat'ed: they are assumed to be on the same device as the store path.
+/// Without `stat`, no file is stat'ed and their inodes are returned in `Walk::ids`
+/// instead of `Walk::files`, on the same assumption.
+/// Store metadata (see `is_store_metadata`) is never walked, `links_dir` being the
+/// canonical path of `.links`.
+fn files_of(
+    di: &DepInfos,
+    idx: NodeIndex,
+    options: &RefineOptions,
+    linked: Option<&HashSet<FileId>>,
+    stat: bool,
+    links_dir: Option<&std::path::Path>,
+) -> Result<Walk> {
+    let weight = &di.graph[idx];
+    if options.root_links && weight.kind() == NodeKind::Link {
+        return root_link(di, weight, options);
+    }
+    // roots are not necessary readable, and anyway they are symlinks
+    if weight.kind() != NodeKind::Path {
+        return Ok(Walk::default());
+    }
+    let path = di.on_disk(
+        weight
+            .description
+            .path_as_os_str()
+            .expect("node with kind path without path"),
+    );
+
+    if is_store_metadata(&path, links_dir) {
+        if options.verbosity >= Verbosity::Verbose {
+            eprintln!("not walking store metadata {}", path.display());
+        }
+        return Ok(Walk::default());
+    }
+
+    // if path is a symlink to a directory, we enumerate files not in this
+    // derivation.
+    let mut denied = false;
+    let metadata = match skip_error(path.symlink_metadata(), options, &mut denied)? {
+        Some(m) => m,
+        None => {
+            return Ok(Walk {
+                denied,
+                ..Walk::default()
+            })
+        }
+    };
+    if metadata.file_type().is_symlink() {
+        return Ok(Walk::default());
+    };
+    if options.verbosity >= Verbosity::Verbose {
+        eprintln!("walking {}", path.display());
+    }
+
+    let mut files: Vec<File> = Vec::new();
+    let mut ids = Vec::new();
+    let mut extra = 0;
+    // a file hardlinked twice in the same store path does not make it shared
+    let mut seen: HashMap<FileId, usize> = HashMap::new();
+    let entries = WalkDir::new(&path)
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".links");
+    for entry in entries {
+        let entry = match skip_error(entry, options, &mut denied)? {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if options.directory_sizes && entry.file_type().is_dir() {
+            if let Some(metadata) = skip_error(entry.metadata(), options, &mut denied)? {
+                extra += rounded(size_of(&metadata, options), options);
+            }
+            continue;
+        }
+        // only files are hardlinked
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if matches!(linked, Some(linked) if !linked.contains(&(metadata.dev(), entry.ino()))) {
+            continue;
+        }
+        if !stat {
+            let id = (metadata.dev(), entry.ino());
+            if seen.insert(id, ids.len()).is_none() {
+                ids.push(id);
+            }
+            continue;
+        }
+        if let Some(metadata) = skip_error(entry.metadata(), options, &mut denied)? {
+            let id = (metadata.dev(), metadata.ino());
+            if let Some(&i) = seen.get(&id) {
+                files[i].links += 1;
+                continue;
+            }
+            let size = size_of(&metadata, options);
+            let size_rounded = rounded(size, options);
+            extra += size_rounded - size;
+            seen.insert(id, files.len());
+            files.push(File {
+                id,
+                size: size_rounded,
+                nlink: metadata.nlink(),
+                links: 1,
+            });
+        }
+    }
+    if denied {
+        msg!(
+            "Warning: some files in {} are not readable, skipping them\n",
+            path.display()
+        );
+    }
+    Ok(Walk {
+        files,
+        ids,
+        extra,
+        denied,
+    })
+}
+
+/// The symlink of gc root `weight` as a file, see `RefineOptions::root_links`.
+fn root_link(di: &DepInfos, weight: &DepNode, options: &RefineOptions) -> Result<Walk> {
+    let path = di.on_disk(
+        weight
+            .description
+            .path_as_os_str()
+            .expect("node with kind link without path"),
+    );
+    let mut denied = false;
+    let metadata = match skip_error(path.symlink_metadata(), options, &mut denied)? {
+        Some(m) => m,
+        None => {
+            return Ok(Walk {
+                denied,
+                ..Walk::default()
+            })
+        }
+    };
+    let file = File {
+        id: (metadata.dev(), metadata.ino()),
+        size: rounded(size_of(&metadata, options), options),
+        nlink: metadata.nlink(),
+        links: 1,
+    };
+    Ok(Walk {
+        files: vec![file],
+        ..Walk::default()
+    })
+}
+
+/// Removes `filesize` from the size of node `idx`.
+///
+/// The size reported by nix is the NAR size, which may not be exactly the sum of
+/// the sizes of the files, so saturate instead of underflowing.
+fn subtract_size(graph: &mut DepGraph, idx: NodeIndex, filesize: u64) {
+    let w = &mut graph[idx];
+    if w.size < filesize {
+        msg!(
+            "Warning: {:?} has size {} but contains a {} bytes hardlinked file, its size will be underestimated\n",
+            w.description,
+            w.size,
+            filesize
+        );
+    }
+    w.size = w.size.saturating_sub(filesize);
+}
+
+/// Calls `f` on all node indices less than `n`, in parallel if `parallel` is true:
+/// on a thread pool of `jobs` threads, or on rayon's global one if `jobs` is `None`.
+#[cfg(feature = "rayon")]
+fn map_nodes<T: Send, F: Fn(NodeIndex) -> T + Sync>(
+    n: usize,
+    parallel: bool,
+    jobs: Option<usize>,
+    f: F,
+) -> Result<Vec<T>> {
+    let par_map = || {
+        (0..n)
+            .into_par_iter()
+            .map(|i| f(NodeIndex::new(i)))
+            .collect()
+    };
+    match jobs {
+        _ if !parallel || jobs == Some(1) => Ok((0..n).map(|i| f(NodeIndex::new(i))).collect()),
+        Some(jobs) => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .map_err(std::io::Error::other)?;
+            Ok(pool.install(par_map))
+        }
+        None => Ok(par_map()),
+    }
+}
+
+/// Calls `f` on all node indices less than `n`.
+#[cfg(not(feature = "rayon"))]
+fn map_nodes<T, F: Fn(NodeIndex) -> T>(
+    n: usize,
+    _parallel: bool,
+    _jobs: Option<usize>,
+    f: F,
+) -> Result<Vec<T>> {
+    Ok((0..n).map(|i| f(NodeIndex::new(i))).collect())
+}
+
 /// Stats all the files in the store looking for hardlinked files
 /// and adapt the sizes of the nodes to take this into account.
-pub fn refine_optimized_store(di: &mut DepInfos) -> Result<()> {
+///
+/// Store paths are walked in parallel when the `rayon` feature is enabled, see
+/// `RefineOptions::jobs`.
+/// Progress is shown on stderr, see `RefineOptions::verbosity`.
+pub fn refine_optimized_store(di: &mut DepInfos, options: &RefineOptions) -> Result<()> {
+    refine(di, options, cfg!(feature = "rayon"))
+}
+
+/// Like `refine_optimized_store`, but reports progress by calling `progress` with
+/// the number of nodes processed so far and the total number of nodes, once per
+/// node, instead of showing a progress bar.
+///
+/// With the `rayon` feature, `progress` is called from several threads, so calls
+/// may arrive slightly out of order.
+pub fn refine_optimized_store_with_progress(
+    di: &mut DepInfos,
+    options: &RefineOptions,
+    progress: &(dyn Fn(usize, usize) + Sync),
+) -> Result<()> {
+    refine_with(di, options, cfg!(feature = "rayon"), progress)
+}
+
+/// A progress bar counting to `len`, which is hidden unless `verbosity` is `Normal`.
+fn progress_bar(len: u64, verbosity: Verbosity) -> indicatif::ProgressBar {
+    if verbosity != Verbosity::Normal {
+        // either silent, or the verbose logs would garble it
+        return indicatif::ProgressBar::hidden();
+    }
+    let progress = indicatif::ProgressBar::new(len).with_style(
+        indicatif::ProgressStyle::default_bar()
+            .template("{wide_bar} {pos}/{len} ({percent}%) ~{eta} left, {per_sec}")
+            .expect("invalid template"),
+    );
+    // refresh only 3 times per second, otherwise it's slow
+    progress.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(3));
+    progress
+}
+
+/// Returns the files in `.links`, ie. the files deduplicated by store optimisation,
+/// or `None` if it cannot be read.
+fn linked_inodes(di: &DepInfos) -> Option<HashSet<FileId>> {
+    let dir = di.links_dir()?;
+    // files are on the device of .links, it is never a mount point
+    let dev = dir.metadata().ok()?.dev();
+    let mut res = HashSet::new();
+    for entry in dir.read_dir().ok()? {
+        res.insert((dev, std::os::unix::fs::DirEntryExt::ino(&entry.ok()?)));
+    }
+    Some(res)
+}
+
+/// `refine_with` with a progress bar
+fn refine(di: &mut DepInfos, options: &RefineOptions, parallel: bool) -> Result<()> {
+    let progress = progress_bar(di.graph.node_count() as u64, options.verbosity);
+    let res = refine_with(di, options, parallel, &|_, _| progress.inc(1));
+    progress.finish_and_clear();
+    res
+}
+
+fn refine_with(
+    di: &mut DepInfos,
+    options: &RefineOptions,
+    parallel: bool,
+    progress: &(dyn Fn(usize, usize) + Sync),
+) -> Result<()> {
+    // only files in .links can be shared, which saves stat'ing all the others
+    let linked = linked_inodes(di);
+    if options.verbosity >= Verbosity::Verbose {
+        match &linked {
+            Some(linked) => eprintln!("{} deduplicated inodes in .links", linked.len()),
+            None => eprintln!("cannot read .links, checking all files"),
+        }
+    }
+    // files hardlinked outside of the store are not in .links, and rounding
+    // applies to all files
+    let stat_all = options.fractional_links || options.block_size.is_some();
+    let only = if stat_all { None } else { linked.as_ref() };
+
+    // the walk is the expensive part, and does not modify the graph
+    let links_dir = di.links_dir().and_then(|dir| dir.canonicalize().ok());
+    let frozen: &DepInfos = di;
+    let total = frozen.graph.node_count();
+    let done = std::sync::atomic::AtomicUsize::new(0);
+    let files = map_nodes(total, parallel, options.jobs, |idx| {
+        let res = files_of(frozen, idx, options, only, stat_all, links_dir.as_deref());
+        progress(
+            done.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1,
+            total,
+        );
+        res
+    })?;
+    let files = if stat_all {
+        files
+    } else {
+        stat_shared(frozen, files, options, parallel, links_dir.as_deref())?
+    };
+    account::<FastMap<_>, FastMap<_>>(di, files, linked.as_ref(), options)
+}
+
+/// Completes `walks`, which did not stat files, by walking again the store paths
+/// which have inodes also found in other store paths, and stat'ing only these.
+/// Most files of a store are in a single store path, so this is much cheaper
+/// than stat'ing all of them.
+fn stat_shared(
+    di: &DepInfos,
+    walks: Vec<Result<Walk>>,
+    options: &RefineOptions,
+    parallel: bool,
+    links_dir: Option<&std::path::Path>,
+) -> Result<Vec<Result<Walk>>> {
+    let mut walks = walks.into_iter().collect::<Result<Vec<_>>>()?;
+    let mut count: HashMap<FileId, usize, BuildHasherDefault<FileIdHasher>> = HashMap::default();
+    for walk in &walks {
+        for &id in &walk.ids {
+            *count.entry(id).or_insert(0) += 1;
+        }
+    }
+    let shared: HashSet<FileId> = count
+        .into_iter()
+        .filter(|&(_, n)| n > 1)
+        .map(|(id, _)| id)
+        .collect();
+    let files_only = RefineOptions {
+        directory_sizes: false,
+        ..options.clone()
+    };
+    let again = map_nodes(walks.len(), parallel, options.jobs, |idx| {
+        if walks[idx.index()].ids.iter().any(|id| shared.contains(id)) {
+            files_of(di, idx, &files_only, Some(&shared), true, links_dir).map(Some)
+        } else {
+            Ok(None)
+        }
+    })?;
+    for (walk, again) in walks.iter_mut().zip(again) {
+        if let Some(again) = again? {
+            // directories were counted in the first walk
+            walk.files = again.files;
+            walk.denied |= again.denied;
+        }
+    }
+    Ok(walks.into_iter().map(Ok).collect())
+}
+
+/// Updates the graph with the hardlinked files and directories found in `walks`, the
+/// result of `files_of` for each node (by index).
+///
+/// Inodes are indexed with `O` and `L`, the result does not depend on them.
+fn account<O: InodeMap<Owner>, L: InodeMap<(u64, u64, u64)>>(
+    di: &mut DepInfos,
+    walks: Vec<Result<Walk>>,
+    linked: Option<&HashSet<FileId>>,
+    options: &RefineOptions,
+) -> Result<()> {
     // invariant:
     // forall visited file:
     // its inode is a key in inode_to_owner
@@ -31,141 +600,192 @@ pub fn refine_optimized_store(di: &mut DepInfos) -> Result<()> {
     // forall store path containing this file, then there is an edge from the
     // corresponding node to this files's node.
     // In this case, parents do not count this file's size in their size.
-    let inode_to_owner = dashmap::DashMap::new();
-
-    let indices = 0..di.graph.node_count();
-    let progress = if quiet() {
-        indicatif::ProgressBar::hidden()
-    } else {
-        indicatif::ProgressBar::new(di.graph.node_count() as u64).with_style(
-            indicatif::ProgressStyle::default_bar()
-                .template("{wide_bar} {percent:>3}% ETA {eta:>7}")
-                .expect("invalid template"),
-        )
-    };
-    // refresh only 3 times per second, otherwise it's slow
-    progress.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(3));
-    let locked_graph = Arc::new(RwLock::new(&mut di.graph));
-    indices
-        .into_par_iter()
-        .try_for_each(|i| -> std::io::Result<()> {
-            noisy!({
-                progress.inc(1);
-            });
-            let idx = petgraph::graph::NodeIndex::new(i);
-
-            let walker = {
-                let graph = locked_graph.read().expect("poisoned lock");
-                // scope where we borrow the graph
-                let weight = &graph[idx];
-                // roots are not necessary readable, and anyway they are symlinks
-                if weight.kind() != NodeKind::Path {
-                    return Ok(());
-                }
-                let path = std::path::Path::new(
-                    weight
-                        .description
-                        .path_as_os_str()
-                        .expect("node with kind path without path"),
-                );
-
-                // if path is a symlink to a directory, we enumerate files not in this
-                // derivation.
-                if path.symlink_metadata()?.file_type().is_symlink() {
-                    return Ok(());
-                };
-
-                WalkDir::new(&path)
-            };
-            for entry in walker {
-                let entry = entry?;
-                // only files are hardlinked
-                if !entry.file_type().is_file() {
-                    continue;
+    let mut inode_to_owner = O::default();
+    // for each inode, its size, nlink, and the number of hardlinks seen
+    let mut links = L::default();
+    let mut incomplete = 0;
+    for (i, walk) in walks.into_iter().enumerate() {
+        let idx = NodeIndex::new(i);
+        let Walk {
+            files,
+            extra,
+            denied,
+            ..
+        } = walk?;
+        if denied {
+            incomplete += 1;
+        }
+        // not part of the nar size, but directories are never hardlinked, and
+        // rounding of hardlinked files is moved to shared nodes below
+        di.graph[idx].size += extra;
+        if di.graph[idx].kind() == NodeKind::Link {
+            // the only file of a root is its symlink, which nix did not count
+            di.graph[idx].size = files.iter().map(|f| f.size).sum();
+        }
+        for file in files {
+            let File {
+                id, size: filesize, ..
+            } = file;
+            links.get_or_insert(id, (filesize, file.nlink, 0)).0 .2 += file.links;
+            match inode_to_owner.get_or_insert(id, Owner::One(idx)) {
+                (_, true) => {
+                    // first time we see this inode
                 }
-                let ino = entry.ino();
-                // attempt to make the stat syscall without taking a write lock
-                let must_stat = matches!(inode_to_owner.get(&ino).map(|x| *x), Some(Owner::One(_)));
-                let filesize = if must_stat {
-                    Some(entry.metadata()?.len())
-                } else {
-                    None
-                };
-
-                match inode_to_owner.entry(ino) {
-                    Entry::Vacant(e) => {
-                        // first time we see this inode
-                        e.insert(Owner::One(idx));
-                    }
-                    Entry::Occupied(mut e) => {
-                        // this inode is deduplicated
-                        let v = e.get_mut();
-                        let (new_node, mut graph) = match *v {
-                            Owner::One(n) => {
-                                // second time we see this inode;
-                                // let's create a "shared" node for these files
-                                let filesize =
-                                    filesize.unwrap_or_else(|| entry.metadata().unwrap().len());
-                                let mut graph = locked_graph.write().expect("poisoned lock");
-                                let name = graph[idx].name().into_owned();
-                                let new_node = graph.add_node(DepNode {
-                                    description: NodeDescription::Shared(name),
-                                    size: filesize,
-                                });
-                                graph.add_edge(n, new_node, ());
-                                let new_w = &mut graph[n];
-                                new_w.size -= filesize;
-                                *v = Owner::Several(new_node);
-                                (new_node, graph)
+                (owner, false) => {
+                    // this inode is deduplicated
+                    let shared = match *owner {
+                        Owner::One(n) => {
+                            // second time we see this inode;
+                            // let's create a "shared" node for these files
+                            if options.verbosity >= Verbosity::Verbose {
+                                eprintln!(
+                                    "inode {} of device {} of {} bytes is shared by {:?} and {:?}",
+                                    id.1,
+                                    id.0,
+                                    filesize,
+                                    di.graph[n].description,
+                                    di.graph[idx].description
+                                );
                             }
-                            Owner::Several(n) => (n, locked_graph.write().expect("poisoned lock")),
-                        };
-                        graph.add_edge(idx, new_node, ());
-                        let filesize = graph[new_node].size;
-                        let w = &mut graph[idx];
-                        w.size -= filesize;
-                    }
+                            let name = shared_name(
+                                options.shared_prefix.as_deref().unwrap_or(b"shared:"),
+                                &di.graph[idx].name(),
+                            );
+                            let new_node = di.graph.add_node(DepNode {
+                                description: NodeDescription::Shared(name),
+                                size: filesize,
+                                link_path: None,
+                            });
+                            di.graph.add_edge(
+                                n,
+                                new_node,
+                                Edge {
+                                    shared_size: Some(filesize),
+                                },
+                            );
+                            subtract_size(&mut di.graph, n, filesize);
+                            *owner = Owner::Several(new_node);
+                            new_node
+                        }
+                        Owner::Several(n) => n,
+                    };
+                    let filesize = di.graph[shared].size;
+                    di.graph.add_edge(
+                        idx,
+                        shared,
+                        Edge {
+                            shared_size: Some(filesize),
+                        },
+                    );
+                    subtract_size(&mut di.graph, idx, filesize);
+                }
+            }
+        }
+    }
+    // hardlinks outside of the store paths we walked
+    let mut external = 0;
+    for (id, (filesize, nlink, seen)) in links {
+        // the hardlink in .links is not an owner
+        let expected = nlink - matches!(linked, Some(l) if l.contains(&id)) as u64;
+        if seen >= expected {
+            continue;
+        }
+        external += 1;
+        if !options.fractional_links {
+            continue;
+        }
+        let owned = (filesize as u128 * seen as u128 / expected as u128) as u64;
+        match *inode_to_owner.get(&id).expect("inode without owner") {
+            Owner::One(n) => subtract_size(&mut di.graph, n, filesize - owned),
+            Owner::Several(shared) => {
+                let size = di.graph[shared].size - (filesize - owned);
+                di.graph[shared].size = size;
+                let mut edges = di
+                    .graph
+                    .neighbors_directed(shared, petgraph::Direction::Incoming)
+                    .detach();
+                while let Some(edge) = edges.next_edge(&di.graph) {
+                    di.graph[edge].shared_size = Some(size);
                 }
             }
-            Ok(())
-        })?;
+        }
+    }
+    if options.verbosity >= Verbosity::Verbose && external > 0 {
+        eprintln!(
+            "{} files have hardlinks outside of the store paths analysed",
+            external
+        );
+    }
+    if incomplete > 0 {
+        msg!(
+            "Warning: {} store paths could not be read entirely, the space saved by hardlinks may be undercounted\n",
+            incomplete
+        );
+    }
     di.metadata.dedup = DedupAwareness::Aware;
-    progress.finish_and_clear();
     di.record_metadata();
     Ok(())
 }
 
+/// The number of files of `.links` which `store_is_optimised` checks
+pub const DEFAULT_PROBES: usize = 10;
+
+/// How many entries of `.links` are read per file checked by
+/// `store_is_optimised_probing`. Reading entries is cheap, stat'ing them is not.
+const PROBE_SPREAD: usize = 100;
+
 /// Determine whether at least one path has been optimised in the store.
 /// This function is designed to be cheap, and to fail when it cannot be cheap
 /// (it will return `Ok(None)` then).
 pub fn store_is_optimised(di: &DepInfos) -> Result<Option<bool>> {
-    // there is no way in the nix api to get the linksDir field of a RemoteStore
+    store_is_optimised_probing(di, DEFAULT_PROBES)
+}
+
+/// Like `store_is_optimised`, checking `probes` files of `.links` chosen at random
+/// among its first `probes * PROBE_SPREAD` entries.
+///
+/// Returns `Ok(Some(true))` as soon as one of them has other hardlinks, and
+/// `Ok(Some(false))` only when `.links` has no more than `probes` files, all checked.
+/// With no probes, nothing is known and `Ok(None)` is returned.
+pub fn store_is_optimised_probing(di: &DepInfos, probes: usize) -> Result<Option<bool>> {
+    if probes == 0 {
+        return Ok(None);
+    }
     // Using this api would only work for LocalStore, which is unfortunate.
-    // So we just infer the linksDir from a drv. Not a gc root because it is
-    // usually a symlink.
-    let drv = match &di
-        .graph
-        .raw_nodes()
-        .iter()
-        .find(|node| node.weight.kind() == NodeKind::Path)
-    {
-        Some(ref node) => &node.weight,
+    let p = match di.links_dir() {
+        Some(p) => p,
         None => return Ok(None),
     };
-    let mut p = PathBuf::from(drv.description.path_as_os_str().unwrap().to_os_string());
-    // compute the location of .links
-    if !p.pop() {
-        return Ok(None);
-    }
-    p.push(".links");
 
-    // iterate on the first ten files in .links, and then yield None and give up
-    for entry in p.read_dir()?.map(Some).take(10).chain(once(None)) {
-        let entry = match entry {
-            Some(entry) => entry?,
-            // .links contains more than ten files, give up
-            None => return Ok(None),
-        };
+    // reservoir sampling of the first entries, with a xorshift generator
+    let mut state = {
+        use std::hash::{BuildHasher, Hasher};
+        std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish()
+            | 1
+    };
+    let mut random = |bound: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % bound as u64) as usize
+    };
+    let mut sample = Vec::with_capacity(probes);
+    let mut seen = 0;
+    for entry in p.read_dir()?.take(probes.saturating_mul(PROBE_SPREAD)) {
+        let entry = entry?;
+        seen += 1;
+        if sample.len() < probes {
+            sample.push(entry);
+        } else {
+            let i = random(seen);
+            if i < probes {
+                sample[i] = entry;
+            }
+        }
+    }
+    for entry in &sample {
         let ty = entry.file_type()?;
         if !ty.is_file() {
             eprintln!("Strange, {} is not a file", entry.path().display());
@@ -176,5 +796,848 @@ pub fn store_is_optimised(di: &DepInfos) -> Result<Option<bool>> {
             return Ok(Some(true));
         }
     }
-    Ok(Some(false))
+    if seen > sample.len() {
+        // not all files were checked, give up
+        Ok(None)
+    } else {
+        Ok(Some(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opt::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Creates a fresh directory for `name` in the temporary directory.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nix-du-test-{}-{}", name, std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Builds a `DepInfos` with a root pointing to a store path for each
+    /// subdirectory of `store`, whose size is the sum of the size of its files.
+    fn fixture_graph(store: &Path) -> DepInfos {
+        let mut paths: Vec<_> = fs::read_dir(store)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        paths.sort();
+        let names: Vec<_> = paths
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        let nodes: Vec<_> = names.iter().map(|name| (name.as_str(), 0)).collect();
+        let roots: Vec<_> = (0..paths.len() as u32).collect();
+        let mut di = DepInfos::from_spec(&nodes, &[], &roots);
+        for (i, path) in paths.iter().enumerate() {
+            let size = walkdir::WalkDir::new(path)
+                .into_iter()
+                .map(|e| e.unwrap())
+                .filter(|e| e.file_type().is_file())
+                .map(|e| e.metadata().unwrap().len())
+                .sum();
+            let node = &mut di.graph[NodeIndex::new(i)];
+            node.description =
+                NodeDescription::Path(path.as_os_str().to_string_lossy().into_owned().into_bytes());
+            node.size = size;
+        }
+        di.metadata.size = enum_map::enum_map! { _ => enum_map::enum_map!{ _ => None }};
+        di.record_metadata();
+        di
+    }
+
+    /// a store with three paths a, b and c, sharing some files
+    fn hardlinked_store(name: &str) -> PathBuf {
+        let store = test_dir(name);
+        for dir in &["a", "b", "c", "c/sub"] {
+            fs::create_dir(store.join(dir)).unwrap();
+        }
+        fs::write(store.join("a/big"), vec![0u8; 1000]).unwrap();
+        fs::write(store.join("a/own"), vec![1u8; 500]).unwrap();
+        fs::write(store.join("b/own"), vec![2u8; 300]).unwrap();
+        fs::hard_link(store.join("a/big"), store.join("b/big")).unwrap();
+        fs::hard_link(store.join("a/big"), store.join("c/sub/big")).unwrap();
+        fs::hard_link(store.join("b/own"), store.join("c/b_own")).unwrap();
+        store
+    }
+
+    type Fingerprint = (Vec<(String, u64)>, Vec<(String, String)>);
+
+    /// sizes by node name, and edges by node names
+    fn fingerprint(di: &DepInfos) -> Fingerprint {
+        let name = |idx: NodeIndex| String::from_utf8_lossy(&di.graph[idx].name()).into_owned();
+        let mut nodes: Vec<_> = di
+            .graph
+            .node_indices()
+            .map(|idx| (name(idx), di.graph[idx].size))
+            .collect();
+        nodes.sort();
+        let mut edges: Vec<_> = di
+            .graph
+            .raw_edges()
+            .iter()
+            .map(|e| (name(e.source()), name(e.target())))
+            .collect();
+        edges.sort();
+        (nodes, edges)
+    }
+
+    #[test]
+    fn quiet_progress_bar() {
+        assert!(progress_bar(10, Verbosity::Quiet).is_hidden());
+        assert!(progress_bar(10, Verbosity::Verbose).is_hidden());
+    }
+
+    #[test]
+    fn refine_hardlinks() {
+        let store = hardlinked_store("refine_hardlinks");
+        let orig = fixture_graph(&store);
+        let mut serial = orig.clone();
+        refine(&mut serial, &RefineOptions::default(), false).unwrap();
+        serial.check_metadata();
+        assert_eq!(serial.reachable_size(), 1000 + 500 + 300);
+        let (nodes, edges) = fingerprint(&serial);
+        let shared: Vec<_> = nodes
+            .iter()
+            .filter(|(name, _)| name.starts_with("shared:"))
+            .map(|(_, size)| *size)
+            .collect();
+        assert_eq!(shared.len(), 2);
+        assert_eq!(shared.iter().sum::<u64>(), 1300);
+        assert_eq!(edges.len(), 3 + 5);
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_shared_prefix() {
+        let store = hardlinked_store("refine_shared_prefix");
+        let orig = fixture_graph(&store);
+        // shared nodes are named after the second path holding the inodes
+        for (prefix, suffix) in &[("dedup/", ""), ("{dedup: ", "}")] {
+            let mut di = orig.clone();
+            let options = RefineOptions {
+                shared_prefix: Some(prefix.as_bytes().to_vec()),
+                ..Default::default()
+            };
+            refine(&mut di, &options, false).unwrap();
+            let mut names: Vec<_> = di
+                .graph
+                .node_weights()
+                .filter(|n| n.kind() == NodeKind::Shared)
+                .map(|n| String::from_utf8_lossy(&n.name()).into_owned())
+                .collect();
+            names.sort();
+            let expected: Vec<_> = ["b", "c"]
+                .iter()
+                .map(|p| format!("{}{}{}", prefix, store.join(p).display(), suffix))
+                .collect();
+            assert_eq!(names, expected);
+        }
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_edge_weights() {
+        let store = hardlinked_store("refine_edge_weights");
+        let mut di = fixture_graph(&store);
+        refine(&mut di, &RefineOptions::default(), false).unwrap();
+        let mut into_shared: Vec<_> = di
+            .graph
+            .raw_edges()
+            .iter()
+            .filter(|e| di.graph[e.target()].kind() == NodeKind::Shared)
+            .map(|e| {
+                assert_eq!(e.weight.shared_size, Some(di.graph[e.target()].size));
+                e.weight.shared_size.unwrap()
+            })
+            .collect();
+        into_shared.sort();
+        // big is in a, b and c, b/own is also in c
+        assert_eq!(into_shared, vec![300, 300, 1000, 1000, 1000]);
+        for e in di.graph.raw_edges() {
+            if di.graph[e.target()].kind() != NodeKind::Shared {
+                assert_eq!(e.weight.shared_size, None);
+            }
+        }
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_links_dir() {
+        let store = hardlinked_store("refine_links_dir");
+        let orig = fixture_graph(&store);
+        let mut full = orig.clone();
+        refine(&mut full, &RefineOptions::default(), false).unwrap();
+        assert_eq!(linked_inodes(&orig), None);
+
+        // what store optimisation would have done
+        fs::create_dir(store.join(".links")).unwrap();
+        fs::hard_link(store.join("a/big"), store.join(".links/big")).unwrap();
+        fs::hard_link(store.join("b/own"), store.join(".links/own")).unwrap();
+        assert_eq!(linked_inodes(&orig).map(|l| l.len()), Some(2));
+        let mut with_links = orig.clone();
+        refine(&mut with_links, &RefineOptions::default(), false).unwrap();
+        with_links.check_metadata();
+        assert_eq!(fingerprint(&full), fingerprint(&with_links));
+
+        // hardlinks not made by store optimisation are ignored
+        fs::remove_file(store.join(".links/own")).unwrap();
+        let mut partial = orig;
+        refine(&mut partial, &RefineOptions::default(), false).unwrap();
+        assert_eq!(partial.reachable_size(), 1000 + 500 + 300 + 300);
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_skips_links_dir() {
+        let store = hardlinked_store("refine_skips_links_dir");
+        let roots = test_dir("refine_skips_links_dir_roots");
+        let orig = fixture_graph(&store);
+        fs::create_dir(store.join(".links")).unwrap();
+        fs::hard_link(store.join("a/big"), store.join(".links/big")).unwrap();
+        let mut expected = orig.clone();
+        refine(&mut expected, &RefineOptions::default(), false).unwrap();
+
+        // paths into .links, directly and through a symlink
+        std::os::unix::fs::symlink(store.join(".links"), roots.join("links")).unwrap();
+        let mut di = orig.clone();
+        let near: Vec<_> = [store.join(".links"), roots.join("links/big")]
+            .iter()
+            .map(|path| {
+                let idx = di.graph.add_node(DepNode {
+                    description: NodeDescription::Path(path.as_os_str().as_bytes().to_vec()),
+                    size: 0,
+                    link_path: None,
+                });
+                di.graph.add_edge(di.root, idx, Edge::default());
+                idx
+            })
+            .collect();
+        di.metadata.size = enum_map::enum_map! { _ => enum_map::enum_map!{ _ => None }};
+        di.record_metadata();
+        refine(&mut di, &RefineOptions::default(), false).unwrap();
+        di.check_metadata();
+        assert_eq!(di.reachable_size(), expected.reachable_size());
+        for &idx in &near {
+            assert_eq!(di.graph[idx].size, 0);
+            assert_eq!(di.graph.neighbors(idx).count(), 0);
+        }
+        let (nodes, _) = fingerprint(&di);
+        let shared = |nodes: &[(String, u64)]| {
+            nodes
+                .iter()
+                .filter(|(name, _)| name.starts_with("shared:"))
+                .count()
+        };
+        assert_eq!(shared(&nodes), shared(&fingerprint(&expected).0));
+        fs::remove_dir_all(&store).unwrap();
+        fs::remove_dir_all(&roots).unwrap();
+    }
+
+    #[test]
+    fn refine_fractional_links() {
+        let store = test_dir("refine_fractional_links");
+        let outside = test_dir("refine_fractional_links_outside");
+        for dir in &["a", "b"] {
+            fs::create_dir(store.join(dir)).unwrap();
+        }
+        fs::write(store.join("a/own"), vec![0u8; 1000]).unwrap();
+        fs::hard_link(store.join("a/own"), outside.join("own")).unwrap();
+        fs::write(store.join("a/shared"), vec![1u8; 900]).unwrap();
+        fs::hard_link(store.join("a/shared"), store.join("b/shared")).unwrap();
+        fs::hard_link(store.join("a/shared"), outside.join("shared")).unwrap();
+        let orig = fixture_graph(&store);
+
+        let mut default = orig.clone();
+        refine(&mut default, &RefineOptions::default(), false).unwrap();
+        default.check_metadata();
+        assert_eq!(default.reachable_size(), 1000 + 900);
+
+        let options = RefineOptions {
+            fractional_links: true,
+            ..Default::default()
+        };
+        let fractional = |options: &RefineOptions| {
+            let mut di = orig.clone();
+            refine(&mut di, options, false).unwrap();
+            di.check_metadata();
+            for e in di.graph.raw_edges() {
+                if di.graph[e.target()].kind() == NodeKind::Shared {
+                    assert_eq!(e.weight.shared_size, Some(di.graph[e.target()].size));
+                }
+            }
+            di
+        };
+        // own has 2 links, one outside; shared has 3 links, one outside
+        let di = fractional(&options);
+        assert_eq!(di.reachable_size(), 500 + 600);
+
+        // the link in .links does not count as outside
+        fs::create_dir(store.join(".links")).unwrap();
+        fs::hard_link(store.join("a/shared"), store.join(".links/shared")).unwrap();
+        let di = fractional(&options);
+        assert_eq!(di.reachable_size(), 500 + 600);
+        fs::remove_file(outside.join("shared")).unwrap();
+        let di = fractional(&options);
+        assert_eq!(di.reachable_size(), 500 + 900);
+        fs::remove_dir_all(&store).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn refine_root_links() {
+        let store = test_dir("refine_root_links");
+        let roots = test_dir("refine_root_links_roots");
+        fs::create_dir(store.join("a")).unwrap();
+        fs::write(store.join("a/own"), vec![0u8; 1000]).unwrap();
+        let mut di = fixture_graph(&store);
+        let target = NodeIndex::new(0);
+        di.graph.clear_edges();
+        let link_size = store.join("a").as_os_str().len() as u64;
+        let mut add_root = |name: &str| {
+            let path = roots.join(name);
+            let node = di.graph.add_node(DepNode {
+                description: NodeDescription::Link(std::os::unix::ffi::OsStringExt::into_vec(
+                    path.into_os_string(),
+                )),
+                size: 0,
+                link_path: None,
+            });
+            di.graph.add_edge(di.root, node, Edge::default());
+            di.graph.add_edge(node, target, Edge::default());
+        };
+        add_root("result");
+        add_root("other-result");
+        std::os::unix::fs::symlink(store.join("a"), roots.join("result")).unwrap();
+        std::os::unix::fs::symlink(store.join("a"), roots.join("other-result")).unwrap();
+        di.record_metadata();
+
+        // roots of size 0 do not inflate the total, and their target is counted once
+        let mut default = di.clone();
+        refine(&mut default, &RefineOptions::default(), false).unwrap();
+        default.check_metadata();
+        assert_eq!(default.reachable_size(), 1000);
+
+        let options = RefineOptions {
+            root_links: true,
+            ..Default::default()
+        };
+        let mut with_links = di.clone();
+        refine(&mut with_links, &options, false).unwrap();
+        with_links.check_metadata();
+        assert_eq!(with_links.reachable_size(), 1000 + 2 * link_size);
+
+        // the same symlink hardlinked as two roots is counted once
+        fs::remove_file(roots.join("other-result")).unwrap();
+        fs::hard_link(roots.join("result"), roots.join("other-result")).unwrap();
+        let mut hardlinked = di;
+        refine(&mut hardlinked, &options, false).unwrap();
+        hardlinked.check_metadata();
+        assert_eq!(hardlinked.reachable_size(), 1000 + link_size);
+        fs::remove_dir_all(&store).unwrap();
+        fs::remove_dir_all(&roots).unwrap();
+    }
+
+    #[test]
+    fn refine_root_links_store_root() {
+        // the root is /nix-du-test-result in a chroot store at base
+        let base = test_dir("refine_root_links_store_root");
+        std::os::unix::fs::symlink("target", base.join("nix-du-test-result")).unwrap();
+        let mut di = DepInfos::from_spec(&[], &[], &[]);
+        let link = di.graph.add_node(DepNode {
+            description: NodeDescription::Link(b"/nix-du-test-result".to_vec()),
+            size: 0,
+            link_path: None,
+        });
+        di.graph.add_edge(di.root, link, Edge::default());
+        di.store_root = Some(base.clone());
+        di.record_metadata();
+        let options = RefineOptions {
+            root_links: true,
+            ..Default::default()
+        };
+        refine(&mut di, &options, false).unwrap();
+        di.check_metadata();
+        assert_eq!(di.graph[link].size, "target".len() as u64);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn store_is_optimised_probing() {
+        let store = test_dir("store_is_optimised_probing");
+        fs::create_dir_all(store.join("a")).unwrap();
+        fs::create_dir_all(store.join(".links")).unwrap();
+        for i in 0..20 {
+            fs::write(store.join(format!(".links/{}", i)), b"alone").unwrap();
+        }
+        let mut di = DepInfos::from_spec(&[("a", 0)], &[], &[0]);
+        di.graph[NodeIndex::new(0)].description = NodeDescription::Path(
+            std::os::unix::ffi::OsStringExt::into_vec(store.join("a").into_os_string()),
+        );
+        assert_eq!(
+            super::store_is_optimised_probing(&di, 20).unwrap(),
+            Some(false)
+        );
+        assert_eq!(super::store_is_optimised_probing(&di, 5).unwrap(), None);
+        assert_eq!(super::store_is_optimised_probing(&di, 0).unwrap(), None);
+        // only the last file created is optimised
+        fs::write(store.join("a/file"), b"content").unwrap();
+        fs::hard_link(store.join("a/file"), store.join(".links/file")).unwrap();
+        assert_eq!(
+            super::store_is_optimised_probing(&di, 21).unwrap(),
+            Some(true)
+        );
+        // sampling a few of the files finds it sometimes, never claims otherwise
+        let found = (0..100)
+            .map(|_| super::store_is_optimised_probing(&di, 5).unwrap())
+            .inspect(|res| assert_ne!(*res, Some(false)))
+            .filter(|&res| res == Some(true))
+            .count();
+        assert!(found > 0);
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn store_is_optimised_root_without_child() {
+        // an empty profile, before any store path
+        let empty_root = |di: &mut DepInfos| {
+            let idx = di.graph.add_node(DepNode {
+                description: NodeDescription::Link(b"/nix/var/nix/profiles/empty".to_vec()),
+                size: 0,
+                link_path: None,
+            });
+            di.graph.add_edge(di.root, idx, Edge::default());
+        };
+        let mut di = DepInfos::from_spec(&[], &[], &[]);
+        empty_root(&mut di);
+        assert_eq!(store_is_optimised(&di).unwrap(), None);
+
+        // the store is still found from other roots
+        let store = test_dir("store_is_optimised_root_without_child");
+        fs::create_dir_all(store.join("a")).unwrap();
+        fs::create_dir_all(store.join(".links")).unwrap();
+        fs::write(store.join("a/file"), b"content").unwrap();
+        fs::hard_link(store.join("a/file"), store.join(".links/file")).unwrap();
+        let mut di = DepInfos::from_spec(&[], &[], &[]);
+        empty_root(&mut di);
+        let a = di.graph.add_node(DepNode {
+            description: NodeDescription::Path(std::os::unix::ffi::OsStringExt::into_vec(
+                store.join("a").into_os_string(),
+            )),
+            size: 7,
+            link_path: None,
+        });
+        di.graph.add_edge(di.root, a, Edge::default());
+        assert_eq!(store_is_optimised(&di).unwrap(), Some(true));
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_directory_sizes() {
+        let store = test_dir("refine_directory_sizes");
+        let mut directories = 0;
+        for i in 0..20 {
+            let dir = store.join(format!("a/{}/{}", i % 4, i));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("file"), vec![0u8; 10]).unwrap();
+        }
+        for entry in walkdir::WalkDir::new(store.join("a")) {
+            let entry = entry.unwrap();
+            if entry.file_type().is_dir() {
+                directories += entry.metadata().unwrap().len();
+            }
+        }
+        assert!(directories > 0);
+        let orig = fixture_graph(&store);
+
+        let mut default = orig.clone();
+        refine(&mut default, &RefineOptions::default(), false).unwrap();
+        assert_eq!(default.reachable_size(), 20 * 10);
+
+        let options = RefineOptions {
+            directory_sizes: true,
+            ..Default::default()
+        };
+        let mut with_directories = orig;
+        refine(&mut with_directories, &options, false).unwrap();
+        with_directories.check_metadata();
+        assert_eq!(with_directories.reachable_size(), 20 * 10 + directories);
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_progress() {
+        let store = hardlinked_store("refine_progress");
+        let orig = fixture_graph(&store);
+        let total = orig.graph.node_count();
+        for &parallel in &[false, true] {
+            let calls = std::sync::Mutex::new(Vec::new());
+            let mut di = orig.clone();
+            refine_with(
+                &mut di,
+                &RefineOptions::default(),
+                parallel,
+                &|done, total| calls.lock().unwrap().push((done, total)),
+            )
+            .unwrap();
+            let mut calls = calls.into_inner().unwrap();
+            assert_eq!(calls.len(), total);
+            calls.sort();
+            for (i, &call) in calls.iter().enumerate() {
+                assert_eq!(call, (i + 1, total));
+            }
+        }
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_block_size() {
+        let store = test_dir("refine_block_size");
+        for dir in &["a", "b"] {
+            fs::create_dir(store.join(dir)).unwrap();
+        }
+        fs::write(store.join("a/tiny"), b"x").unwrap();
+        fs::write(store.join("a/big"), vec![0u8; 5000]).unwrap();
+        fs::write(store.join("b/empty"), b"").unwrap();
+        fs::hard_link(store.join("a/big"), store.join("b/big")).unwrap();
+        let orig = fixture_graph(&store);
+
+        let options = RefineOptions {
+            block_size: Some(4096),
+            ..Default::default()
+        };
+        let mut di = orig.clone();
+        refine(&mut di, &options, false).unwrap();
+        di.check_metadata();
+        let (nodes, _) = fingerprint(&di);
+        assert_eq!(
+            nodes
+                .iter()
+                .map(|(name, size)| (name.rsplit('/').next().unwrap(), *size))
+                .collect::<Vec<_>>(),
+            // the second b is the shared node
+            vec![("a", 4096), ("b", 0), ("b", 8192), ("{dummy}", 0)]
+        );
+
+        // a 1 byte file takes a block
+        fs::remove_file(store.join("b/big")).unwrap();
+        fs::remove_file(store.join("a/big")).unwrap();
+        let mut di = fixture_graph(&store);
+        assert_eq!(di.reachable_size(), 1);
+        refine(&mut di, &options, false).unwrap();
+        assert_eq!(di.reachable_size(), 4096);
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn account_devices() {
+        // we cannot make several devices in a test, so pretend
+        let mut di = DepInfos::from_spec(&[("a", 1000), ("b", 1000)], &[], &[0, 1]);
+        let file = |dev| File {
+            id: (dev, 42),
+            size: 600,
+            nlink: 1,
+            links: 1,
+        };
+        let walk = |file| {
+            Ok(Walk {
+                files: vec![file],
+                ..Walk::default()
+            })
+        };
+        let mut same = di.clone();
+        account::<FastMap<_>, FastMap<_>>(
+            &mut same,
+            vec![walk(file(1)), walk(file(1))],
+            None,
+            &RefineOptions::default(),
+        )
+        .unwrap();
+        same.check_metadata();
+        assert_eq!(same.reachable_size(), 2000 - 600);
+        account::<FastMap<_>, FastMap<_>>(
+            &mut di,
+            vec![walk(file(1)), walk(file(2))],
+            None,
+            &RefineOptions::default(),
+        )
+        .unwrap();
+        di.check_metadata();
+        assert_eq!(di.reachable_size(), 2000);
+        assert!(di
+            .graph
+            .node_weights()
+            .all(|n| n.kind() != NodeKind::Shared));
+    }
+
+    #[test]
+    fn stat_shared_only() {
+        let store = hardlinked_store("stat_shared_only");
+        let di = fixture_graph(&store);
+        let options = RefineOptions {
+            directory_sizes: true,
+            ..RefineOptions::default()
+        };
+        let walk = |stat| {
+            (0..di.graph.node_count())
+                .map(|i| files_of(&di, NodeIndex::new(i), &options, None, stat, None))
+                .collect::<Vec<_>>()
+        };
+        let unstated = walk(false);
+        assert!(unstated
+            .iter()
+            .all(|w| w.as_ref().unwrap().files.is_empty()));
+        let completed = stat_shared(&di, unstated, &options, false, None).unwrap();
+        // only hardlinked files are stat'ed
+        for walk in &completed {
+            assert!(walk.as_ref().unwrap().files.iter().all(|f| f.nlink > 1));
+        }
+        let accounted = |walks| {
+            let mut res = di.clone();
+            account::<FastMap<_>, FastMap<_>>(&mut res, walks, None, &options).unwrap();
+            res.check_metadata();
+            fingerprint(&res)
+        };
+        assert_eq!(accounted(completed), accounted(walk(true)));
+    }
+
+    #[test]
+    fn account_inode_maps() {
+        let store = hardlinked_store("account_inode_maps");
+        let di = fixture_graph(&store);
+        let options = RefineOptions {
+            fractional_links: true,
+            ..RefineOptions::default()
+        };
+        let accounted = |account: &dyn Fn(&mut DepInfos, Vec<Result<Walk>>)| {
+            let walks = (0..di.graph.node_count())
+                .map(|i| files_of(&di, NodeIndex::new(i), &options, None, true, None))
+                .collect();
+            let mut res = di.clone();
+            account(&mut res, walks);
+            res.check_metadata();
+            fingerprint(&res)
+        };
+        let fast = accounted(&|di, walks| {
+            account::<FastMap<_>, FastMap<_>>(di, walks, None, &options).unwrap()
+        });
+        let ordered = accounted(&|di, walks| {
+            account::<BTreeMap<_, _>, BTreeMap<_, _>>(di, walks, None, &options).unwrap()
+        });
+        let default = accounted(&|di, walks| {
+            account::<HashMap<_, _>, HashMap<_, _>>(di, walks, None, &options).unwrap()
+        });
+        assert_eq!(fast, ordered);
+        assert_eq!(fast, default);
+        // the fixture does share files
+        assert!(fast.0.iter().any(|(name, _)| name.starts_with("shared:")));
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_store_root() {
+        // same as refine_hardlinks, but with store paths relative to a store root
+        let store = hardlinked_store("refine_store_root");
+        let mut di = fixture_graph(&store);
+        let base = store.parent().unwrap();
+        for node in di.graph.node_weights_mut() {
+            if let NodeDescription::Path(p) = &mut node.description {
+                let relative = Path::new(std::str::from_utf8(p).unwrap())
+                    .strip_prefix(base)
+                    .unwrap();
+                *p = format!("/{}", relative.display()).into_bytes();
+            }
+        }
+        di.store_root = Some(base.to_owned());
+        refine(&mut di, &RefineOptions::default(), false).unwrap();
+        di.check_metadata();
+        assert_eq!(di.reachable_size(), 1000 + 500 + 300);
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_disk_usage() {
+        let store = test_dir("refine_disk_usage");
+        for dir in &["a", "b"] {
+            fs::create_dir(store.join(dir)).unwrap();
+        }
+        let apparent = 10 << 20;
+        fs::File::create(store.join("a/sparse"))
+            .unwrap()
+            .set_len(apparent)
+            .unwrap();
+        fs::hard_link(store.join("a/sparse"), store.join("b/sparse")).unwrap();
+        let orig = fixture_graph(&store);
+        let shared_size = |options: &RefineOptions| {
+            let mut di = orig.clone();
+            refine(&mut di, options, false).unwrap();
+            di.check_metadata();
+            let shared = di
+                .graph
+                .node_indices()
+                .find(|&idx| di.graph[idx].kind() == NodeKind::Shared)
+                .unwrap();
+            di.graph[shared].size
+        };
+        assert_eq!(shared_size(&RefineOptions::default()), apparent);
+        let options = RefineOptions {
+            disk_usage: true,
+            ..Default::default()
+        };
+        assert!(shared_size(&options) < apparent / 10);
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_hardlinks_in_same_path() {
+        let store = test_dir("refine_hardlinks_in_same_path");
+        for dir in &["a", "b"] {
+            fs::create_dir(store.join(dir)).unwrap();
+        }
+        fs::write(store.join("a/file"), vec![0u8; 1000]).unwrap();
+        fs::hard_link(store.join("a/file"), store.join("a/link")).unwrap();
+        fs::write(store.join("b/file"), vec![0u8; 1000]).unwrap();
+        let orig = fixture_graph(&store);
+        let mut di = orig.clone();
+        refine(&mut di, &RefineOptions::default(), false).unwrap();
+        assert_eq!(fingerprint(&orig), fingerprint(&di));
+
+        // a diamond: the inode hardlinked twice in a is also in b, both point to a
+        // single shared node, and a is only reduced once
+        fs::remove_file(store.join("b/file")).unwrap();
+        fs::hard_link(store.join("a/file"), store.join("b/copy")).unwrap();
+        fs::write(store.join("a/own"), vec![1u8; 10]).unwrap();
+        let orig = fixture_graph(&store);
+        let mut di = orig.clone();
+        refine(&mut di, &RefineOptions::default(), false).unwrap();
+        di.check_metadata();
+        let shared: Vec<_> = di
+            .graph
+            .node_indices()
+            .filter(|&idx| di.graph[idx].kind() == NodeKind::Shared)
+            .collect();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(di.graph[shared[0]].size, 1000);
+        let holders = di
+            .graph
+            .neighbors_directed(shared[0], petgraph::Direction::Incoming)
+            .count();
+        assert_eq!(holders, 2);
+        let (a, b) = (NodeIndex::new(0), NodeIndex::new(1));
+        assert_eq!(orig.graph[a].size, 2010);
+        assert_eq!(di.graph[a].size, 2010 - 1000);
+        assert_eq!(di.graph[b].size, 0);
+        // the shared file is counted once, the duplicate within a is not shared
+        assert_eq!(di.reachable_size(), 2010);
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_errors() {
+        let store = hardlinked_store("refine_errors");
+        let mut di = fixture_graph(&store);
+        let missing = di.graph.add_node(DepNode {
+            description: NodeDescription::Path(
+                store
+                    .join("missing")
+                    .as_os_str()
+                    .to_string_lossy()
+                    .into_owned()
+                    .into_bytes(),
+            ),
+            size: 42,
+            link_path: None,
+        });
+        di.graph.add_edge(di.root, missing, Edge::default());
+        let mut fatal = di.clone();
+        let options = RefineOptions {
+            fatal_errors: true,
+            ..Default::default()
+        };
+        assert!(refine(&mut fatal, &options, false).is_err());
+        refine(&mut di, &RefineOptions::default(), false).unwrap();
+        assert_eq!(di.graph[missing].size, 42);
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+        let store = hardlinked_store("refine_permission_denied");
+        let mut di = fixture_graph(&store);
+        let sub = store.join("c/sub");
+        fs::set_permissions(&sub, fs::Permissions::from_mode(0o000)).unwrap();
+        if fs::read_dir(&sub).is_ok() {
+            // running as root, permissions are not enforced
+            fs::set_permissions(&sub, fs::Permissions::from_mode(0o755)).unwrap();
+            fs::remove_dir_all(&store).unwrap();
+            return;
+        }
+        let options = RefineOptions {
+            fatal_errors: true,
+            ..Default::default()
+        };
+        refine(&mut di, &options, false).unwrap();
+        di.check_metadata();
+        // c/sub/big is not seen, so only a and b share big
+        assert_eq!(di.reachable_size(), 1500 + 300 + 1000);
+        fs::set_permissions(&sub, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_underflow() {
+        let store = hardlinked_store("refine_underflow");
+        let mut di = fixture_graph(&store);
+        for node in di.graph.node_weights_mut() {
+            if node.kind() == NodeKind::Path {
+                node.size = 10;
+            }
+        }
+        refine(&mut di, &RefineOptions::default(), false).unwrap();
+        for node in di.graph.node_weights() {
+            if node.kind() == NodeKind::Path {
+                assert_eq!(node.size, 0);
+            }
+        }
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn refine_parallel() {
+        let store = hardlinked_store("refine_parallel");
+        let orig = fixture_graph(&store);
+        let mut serial = orig.clone();
+        refine(&mut serial, &RefineOptions::default(), false).unwrap();
+        let mut parallel = orig;
+        refine(&mut parallel, &RefineOptions::default(), true).unwrap();
+        assert_eq!(fingerprint(&serial), fingerprint(&parallel));
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn refine_jobs() {
+        let store = hardlinked_store("refine_jobs");
+        let orig = fixture_graph(&store);
+        let mut serial = orig.clone();
+        refine(&mut serial, &RefineOptions::default(), false).unwrap();
+        for jobs in &[1, 4] {
+            let options = RefineOptions {
+                jobs: Some(*jobs),
+                ..Default::default()
+            };
+            let mut di = orig.clone();
+            refine(&mut di, &options, true).unwrap();
+            di.check_metadata();
+            assert_eq!(fingerprint(&serial), fingerprint(&di), "jobs={}", jobs);
+        }
+        fs::remove_dir_all(&store).unwrap();
+    }
 }
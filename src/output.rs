@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// the temporary file `write_atomically` writes before renaming it to `dest`. It
+/// is in the same directory, so that the rename does not cross filesystems.
+fn temporary_path(dest: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(dest.file_name().unwrap_or_else(|| "output".as_ref()));
+    name.push(format!(".{}.tmp", std::process::id()));
+    match dest.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.join(name),
+        _ => PathBuf::from(name),
+    }
+}
+
+/// Calls `write` on a temporary file next to `dest`, and renames it to `dest` if
+/// it succeeds, so that `dest` is either left as is or replaced by the complete
+/// output, even if the program is interrupted.
+///
+/// On error, the temporary file is removed.
+pub fn write_atomically<F>(dest: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let tmp = temporary_path(dest);
+    let res = fs::File::create(&tmp).and_then(|f| {
+        let mut w = io::BufWriter::new(f);
+        write(&mut w)?;
+        let f = w.into_inner().map_err(io::IntoInnerError::into_error)?;
+        f.sync_all()?;
+        fs::rename(&tmp, dest)
+    });
+    if res.is_err() {
+        // it may not have been created
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_atomically;
+    use std::fs;
+    use std::io;
+
+    #[test]
+    fn interrupted_write() {
+        let dir = std::env::temp_dir().join(format!("nix-du-test-output-{}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("graph.dot");
+        fs::write(&dest, "old").unwrap();
+
+        let err = write_atomically(&dest, |w| {
+            w.write_all(b"digraph nixstore {\n")?;
+            Err(io::Error::other("interrupted"))
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "interrupted");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "old");
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        write_atomically(&dest, |w| w.write_all(b"new")).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // a missing directory is an error, not a panic
+        assert!(write_atomically(&dir.join("missing/graph.dot"), |_| Ok(())).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
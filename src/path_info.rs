@@ -0,0 +1,489 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::iter::Peekable;
+use std::process::{Command, Stdio};
+use std::str::Chars;
+
+/// A JSON value. Numbers are kept as written, to be parsed by the consumer, and
+/// booleans are not kept at all as no field we use is a boolean.
+#[derive(Debug)]
+enum Value {
+    Null,
+    Bool,
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> io::Result<&str> {
+        match self {
+            Value::String(s) => Ok(s),
+            v => Err(invalid_data(format!("expected a string, got {:?}", v))),
+        }
+    }
+
+    fn as_u64(&self) -> io::Result<u64> {
+        match self {
+            Value::Number(n) => n
+                .parse()
+                .map_err(|_| invalid_data(format!("expected a size, got {}", n))),
+            v => Err(invalid_data(format!("expected a number, got {:?}", v))),
+        }
+    }
+
+    fn as_array(&self) -> io::Result<&[Value]> {
+        match self {
+            Value::Array(a) => Ok(a),
+            v => Err(invalid_data(format!("expected an array, got {:?}", v))),
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: Peekable<Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_ws(&mut self) {
+        while matches!(self.input.peek(), Some(c) if c.is_whitespace()) {
+            self.input.next();
+        }
+    }
+
+    fn next(&mut self) -> io::Result<char> {
+        self.input
+            .next()
+            .ok_or_else(|| invalid_data("unexpected end of input".into()))
+    }
+
+    fn expect(&mut self, word: &str) -> io::Result<()> {
+        for c in word.chars() {
+            if self.next()? != c {
+                return Err(invalid_data(format!("expected {}", word)));
+            }
+        }
+        Ok(())
+    }
+
+    fn hex4(&mut self) -> io::Result<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.next()?;
+            let digit = digit
+                .to_digit(16)
+                .ok_or_else(|| invalid_data(format!("bad escape digit {:?}", digit)))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => match self.next()? {
+                    c @ ('"' | '\\' | '/') => s.push(c),
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.hex4()?;
+                        if (0xd800..0xdc00).contains(&code) {
+                            // high surrogate, the low one follows
+                            self.expect("\\u")?;
+                            let low = self.hex4()?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return Err(invalid_data(format!("bad low surrogate {:x}", low)));
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        s.push(
+                            char::from_u32(code)
+                                .ok_or_else(|| invalid_data(format!("bad code point {}", code)))?,
+                        );
+                    }
+                    c => return Err(invalid_data(format!("bad escape {:?}", c))),
+                },
+                c if (c as u32) < 0x20 => {
+                    return Err(invalid_data(format!("unescaped control character {:?}", c)))
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    /// parses the separator after an element of an array or object, and returns
+    /// whether it was the last one
+    fn end_of(&mut self, close: char) -> io::Result<bool> {
+        self.skip_ws();
+        match self.next()? {
+            ',' => Ok(false),
+            c if c == close => Ok(true),
+            c => Err(invalid_data(format!(
+                "expected , or {}, got {:?}",
+                close, c
+            ))),
+        }
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        self.skip_ws();
+        match self.next()? {
+            'n' => self.expect("ull").map(|_| Value::Null),
+            't' => self.expect("rue").map(|_| Value::Bool),
+            'f' => self.expect("alse").map(|_| Value::Bool),
+            c @ ('-' | '0'..='9') => {
+                let mut n = c.to_string();
+                while let Some(&c) = self.input.peek() {
+                    if !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E') {
+                        break;
+                    }
+                    n.push(c);
+                    self.input.next();
+                }
+                if !is_number(&n) {
+                    return Err(invalid_data(format!("bad number {}", n)));
+                }
+                Ok(Value::Number(n))
+            }
+            '"' => self.string().map(Value::String),
+            '[' => {
+                let mut items = Vec::new();
+                self.skip_ws();
+                if self.input.peek() == Some(&']') {
+                    self.input.next();
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.end_of(']')? {
+                        return Ok(Value::Array(items));
+                    }
+                }
+            }
+            '{' => {
+                let mut fields = Vec::new();
+                self.skip_ws();
+                if self.input.peek() == Some(&'}') {
+                    self.input.next();
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    self.skip_ws();
+                    self.expect("\"")?;
+                    let key = self.string()?;
+                    self.skip_ws();
+                    self.expect(":")?;
+                    fields.push((key, self.value()?));
+                    if self.end_of('}')? {
+                        return Ok(Value::Object(fields));
+                    }
+                }
+            }
+            c => Err(invalid_data(format!("unexpected character {:?}", c))),
+        }
+    }
+}
+
+/// whether `n` follows the grammar of JSON numbers
+fn is_number(n: &str) -> bool {
+    let digits = |s: &str| s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let rest = n.strip_prefix('-').unwrap_or(n);
+    let int = digits(rest);
+    if int == 0 || (int > 1 && rest.starts_with('0')) {
+        return false;
+    }
+    let mut rest = &rest[int..];
+    if let Some(frac) = rest.strip_prefix('.') {
+        let len = digits(frac);
+        if len == 0 {
+            return false;
+        }
+        rest = &frac[len..];
+    }
+    if let Some(exp) = rest.strip_prefix(['e', 'E']) {
+        let exp = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+        let len = digits(exp);
+        if len == 0 {
+            return false;
+        }
+        rest = &exp[len..];
+    }
+    rest.is_empty()
+}
+
+/// parses `input`, which must be a single JSON value
+fn parse(input: &str) -> io::Result<Value> {
+    let mut parser = Parser {
+        input: input.chars().peekable(),
+    };
+    let value = parser.value()?;
+    parser.skip_ws();
+    match parser.input.next() {
+        None => Ok(value),
+        Some(c) => Err(invalid_data(format!("trailing character {:?}", c))),
+    }
+}
+
+impl DepInfos {
+    /// reads the dependency graph from the JSON output of
+    /// `nix path-info --recursive --json --all`, for when libnixstore cannot
+    /// access the store.
+    ///
+    /// Both the old format (an array of objects with a `path` field) and the one
+    /// of nix 2.19 onwards (an object indexed by path, where invalid paths are `null`)
+    /// are accepted. Only `narSize` and `references` are used.
+    ///
+    /// This data contains no gc roots, so store paths which are not referenced by
+    /// any other path are considered roots.
+    pub fn read_from_path_info<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+        let value = parse(&input)?;
+        let infos: Vec<(&str, &Value)> = match &value {
+            Value::Array(items) => items
+                .iter()
+                .map(|info| {
+                    let path = info
+                        .get("path")
+                        .ok_or_else(|| invalid_data("path info without path".into()))?;
+                    Ok((path.as_str()?, info))
+                })
+                .collect::<io::Result<_>>()?,
+            Value::Object(fields) => fields
+                .iter()
+                .filter(|(_, info)| !matches!(info, Value::Null))
+                .map(|(path, info)| (path.as_str(), info))
+                .collect(),
+            v => return Err(invalid_data(format!("unexpected path info {:?}", v))),
+        };
+
+        let mut graph = DepGraph::new();
+        let mut index = HashMap::new();
+        for &(path, info) in &infos {
+            let size = match info.get("narSize") {
+                Some(size) => size.as_u64()?,
+                None => return Err(invalid_data(format!("no narSize for {}", path))),
+            };
+            let idx = graph.add_node(DepNode {
+                description: NodeDescription::Path(path.as_bytes().to_vec()),
+                size,
+                link_path: None,
+            });
+            index.insert(path, idx);
+        }
+        for &(path, info) in &infos {
+            let from = index[path];
+            let references = match info.get("references") {
+                Some(references) => references.as_array()?,
+                None => continue,
+            };
+            for reference in references {
+                let to = *index.get(reference.as_str()?).ok_or_else(|| {
+                    invalid_data(format!(
+                        "{} references {:?}, which is not in the input",
+                        path, reference
+                    ))
+                })?;
+                if from != to {
+                    graph.add_edge(from, to, Edge::default());
+                }
+            }
+        }
+        Ok(DepInfos::rooted_at_unreferenced(graph))
+    }
+
+    /// reads the dependency graph of the store of `host`, by running
+    /// `nix path-info --json` there with `ssh`. `host` is anything ssh accepts, like
+    /// `user@builder`. Errors of ssh and of nix are printed on stderr.
+    ///
+    /// Without `root`, all the valid paths of the remote store are read. As with
+    /// `read_from_path_info`, gc roots are not known.
+    pub fn read_over_ssh(host: &str, root: Option<&OsStr>) -> io::Result<Self> {
+        let mut command = Command::new("ssh");
+        command.arg(host).arg("--").args([
+            "nix",
+            "--extra-experimental-features",
+            "nix-command",
+            "path-info",
+            "--json",
+        ]);
+        match root {
+            Some(root) => command.arg("--recursive").arg(shell_quote(root)),
+            None => command.arg("--all"),
+        };
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let res = Self::read_from_path_info(child.stdout.take().expect("no piped stdout"));
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "nix path-info on {} failed: {}",
+                host, status
+            )));
+        }
+        res
+    }
+}
+
+/// quotes `arg` for a POSIX shell: ssh joins its arguments into a command line
+/// which the remote shell parses again.
+fn shell_quote(arg: &OsStr) -> OsString {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    let mut res = b"'".to_vec();
+    for &byte in arg.as_bytes() {
+        match byte {
+            b'\'' => res.extend_from_slice(b"'\\''"),
+            byte => res.push(byte),
+        }
+    }
+    res.push(b'\'');
+    OsString::from_vec(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+
+    /// captured from `nix path-info --recursive --json` on nix 2.18, trimmed
+    const OLD_FORMAT: &str = r#"[{"path":"/nix/store/0aqmw36wh3vkby9c5gaqsyvqms7ai7ph-hello-2.12.1","narHash":"sha256-iF/4yh4yg8YbajYFrPq0yVP7xyaJ+TiMVX/l5eM8sVo=","narSize":226488,"references":["/nix/store/0aqmw36wh3vkby9c5gaqsyvqms7ai7ph-hello-2.12.1","/nix/store/ddwyrxif62r8n6xclvskjyy6szdhvj60-glibc-2.39-5"],"deriver":"/nix/store/sqnrscb0c6dr2n4k5vqxhhxf5iv9m4yy-hello-2.12.1.drv","registrationTime":1717192283,"signatures":["cache.nixos.org-1:j4d0e0+mVQ5LUNfXJvW0IPQ7xiJ1uV9nx8TvQTL9amMlVkZSErUEsMMIZ2yHYr9NIFCrOg/ABPiBOoXvtbHNBg=="]},
+{"path":"/nix/store/ddwyrxif62r8n6xclvskjyy6szdhvj60-glibc-2.39-5","narHash":"sha256-pTn+mtW3Mopy2E3wOYMkKd4yH/tVl+m/N21VRGnuXe0=","narSize":30187096,"references":["/nix/store/ddwyrxif62r8n6xclvskjyy6szdhvj60-glibc-2.39-5","/nix/store/fdfrczpjzklj6rxyx2ghrmic02bmrbzl-xgcc-13.2.0-libgcc","/nix/store/skjdnms4gqpjyr4rq98cakm9wkb5md1j-libidn2-2.3.7"],"deriver":"/nix/store/7a3x6dl3yqwsb2vramhhy8aa5m7n5wv7-glibc-2.39-5.drv","registrationTime":1717192280,"signatures":[]},
+{"path":"/nix/store/fdfrczpjzklj6rxyx2ghrmic02bmrbzl-xgcc-13.2.0-libgcc","narHash":"sha256-wkIIfdOpBtR2AB0AnwMqeRI2XY2pCrdP340CQdFrpLw=","narSize":139704,"references":[],"deriver":"/nix/store/wc0l5jbc4ihsbqqqjwsx6arbjmzzpjqa-xgcc-13.2.0.drv","registrationTime":1717192278,"signatures":[]},
+{"path":"/nix/store/skjdnms4gqpjyr4rq98cakm9wkb5md1j-libidn2-2.3.7","narHash":"sha256-zAD7F/pSyRlXW7d1Zsgyjd0yHvZb6KWSBmBf23Q6r1s=","narSize":350392,"references":["/nix/store/skjdnms4gqpjyr4rq98cakm9wkb5md1j-libidn2-2.3.7","/nix/store/vky8hh1hr0s8c6rag4f7c80p1gvnlv2v-libunistring-1.1"],"deriver":null,"registrationTime":1717192279,"signatures":[],"ultimate":false},
+{"path":"/nix/store/vky8hh1hr0s8c6rag4f7c80p1gvnlv2v-libunistring-1.1","narHash":"sha256-MUeZcdSBc8fY+5ZiWfvaBAmZ0gc1Y4hw6/Ua+U3FAUA=","narSize":1871904,"references":["/nix/store/vky8hh1hr0s8c6rag4f7c80p1gvnlv2v-libunistring-1.1"],"registrationTime":1717192279,"signatures":[]}]"#;
+
+    fn check_sample(di: &DepInfos) {
+        di.check_metadata();
+        // 5 paths and the root
+        assert_eq!(di.graph.node_count(), 6);
+        // 4 references which are not self references, and one root
+        assert_eq!(di.graph.edge_count(), 5);
+        assert_eq!(
+            di.roots_name(),
+            vec!["hello-2.12.1".to_string()].into_iter().collect()
+        );
+        assert_eq!(
+            di.reachable_size(),
+            226488 + 30187096 + 139704 + 350392 + 1871904
+        );
+    }
+
+    #[test]
+    fn old_format() {
+        let di = DepInfos::read_from_path_info(OLD_FORMAT.as_bytes()).unwrap();
+        check_sample(&di);
+    }
+
+    #[test]
+    fn new_format() {
+        // turn the old format into the new one by hand
+        let mut new = String::from("{");
+        for (i, line) in OLD_FORMAT
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .lines()
+            .enumerate()
+        {
+            let line = line.trim_end_matches(',');
+            let path_end = line.find("\",").unwrap() + 1;
+            if i > 0 {
+                new.push(',');
+            }
+            new.push_str(&line["{\"path\":".len()..path_end]);
+            new.push_str(":{");
+            new.push_str(&line[path_end + 1..]);
+        }
+        new.push_str(r#","/nix/store/00000000000000000000000000000000-invalid":null}"#);
+        let di = DepInfos::read_from_path_info(new.as_bytes()).unwrap();
+        check_sample(&di);
+    }
+
+    #[test]
+    fn parse() {
+        let value =
+            super::parse(" {\"a\" : [1, -2.5e+3, \"\\\"\\u00e9\\n\"], \"b\":{}, \"c\":[]} ")
+                .unwrap();
+        let a = value.get("a").unwrap().as_array().unwrap();
+        assert_eq!(a[0].as_u64().unwrap(), 1);
+        assert!(matches!(&a[1], super::Value::Number(n) if n == "-2.5e+3"));
+        assert_eq!(a[2].as_str().unwrap(), "\"\u{e9}\n");
+        assert!(value.get("b").is_some() && value.get("c").is_some());
+        for input in &[
+            "[1 2]",
+            "[1,,2]",
+            "[,1]",
+            "[1,]",
+            "{\"a\":1 \"b\":2}",
+            "{\"a\":1,,\"b\":2}",
+            "{,}",
+            "{\"a\":1,}",
+            "{\"a\" 1}",
+            "[] x",
+            "{}{}",
+            "\"\\x\"",
+            "\"a\nb\"",
+            "01",
+            "1.",
+            "-",
+            "1e",
+            "1-2",
+        ] {
+            assert!(
+                super::parse(input).is_err(),
+                "{:?} should be invalid",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn errors() {
+        for input in &[
+            "",
+            "[",
+            "{\"/nix/store/a\":{}}",
+            "[{\"narSize\":1}]",
+            "[{\"path\":\"/nix/store/a\",\"narSize\":-1}]",
+            "[{\"path\":\"/nix/store/a\",\"narSize\":1,\"references\":[\"/nix/store/b\"]}]",
+        ] {
+            assert!(
+                DepInfos::read_from_path_info(input.as_bytes()).is_err(),
+                "{:?} should be invalid",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn shell_quote() {
+        use std::ffi::OsStr;
+        use std::process::Command;
+        for arg in &["/nix/store/a", "a b; echo $HOME", "it's \"here\"", "*"] {
+            let quoted = super::shell_quote(OsStr::new(arg));
+            // the shell gives back the argument unchanged
+            let mut script = std::ffi::OsString::from("printf %s ");
+            script.push(&quoted);
+            let out = Command::new("sh").arg("-c").arg(script).output().unwrap();
+            assert_eq!(String::from_utf8(out.stdout).unwrap(), *arg);
+        }
+        assert_eq!(super::shell_quote(OsStr::new("it's")), "'it'\\''s'");
+    }
+}
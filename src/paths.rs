@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::{self, NodeDescription};
+use std::io::{self, Write};
+
+/// Outputs the store paths of the graph, sorted, one per line, as expected by
+/// `xargs nix-store --delete`.
+///
+/// Only nodes which are store paths are output: gc roots, transient roots and
+/// nodes made up by nix-du, like `Shared` nodes or the dummy root, are skipped.
+pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Result<()> {
+    let mut paths: Vec<_> = dependencies
+        .graph
+        .raw_nodes()
+        .iter()
+        .filter_map(|node| match &node.weight.description {
+            NodeDescription::Path(path) => Some(path),
+            _ => None,
+        })
+        .collect();
+    paths.sort();
+    for path in paths {
+        w.write_all(path)?;
+        w.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+
+    #[test]
+    fn render() {
+        let mut di = DepInfos::from_spec(&[("b", 1), ("a", 2), ("c", 4)], &[(0, 1)], &[0]);
+        for description in [
+            NodeDescription::Link(b"/nix/var/nix/gcroots/auto/x".to_vec()),
+            NodeDescription::Memory(b"{memory:1}".to_vec()),
+            NodeDescription::Shared(b"a".to_vec()),
+            NodeDescription::Cycle(b"{cycle: a, b}".to_vec()),
+            NodeDescription::Transient,
+            NodeDescription::Dedup,
+        ] {
+            let idx = di.graph.add_node(DepNode {
+                description,
+                size: 8,
+                link_path: None,
+            });
+            di.graph.add_edge(di.root, idx, Edge::default());
+        }
+        let mut out = Vec::new();
+        super::render(&di, &mut out).unwrap();
+        let expected: String = [(0, "b"), (1, "a"), (2, "c")]
+            .iter()
+            .map(|(i, name)| format!("/nix/store/{:032}-{}\n", i, name))
+            .collect();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
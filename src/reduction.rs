@@ -4,6 +4,7 @@ use std::collections;
 use std::hash::Hasher;
 use std::{self, hash::Hash};
 
+use petgraph::prelude::NodeIndex;
 use petgraph::visit::{DfsPostOrder, EdgeFiltered, EdgeRef, IntoEdgeReferences};
 
 use crate::depgraph::*;
@@ -28,16 +29,131 @@ pub fn merge_transient_roots(mut di: DepInfos) -> DepInfos {
     let fake_root_idx = di.graph.add_node(DepNode {
         description: NodeDescription::Transient,
         size: 0,
+        link_path: None,
     });
-    di.graph.add_edge(di.root, fake_root_idx, ());
+    di.graph.add_edge(di.root, fake_root_idx, Edge::default());
     for idx in targets {
         let edx = di.graph.find_edge(di.root, idx).unwrap();
         di.graph.remove_edge(edx);
-        di.graph.add_edge(fake_root_idx, idx, ());
+        di.graph.add_edge(fake_root_idx, idx, Edge::default());
     }
     di
 }
 
+/// Removes in memory and temporary roots, and the paths which were only alive
+/// because of them.
+///
+/// Nodes which were already unreachable are kept.
+pub fn drop_transient_roots(mut di: DepInfos) -> DepInfos {
+    let mut before = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    let mut dfs = di.dfs();
+    while let Some(idx) = dfs.next(&di.graph) {
+        before.insert(idx.index());
+    }
+    let mut after = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    {
+        let filtered = petgraph::visit::NodeFiltered::from_fn(&di.graph, |idx| {
+            !di.graph[idx].kind().is_transient()
+        });
+        let mut dfs = petgraph::visit::Dfs::new(&filtered, di.root);
+        while let Some(idx) = dfs.next(&filtered) {
+            after.insert(idx.index());
+        }
+    }
+
+    let mut new_graph = DepGraph::new();
+    let mut new_ids = vec![None; di.graph.node_count()];
+    for idx in di.graph.node_indices() {
+        // transient roots are reachable, so they are not kept
+        if after.contains(idx.index()) || !before.contains(idx.index()) {
+            let mut new_w = DepNode::dummy();
+            std::mem::swap(&mut di.graph[idx], &mut new_w);
+            new_ids[idx.index()] = Some(new_graph.add_node(new_w));
+        }
+    }
+    for edge in di.graph.raw_edges() {
+        if let (Some(from), Some(to)) = (
+            new_ids[edge.source().index()],
+            new_ids[edge.target().index()],
+        ) {
+            new_graph.add_edge(from, to, edge.weight);
+        }
+    }
+
+    di.graph = new_graph;
+    di.root = new_ids[di.root.index()].unwrap();
+    di.metadata.size[di.metadata.dedup] = enum_map::enum_map! { _ => None };
+    di.record_metadata();
+    di
+}
+
+/// Merges all store paths of the same package (as returned by `DepNode::pname`) and
+/// output into one node, whatever their version, with the size of the sum and the
+/// description of an arbitrary member.
+///
+/// Edges are redirected to the merged nodes, without creating self loops, so the
+/// merged node is a root if any of its members was. Note that the result may
+/// contain cycles.
+///
+/// Requires that all nodes are reachable from the root, otherwise unreachable
+/// nodes may become reachable through their merged counterpart.
+/// `assert_eq!(di.metadata.reachable, Reachability::Connected);`
+pub fn merge_by_name(di: DepInfos) -> DepInfos {
+    merge_by_name_remapping(di, |_, _| ())
+}
+
+/// Like `merge_by_name`, calling `remap(old, new)` for each node of the old graph
+/// with its index in the new one, for example to update `Annotations`.
+pub fn merge_by_name_remapping(
+    mut di: DepInfos,
+    mut remap: impl FnMut(NodeIndex, NodeIndex),
+) -> DepInfos {
+    assert_eq!(di.metadata.reachable, Reachability::Connected);
+    let mut new_graph = DepGraph::with_capacity(di.graph.node_count(), di.graph.edge_count());
+    let mut new_ids = Vec::with_capacity(di.graph.node_count());
+    // (pname, output) => new node index
+    let mut by_name: collections::BTreeMap<(Vec<u8>, Vec<u8>), NodeIndex> =
+        collections::BTreeMap::new();
+    for idx in di.graph.node_indices() {
+        let node = &di.graph[idx];
+        let name = match node.pname() {
+            Some(pname) if idx != di.root && node.kind() == NodeKind::Path => Some((
+                pname.to_vec(),
+                node.output_name().unwrap_or_default().to_vec(),
+            )),
+            _ => None,
+        };
+        let existing = name.as_ref().and_then(|name| by_name.get(name).copied());
+        let new_node = match existing {
+            Some(new_node) => {
+                new_graph[new_node].size += di.graph[idx].size;
+                new_node
+            }
+            None => {
+                let mut w = DepNode::dummy();
+                std::mem::swap(&mut w, &mut di.graph[idx]);
+                let new_node = new_graph.add_node(w);
+                if let Some(name) = name {
+                    by_name.insert(name, new_node);
+                }
+                new_node
+            }
+        };
+        remap(idx, new_node);
+        new_ids.push(new_node);
+    }
+    for edge in di.graph.raw_edges() {
+        let from = new_ids[edge.source().index()];
+        let to = new_ids[edge.target().index()];
+        if from != to {
+            merge_edge(&mut new_graph, from, to, edge.weight);
+        }
+    }
+    di.root = new_ids[di.root.index()];
+    di.graph = new_graph;
+    di
+}
+
 /// Transitive reduction
 ///
 /// Handles cycles by removing back edges first, then doing tred on the resulting dag, and then
@@ -85,16 +201,16 @@ pub fn transitive_reduction(mut di: DepInfos) -> DepInfos {
     // ... the back edges and edges to roots
     for e in di.graph.edge_references() {
         if is_back_edge(e) || e.source() == di.root {
-            new.add_edge(e.source(), e.target(), ());
+            new.add_edge(e.source(), e.target(), *e.weight());
         }
     }
     // ... and the edges of tred
     for e in tred.edge_references() {
-        new.add_edge(
-            toposort[e.source() as usize],
-            toposort[e.target() as usize],
-            (),
-        );
+        let from = toposort[e.source() as usize];
+        let to = toposort[e.target() as usize];
+        // tred only keeps edges of the original graph
+        let weight = di.graph.find_edge(from, to).map(|e| di.graph[e]);
+        new.add_edge(from, to, weight.unwrap_or_default());
     }
     std::mem::swap(&mut di.graph, &mut new);
     di
@@ -215,7 +331,7 @@ pub fn condense(mut di: DepInfos) -> DepInfos {
             // keep the graph acyclic
             continue;
         }
-        new_graph.update_edge(from, to, ());
+        merge_edge(&mut new_graph, from, to, edge.weight);
     }
 
     di.graph = new_graph;
@@ -243,7 +359,7 @@ pub fn keep_reachable(mut di: DepInfos) -> DepInfos {
         if let (Some(&newfrom), Some(&newto)) =
             (new_ids.get(&edge.source()), new_ids.get(&edge.target()))
         {
-            new_graph.add_edge(newfrom, newto, ());
+            new_graph.add_edge(newfrom, newto, edge.weight);
         }
     }
 
@@ -253,6 +369,189 @@ pub fn keep_reachable(mut di: DepInfos) -> DepInfos {
     di
 }
 
+/// Restricts the graph to the closure of `keep`: only they remain as roots, and the
+/// nodes which are not reachable from them are dropped. Nodes of `keep` which were
+/// not roots become roots.
+///
+/// The sizes in metadata become the sizes of this closure.
+pub fn restrict_to_roots(mut di: DepInfos, keep: &[NodeIndex]) -> DepInfos {
+    let root = di.root;
+    di.graph.retain_edges(|g, e| {
+        let (from, to) = g.edge_endpoints(e).unwrap();
+        from != root || keep.contains(&to)
+    });
+    for &idx in keep {
+        di.graph.update_edge(root, idx, Edge::default());
+    }
+    let mut di = keep_reachable(di);
+    di.metadata.size = enum_map::enum_map! { _ => enum_map::enum_map!{ _ => None }};
+    di.record_metadata();
+    di
+}
+
+/// Restricts the graph to what deleting all the gc roots except `keep` would free:
+/// the nodes reachable from the other roots but not from `keep`. Dead paths are
+/// dropped too, even though the garbage collector would free them.
+///
+/// The sizes in metadata become the sizes of what remains.
+pub fn reclaimable(mut di: DepInfos, keep: &[NodeIndex]) -> DepInfos {
+    let mut dfs = petgraph::visit::Dfs::empty(&di.graph);
+    for &idx in keep {
+        dfs.move_to(idx);
+        while dfs.next(&di.graph).is_some() {}
+    }
+    let mut kept = dfs.discovered;
+    // the dummy root is not really reachable from `keep`
+    kept.set(di.root.index(), false);
+    di.remove_nodes(&kept);
+    let mut di = keep_reachable(di);
+    di.metadata.size = enum_map::enum_map! { _ => enum_map::enum_map!{ _ => None }};
+    di.record_metadata();
+    di
+}
+
+/// What `exclude_folding` does with the size of removed nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FoldPolicy {
+    /// the size is dropped: the sizes in metadata become the sizes of what remains
+    Drop,
+    /// the size is divided evenly among the parents of the node, the remainder
+    /// going to the first one. Parents which are removed too pass it on to their
+    /// own parents.
+    ToParents,
+    /// the size is added to a single kept ancestor, the nearest one, or the one
+    /// of smallest index among the nearest ones
+    ToNearestKept,
+}
+
+/// Removes the nodes which are not roots and for which `exclude` returns `true`.
+/// Their parents are connected to their children instead, so the nodes which were
+/// reachable through them stay reachable.
+///
+/// Unlike `keep`, the size of removed nodes is dropped, not merged upstream: the
+/// sizes in metadata become the sizes of what remains.
+pub fn exclude<T: Fn(&DepNode) -> bool>(di: DepInfos, exclude: T) -> DepInfos {
+    exclude_folding(di, exclude, FoldPolicy::Drop)
+}
+
+/// Returns, at the index of each node in `removed`, its nearest ancestor not in
+/// `removed`, the one of smallest index among the nearest ones. Other nodes, and
+/// removed nodes without kept ancestor, are mapped to `None`.
+///
+/// This is one breadth first search from all the kept nodes at once, through
+/// removed nodes only.
+fn nearest_kept(graph: &DepGraph, removed: &fixedbitset::FixedBitSet) -> Vec<Option<NodeIndex>> {
+    let mut nearest = vec![None; graph.node_count()];
+    // nodes of the levels already done, whose nearest kept ancestor is final
+    let mut settled = fixedbitset::FixedBitSet::with_capacity(graph.node_count());
+    let mut level: Vec<NodeIndex> = graph
+        .node_indices()
+        .filter(|idx| !removed.contains(idx.index()))
+        .collect();
+    while !level.is_empty() {
+        let mut next = Vec::new();
+        for &idx in &level {
+            let ancestor = if removed.contains(idx.index()) {
+                nearest[idx.index()]
+            } else {
+                Some(idx)
+            };
+            for child in graph.neighbors(idx) {
+                if !removed.contains(child.index()) || settled.contains(child.index()) {
+                    continue;
+                }
+                match nearest[child.index()] {
+                    None => {
+                        nearest[child.index()] = ancestor;
+                        next.push(child);
+                    }
+                    Some(found) => nearest[child.index()] = ancestor.min(Some(found)),
+                }
+            }
+        }
+        settled.extend(next.iter().map(|idx| idx.index()));
+        level = next;
+    }
+    nearest
+}
+
+/// `exclude`, with the size of removed nodes handled according to `policy`.
+///
+/// Unless `policy` is `FoldPolicy::Drop`, the total size is preserved, except for
+/// removed nodes with no kept ancestor, which are not reachable from the root.
+pub fn exclude_folding<T: Fn(&DepNode) -> bool>(
+    mut di: DepInfos,
+    exclude: T,
+    policy: FoldPolicy,
+) -> DepInfos {
+    let mut is_root = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    for idx in di.roots() {
+        is_root.insert(idx.index());
+    }
+    is_root.insert(di.root.index());
+    let excluded: Vec<NodeIndex> = di
+        .graph
+        .node_indices()
+        .filter(|idx| !is_root.contains(idx.index()) && exclude(&di.graph[*idx]))
+        .collect();
+    if excluded.is_empty() {
+        return di;
+    }
+    let mut removed = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    for &idx in &excluded {
+        removed.insert(idx.index());
+    }
+    if policy == FoldPolicy::ToNearestKept {
+        // before the graph is modified
+        let nearest = nearest_kept(&di.graph, &removed);
+        for &idx in &excluded {
+            if let Some(ancestor) = nearest[idx.index()] {
+                di.graph[ancestor].size += di.graph[idx].size;
+            }
+        }
+    }
+    // bypass excluded nodes one after the other, so that chains of excluded nodes
+    // are bypassed as a whole
+    for &idx in &excluded {
+        let mut parents: Vec<_> = di
+            .graph
+            .neighbors_directed(idx, petgraph::Direction::Incoming)
+            .collect();
+        let children: Vec<_> = di.graph.neighbors(idx).collect();
+        if policy == FoldPolicy::ToParents {
+            parents.sort();
+            parents.dedup();
+            parents.retain(|&parent| parent != idx);
+            if let Some(&first) = parents.first() {
+                let size = di.graph[idx].size;
+                let n = parents.len() as u64;
+                for &parent in &parents {
+                    di.graph[parent].size += size / n;
+                }
+                di.graph[first].size += size % n;
+            }
+        }
+        for &parent in &parents {
+            for &child in &children {
+                if parent != child && parent != idx && child != idx {
+                    merge_edge(&mut di.graph, parent, child, Edge::default());
+                }
+            }
+        }
+        while let Some(edge) = di
+            .graph
+            .first_edge(idx, petgraph::Direction::Outgoing)
+            .or_else(|| di.graph.first_edge(idx, petgraph::Direction::Incoming))
+        {
+            di.graph.remove_edge(edge);
+        }
+    }
+    di.remove_nodes(&removed);
+    di.metadata.size = enum_map::enum_map! { _ => enum_map::enum_map!{ _ => None }};
+    di.record_metadata();
+    di
+}
+
 /// Creates a new graph retaining only nodes whose weight return
 /// `true` when passed to `filter`. The nodes which are dropped are
 /// merged into an arbitrary parent (ie. the name is dropped, but edges and size
@@ -263,7 +562,27 @@ pub fn keep_reachable(mut di: DepInfos) -> DepInfos {
 ///
 /// Requires that all nodes are reachable from the root.
 /// `assert_eq!(di.metadata.reachable, Reachability::Connected);`
-pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
+pub fn keep<T: Fn(&DepNode) -> bool>(di: DepInfos, filter: T) -> DepInfos {
+    keep_remapping(di, filter, |_, _| ())
+}
+
+/// Like `keep`, calling `remap(old, new)` for each kept node with its index in the
+/// new graph. Dropped nodes are not reported, even when their size goes to a kept
+/// node.
+pub fn keep_remapping<T: Fn(&DepNode) -> bool>(
+    di: DepInfos,
+    filter: T,
+    remap: impl FnMut(NodeIndex, NodeIndex),
+) -> DepInfos {
+    keep_nodes(di, |_, d| filter(d), remap)
+}
+
+/// Like `keep_remapping`, with a `filter` which is also given the index of nodes.
+fn keep_nodes<T: Fn(NodeIndex, &DepNode) -> bool>(
+    mut di: DepInfos,
+    filter: T,
+    mut remap: impl FnMut(NodeIndex, NodeIndex),
+) -> DepInfos {
     assert_eq!(di.metadata.reachable, Reachability::Connected);
     let mut new_graph = DepGraph::new();
     // ids of nodes put in new_graph
@@ -274,10 +593,12 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
 
     // loop over nodes to see which we keep
     for idx in di.graph.node_indices() {
-        if idx == di.root || filter(&di.graph[idx]) {
+        if idx == di.root || filter(idx, &di.graph[idx]) {
             let mut new_w = DepNode::dummy();
             std::mem::swap(&mut di.graph[idx], &mut new_w);
-            new_ids.insert(idx, new_graph.add_node(new_w));
+            let new = new_graph.add_node(new_w);
+            remap(idx, new);
+            new_ids.insert(idx, new);
         }
     }
     // store the weight of remaining roots
@@ -335,7 +656,9 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
                             }
                             None => old_id.unwrap_or_else(|| new_ids[&old]),
                         };
-                        new_graph.add_edge(new, new2, ());
+                        // only a direct edge keeps its weight
+                        let weight = di.graph.find_edge(old, idx).map(|e| di.graph[e]);
+                        new_graph.add_edge(new, new2, weight.unwrap_or_default());
                     } else {
                         // this child is not kept
                         // absorb its size upstream
@@ -349,6 +672,7 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
                 }
             }
             if let Some(id) = old_id {
+                remap(old, id);
                 new_ids.insert(old, id);
             };
         }
@@ -358,7 +682,7 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
     // we add edges to kept roots
     for id in di.roots() {
         if let Some(&nid) = new_ids.get(&id) {
-            new_graph.add_edge(new_root, nid, ());
+            new_graph.add_edge(new_root, nid, Edge::default());
         }
     }
     // to keep the size unchanged, we create a dummy root with the remaining size
@@ -367,9 +691,10 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
         let fake_root = DepNode {
             description: NodeDescription::FilteredOut,
             size: remaining_size,
+            link_path: None,
         };
         let id = new_graph.add_node(fake_root);
-        new_graph.add_edge(new_root, id, ());
+        new_graph.add_edge(new_root, id, Edge::default());
     }
 
     di.root = new_root;
@@ -378,6 +703,224 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
     di
 }
 
+/// Keeps the `n` largest nodes which are not roots, as well as all roots,
+/// and merges all the other nodes into a single `{others}` node, whose size is
+/// the sum of their sizes.
+///
+/// `{others}` inherits the edges of the nodes it replaces, so it is reachable from
+/// their parents and kept nodes stay reachable. This can create cycles through
+/// `{others}`.
+///
+/// Requires that all nodes are reachable from the root.
+/// `assert_eq!(di.metadata.reachable, Reachability::Connected);`
+pub fn keep_top(mut di: DepInfos, n: usize) -> DepInfos {
+    assert_eq!(di.metadata.reachable, Reachability::Connected);
+    let mut kept = vec![false; di.graph.node_count()];
+    kept[di.root.index()] = true;
+    for idx in di.roots() {
+        kept[idx.index()] = true;
+    }
+    let mut candidates: Vec<_> = di
+        .graph
+        .node_indices()
+        .filter(|idx| !kept[idx.index()])
+        .collect();
+    candidates.sort_by_key(|&idx| std::cmp::Reverse(di.graph[idx].size));
+    for idx in candidates.into_iter().take(n) {
+        kept[idx.index()] = true;
+    }
+
+    let mut new_graph = DepGraph::new();
+    let mut new_ids = vec![None; di.graph.node_count()];
+    let mut others_size = 0;
+    for idx in di.graph.node_indices() {
+        if kept[idx.index()] {
+            let mut new_w = DepNode::dummy();
+            std::mem::swap(&mut di.graph[idx], &mut new_w);
+            new_ids[idx.index()] = Some(new_graph.add_node(new_w));
+        } else {
+            others_size += di.graph[idx].size;
+        }
+    }
+    let others = if kept.iter().all(|&k| k) {
+        None
+    } else {
+        Some(new_graph.add_node(DepNode {
+            description: NodeDescription::Others,
+            size: others_size,
+            link_path: None,
+        }))
+    };
+    for edge in di.graph.raw_edges() {
+        let from = new_ids[edge.source().index()].or(others).unwrap();
+        let to = new_ids[edge.target().index()].or(others).unwrap();
+        if from != to {
+            merge_edge(&mut new_graph, from, to, edge.weight);
+        }
+    }
+
+    di.root = new_ids[di.root.index()].unwrap();
+    di.graph = new_graph;
+    di
+}
+
+/// Keeps only nodes of size at least `min_size`, and all roots, see `keep`.
+///
+/// Dropped nodes are merged into a kept parent, so that reachability and sizes are
+/// preserved. Roots are never dropped, even below the threshold.
+pub fn retain_above(di: DepInfos, min_size: u64) -> DepInfos {
+    retain_above_remapping(di, min_size, |_, _| ())
+}
+
+/// Like `retain_above`, calling `remap` like `keep_remapping`.
+pub fn retain_above_remapping(
+    di: DepInfos,
+    min_size: u64,
+    remap: impl FnMut(NodeIndex, NodeIndex),
+) -> DepInfos {
+    let mut is_root = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    is_root.extend(di.roots().map(|idx| idx.index()));
+    keep_nodes(
+        di,
+        |idx, d| d.size >= min_size || is_root.contains(idx.index()),
+        remap,
+    )
+}
+
+/// Removes the edges to nodes whose closure size is less than `min_size`, except
+/// from the root, and then the nodes which are no longer reachable. The size of a
+/// removed node goes to its nearest kept ancestor, see `FoldPolicy::ToNearestKept`,
+/// so the reachable size is preserved.
+///
+/// Unlike `retain_above`, a small node leading to large ones is kept.
+/// `closure_sizes` are those of `di`, so this is meant for graphs already reduced,
+/// for example by `condense`.
+pub fn prune_light_subtrees(
+    mut di: DepInfos,
+    min_size: u64,
+    closure_sizes: &ClosureSizes,
+) -> DepInfos {
+    let reachable = |di: &DepInfos| {
+        let mut dfs = di.dfs();
+        while dfs.next(&di.graph).is_some() {}
+        dfs.discovered
+    };
+    let before = reachable(&di);
+    let original = di.graph.clone();
+    let root = di.root;
+    di.graph.retain_edges(|g, e| {
+        let (source, target) = g.edge_endpoints(e).unwrap();
+        source == root || closure_sizes.get(target) >= min_size
+    });
+    let mut pruned = before;
+    pruned.difference_with(&reachable(&di));
+    if pruned.count_ones(..) == 0 {
+        return di;
+    }
+    let nearest = nearest_kept(&original, &pruned);
+    for idx in pruned.ones().map(NodeIndex::new) {
+        if let Some(ancestor) = nearest[idx.index()] {
+            di.graph[ancestor].size += original[idx].size;
+        }
+    }
+    di.remove_nodes(&pruned);
+    di.metadata.size = enum_map::enum_map! { _ => enum_map::enum_map!{ _ => None }};
+    di.record_metadata();
+    di
+}
+
+/// Removes the leaves (nodes without children) smaller than `min_size`, and then
+/// the nodes which become leaves and are still smaller than `min_size`, and so on.
+/// The size of a removed leaf is divided among its parents, like with
+/// `FoldPolicy::ToParents`, so that the total size is preserved.
+///
+/// Roots, and leaves without parents, are never removed.
+pub fn fold_small_leaves(mut di: DepInfos, min_size: u64) -> DepInfos {
+    let mut is_root = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    for idx in di.roots() {
+        is_root.insert(idx.index());
+    }
+    is_root.insert(di.root.index());
+    let foldable = |di: &DepInfos, idx: NodeIndex| {
+        !is_root.contains(idx.index())
+            && di.graph[idx].size < min_size
+            && di.graph.neighbors(idx).next().is_none()
+            && di
+                .graph
+                .neighbors_directed(idx, petgraph::Direction::Incoming)
+                .next()
+                .is_some()
+    };
+    let mut queue: collections::VecDeque<NodeIndex> = di
+        .graph
+        .node_indices()
+        .filter(|&idx| foldable(&di, idx))
+        .collect();
+    let mut removed = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    while let Some(idx) = queue.pop_front() {
+        let mut parents: Vec<_> = di
+            .graph
+            .neighbors_directed(idx, petgraph::Direction::Incoming)
+            .collect();
+        parents.sort();
+        parents.dedup();
+        let size = di.graph[idx].size;
+        let n = parents.len() as u64;
+        for &parent in &parents {
+            di.graph[parent].size += size / n;
+        }
+        di.graph[parents[0]].size += size % n;
+        while let Some(edge) = di.graph.first_edge(idx, petgraph::Direction::Incoming) {
+            di.graph.remove_edge(edge);
+        }
+        removed.insert(idx.index());
+        for parent in parents {
+            if foldable(&di, parent) {
+                queue.push_back(parent);
+            }
+        }
+    }
+    if removed.count_ones(..) == 0 {
+        return di;
+    }
+    di.remove_nodes(&removed);
+    di.metadata.size = enum_map::enum_map! { _ => enum_map::enum_map!{ _ => None }};
+    di.record_metadata();
+    di
+}
+
+/// Gathers all the live `Shared` nodes in one `Dedup` root, whose size is the sum
+/// of theirs, so that the space saved by store optimisation is not attributed to
+/// the store paths sharing it.
+///
+/// `Shared` nodes which are only kept alive by dead paths are left as is, so that
+/// what the garbage collector would free does not change.
+pub fn merge_shared(mut di: DepInfos) -> DepInfos {
+    let mut removed = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    let mut size = 0;
+    let mut dfs = di.dfs();
+    while let Some(idx) = dfs.next(&di.graph) {
+        if di.graph[idx].kind() == NodeKind::Shared {
+            removed.insert(idx.index());
+            size += di.graph[idx].size;
+        }
+    }
+    if removed.count_ones(..) == 0 {
+        return di;
+    }
+    let dedup = di.graph.add_node(DepNode {
+        description: NodeDescription::Dedup,
+        size,
+        link_path: None,
+    });
+    di.graph.add_edge(di.root, dedup, Edge::default());
+    removed.grow(di.graph.node_count());
+    di.remove_nodes(&removed);
+    di.metadata.size = enum_map::enum_map! { _ => enum_map::enum_map!{ _ => None }};
+    di.record_metadata();
+    di
+}
+
 #[cfg(test)]
 mod tests {
     use crate::depgraph::*;
@@ -459,13 +1002,17 @@ mod tests {
             } else {
                 3 + 2 * (i as u64)
             };
-            let w = DepNode { description, size };
+            let w = DepNode {
+                description,
+                size,
+                link_path: None,
+            };
             g.add_node(w);
         }
         for i in 0..size {
             for j in (i + 1)..size {
                 if choices[wc.sample(&mut rng)] && !g[NodeIndex::from(j)].kind().is_gc_root() {
-                    g.add_edge(NodeIndex::from(i), NodeIndex::from(j), ());
+                    g.add_edge(NodeIndex::from(i), NodeIndex::from(j), Edge::default());
                 }
             }
         }
@@ -478,6 +1025,7 @@ mod tests {
             DepNode {
                 description: Path("root".into()),
                 size: 42,
+                link_path: None,
             }
         } else {
             DepNode::dummy()
@@ -501,7 +1049,7 @@ mod tests {
             }
             let make_reachable = connected || g[idx].kind().is_gc_root() || rng.gen();
             if root != idx && make_reachable {
-                g.add_edge(root, idx, ());
+                g.add_edge(root, idx, Edge::default());
             }
             if !make_reachable {
                 metadata.reachable = Reachability::Disconnected;
@@ -511,12 +1059,13 @@ mod tests {
             graph: g,
             root,
             metadata,
+            store_root: None,
         };
         // there may be edges from root to root
         for i in di.roots().collect::<Vec<_>>() {
             for j in di.roots().collect::<Vec<_>>() {
                 if j > i && choices[wc.sample(&mut rng)] {
-                    di.graph.add_edge(i, j, ());
+                    di.graph.add_edge(i, j, Edge::default());
                 }
             }
         }
@@ -551,6 +1100,8 @@ mod tests {
             let di = generate_random(250, 10, false);
             println!("testing merge_transient_roots");
             check_invariants(merge_transient_roots, di.clone(), false);
+            println!("testing merge_cycles");
+            check_invariants(crate::transform::merge_cycles, di.clone(), true);
             println!("testing condense");
             check_invariants(condense, di.clone(), true);
             println!("testing keep_reachable");
@@ -558,8 +1109,12 @@ mod tests {
             println!("testing keep none");
             let trimmed = keep_reachable(di);
             check_invariants(|x| keep(x, |_| false), trimmed.clone(), false);
+            println!("testing merge_by_name");
+            check_invariants(merge_by_name, trimmed.clone(), true);
             println!("testing keep all");
             check_invariants(|x| keep(x, |_| true), trimmed.clone(), true);
+            println!("testing retain_above");
+            check_invariants(|x| retain_above(x, 1 << 20), trimmed.clone(), false);
             println!("testing tred");
             check_invariants(transitive_reduction, trimmed, true);
         }
@@ -648,6 +1203,47 @@ mod tests {
         }
     }
     #[test]
+    fn check_keep_reachable_shared_size() {
+        // junk is unreachable, so keep_reachable renumbers the other nodes
+        let mut di = DepInfos::from_spec(
+            &[("junk", 1), ("a", 2), ("b", 4), ("shared", 8)],
+            &[(0, 3), (1, 3), (2, 3)],
+            &[1, 2],
+        );
+        for (from, size) in [(0, 1), (1, 3), (2, 5)] {
+            let e = di
+                .graph
+                .find_edge(NodeIndex::new(from), NodeIndex::new(3))
+                .unwrap();
+            di.graph[e].shared_size = Some(size);
+        }
+        let shared_size = |di: &DepInfos, from: &[u8]| {
+            let find = |name: &[u8]| {
+                di.graph
+                    .node_indices()
+                    .find(|&idx| &*di.graph[idx].name() == name)
+                    .unwrap()
+            };
+            let e = di.graph.find_edge(find(from), find(b"shared")).unwrap();
+            di.graph[e].shared_size
+        };
+        let new = keep_reachable(di);
+        assert_eq!(new.graph.node_count(), 4);
+        assert_eq!(shared_size(&new, b"a"), Some(3));
+        assert_eq!(shared_size(&new, b"b"), Some(5));
+        // merging a and b merges their edges, and adds up the weights
+        let mut merged = new.clone();
+        let b = merged
+            .graph
+            .node_indices()
+            .find(|&idx| &*merged.graph[idx].name() == b"b")
+            .unwrap();
+        merged.graph[b].description =
+            NodeDescription::Path(b"/nix/store/00000000000000000000000000000000-a".to_vec());
+        let merged = merge_by_name(merged);
+        assert_eq!(shared_size(&merged, b"a"), Some(8));
+    }
+    #[test]
     fn check_transitive_reduction() {
         let mut rng = rand::thread_rng();
         for _ in 0..40 {
@@ -656,8 +1252,11 @@ mod tests {
             for _ in 1..20 {
                 let from = rng.gen_range(1..old.graph.node_count());
                 let to = rng.gen_range(1..old.graph.node_count());
-                old.graph
-                    .add_edge(NodeIndex::from(from as u32), NodeIndex::from(to as u32), ());
+                old.graph.add_edge(
+                    NodeIndex::from(from as u32),
+                    NodeIndex::from(to as u32),
+                    Edge::default(),
+                );
                 old.check_metadata();
             }
 
@@ -692,6 +1291,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn transitive_reduction_edge_count() {
+        // a -> b -> c and a -> c, plus a cycle d -> e -> d below c
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 1), ("c", 1), ("d", 1), ("e", 1)],
+            &[(0, 1), (1, 2), (0, 2), (2, 3), (3, 4), (4, 3), (2, 4)],
+            &[0],
+        );
+        assert_eq!(di.graph.edge_count(), 7 + 1);
+        let new = transitive_reduction(di);
+        let mut edges: Vec<_> = new
+            .graph
+            .raw_edges()
+            .iter()
+            .filter(|e| e.source() != new.root)
+            .map(|e| (e.source().index(), e.target().index()))
+            .collect();
+        edges.sort();
+        // a -> c is removed, and one of c -> d and c -> e
+        assert_eq!(edges.len(), 5);
+        assert!(!edges.contains(&(0, 2)));
+    }
+
     #[test]
     fn check_condense() {
         // 62 so that each node is uniquely determined by its size, and
@@ -924,4 +1546,536 @@ mod tests {
             }
         }
     }
+    #[test]
+    fn check_retain_above() {
+        // a is a small root with a big child c, b a small root with a small child d
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 100), ("d", 3), ("e", 200)],
+            &[(0, 2), (1, 3), (2, 4)],
+            &[0, 1],
+        );
+        let di = keep_reachable(di);
+        let new = retain_above(di.clone(), 50);
+        new.check_metadata();
+        assert_eq!(new.reachable_size(), di.reachable_size());
+        let mut names: Vec<_> = new
+            .graph
+            .node_weights()
+            .map(|n| String::from_utf8_lossy(&n.name()).into_owned())
+            .collect();
+        names.sort();
+        // b is kept although it is small and has no large child, with the size of d
+        assert_eq!(names, vec!["a", "b", "c", "e", "{dummy}"]);
+        let kept_root = new.roots().find(|&idx| new.graph[idx].size == 1).unwrap();
+        assert_eq!(new.closure_size(kept_root), 301);
+        let tiny_root = new.roots().find(|&idx| new.graph[idx].size == 5).unwrap();
+        assert_eq!(new.graph.neighbors(tiny_root).count(), 0);
+
+        let mut in_place = di.clone();
+        in_place.retain_above(50);
+        assert_eq!(in_place.roots_name(), new.roots_name());
+        assert_eq!(in_place.graph.node_count(), new.graph.node_count());
+    }
+    #[test]
+    fn check_drop_transient_roots() {
+        // roots a and {memory:1234}; memory -> c -> d, a -> d; e is dead
+        let mut di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8), ("e", 16)],
+            &[(1, 2), (2, 3), (0, 3)],
+            &[0, 1],
+        );
+        di.graph[NodeIndex::new(1)].description =
+            NodeDescription::Memory(b"{memory:1234}".to_vec());
+        let new = drop_transient_roots(di);
+        new.check_metadata();
+        let mut names: Vec<_> = new
+            .graph
+            .node_indices()
+            .filter(|&idx| idx != new.root)
+            .map(|idx| String::from_utf8_lossy(&new.graph[idx].name()).into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "d", "e"]);
+        assert_eq!(new.reachable_size(), 1 + 8);
+        assert_eq!(new.graph.edge_count(), 2);
+    }
+
+    #[test]
+    fn check_exclude() {
+        // a -> a-doc -> b-doc -> c, a -> d, e-doc -> d; e-doc is a root
+        let di = DepInfos::from_spec(
+            &[
+                ("a", 1),
+                ("a-doc", 2),
+                ("b-doc", 4),
+                ("c", 8),
+                ("d", 16),
+                ("e-doc", 32),
+            ],
+            &[(0, 1), (1, 2), (2, 3), (0, 4), (5, 4)],
+            &[0, 5],
+        );
+        let re = regex::bytes::Regex::new("^(?:.*-doc)$").unwrap();
+        let new = exclude(di, |node| re.is_match(&node.name()));
+        new.check_metadata();
+        let name = |idx: NodeIndex| String::from_utf8_lossy(&new.graph[idx].name()).into_owned();
+        let names: collections::BTreeSet<_> = new
+            .graph
+            .node_indices()
+            .filter(|&idx| idx != new.root)
+            .map(name)
+            .collect();
+        assert_eq!(
+            names,
+            ["a", "c", "d", "e-doc"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        );
+        // c stays reachable from a
+        let mut edges: Vec<_> = new
+            .graph
+            .raw_edges()
+            .iter()
+            .filter(|e| e.source() != new.root)
+            .map(|e| (name(e.source()), name(e.target())))
+            .collect();
+        edges.sort();
+        let expected: Vec<_> = [("a", "c"), ("a", "d"), ("e-doc", "d")]
+            .iter()
+            .map(|&(a, b)| (a.to_string(), b.to_string()))
+            .collect();
+        assert_eq!(edges, expected);
+        assert_eq!(new.reachable_size(), 1 + 8 + 16 + 32);
+    }
+
+    #[test]
+    fn check_exclude_folding() {
+        // roots a and b; a -> x-doc, b -> x-doc, x-doc -> c, a -> y-doc -> z-doc
+        let di = DepInfos::from_spec(
+            &[
+                ("a", 1),
+                ("b", 2),
+                ("x-doc", 11),
+                ("c", 8),
+                ("y-doc", 16),
+                ("z-doc", 32),
+            ],
+            &[(0, 2), (1, 2), (2, 3), (0, 4), (4, 5)],
+            &[0, 1],
+        );
+        let total = di.reachable_size();
+        let re = regex::bytes::Regex::new("^(?:.*-doc)$").unwrap();
+        let sizes = |policy| {
+            let new = exclude_folding(di.clone(), |node| re.is_match(&node.name()), policy);
+            new.check_metadata();
+            let sizes: BTreeMap<_, _> = new
+                .graph
+                .node_indices()
+                .filter(|&idx| idx != new.root)
+                .map(|idx| (new.graph[idx].name().into_owned(), new.graph[idx].size))
+                .collect();
+            (new.reachable_size(), sizes)
+        };
+        let expected = |a, b| {
+            [(&b"a"[..], a), (b"b", b), (b"c", 8)]
+                .iter()
+                .map(|&(name, size)| (name.to_vec(), size))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert_eq!(sizes(FoldPolicy::Drop), (1 + 2 + 8, expected(1, 2)));
+        // x-doc is split between a and b, a gets the remainder
+        assert_eq!(
+            sizes(FoldPolicy::ToParents),
+            (total, expected(1 + 6 + 16 + 32, 2 + 5))
+        );
+        assert_eq!(
+            sizes(FoldPolicy::ToNearestKept),
+            (total, expected(1 + 11 + 16 + 32, 2))
+        );
+        // same as exclude
+        let new = exclude(di.clone(), |node| re.is_match(&node.name()));
+        assert_eq!(new.reachable_size(), 1 + 2 + 8);
+    }
+
+    #[test]
+    fn check_nearest_kept() {
+        // roots a and b are kept; b -> c, a -> d, {c, d} -> e -> g -> e, f is dead
+        let di = DepInfos::from_spec(
+            &[
+                ("a", 1),
+                ("b", 2),
+                ("c", 4),
+                ("d", 8),
+                ("e", 16),
+                ("f", 32),
+                ("g", 64),
+            ],
+            &[(1, 2), (0, 3), (2, 4), (3, 4), (4, 6), (6, 4), (5, 5)],
+            &[0, 1],
+        );
+        let mut removed = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+        removed.extend(2..7);
+        let nearest: Vec<_> = nearest_kept(&di.graph, &removed)
+            .into_iter()
+            .map(|found| found.map(|idx| idx.index()))
+            .collect();
+        // e is as near to a as to b, a has the smallest index
+        assert_eq!(
+            nearest,
+            vec![None, None, Some(1), Some(0), Some(0), None, Some(0), None]
+        );
+    }
+
+    #[test]
+    fn check_restrict_to_roots() {
+        // roots a and b; a -> c -> e, b -> d -> e; f is dead
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8), ("e", 16), ("f", 32)],
+            &[(0, 2), (2, 4), (1, 3), (3, 4)],
+            &[0, 1],
+        );
+        let names = |di: &DepInfos| {
+            let mut names: Vec<_> = di
+                .graph
+                .node_indices()
+                .filter(|&idx| idx != di.root)
+                .map(|idx| String::from_utf8_lossy(&di.graph[idx].name()).into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        let new = restrict_to_roots(di.clone(), &[NodeIndex::new(0)]);
+        new.check_metadata();
+        assert_eq!(names(&new), vec!["a", "c", "e"]);
+        assert_eq!(
+            new.roots_name(),
+            vec!["a".to_string()].into_iter().collect()
+        );
+        assert_eq!(new.reachable_size(), 1 + 4 + 16);
+        // d was not a root
+        let new = restrict_to_roots(di, &[NodeIndex::new(3)]);
+        new.check_metadata();
+        assert_eq!(names(&new), vec!["d", "e"]);
+        assert_eq!(
+            new.roots_name(),
+            vec!["d".to_string()].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn check_reclaimable() {
+        // roots a, b and c; a -> d -> f, b -> e -> f, c -> e; g is dead
+        let di = DepInfos::from_spec(
+            &[
+                ("a", 1),
+                ("b", 2),
+                ("c", 4),
+                ("d", 8),
+                ("e", 16),
+                ("f", 32),
+                ("g", 64),
+            ],
+            &[(0, 3), (3, 5), (1, 4), (4, 5), (2, 4)],
+            &[0, 1, 2],
+        );
+        let names = |di: &DepInfos| {
+            let mut names: Vec<_> = di
+                .graph
+                .node_indices()
+                .filter(|&idx| idx != di.root)
+                .map(|idx| String::from_utf8_lossy(&di.graph[idx].name()).into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        // f is shared with a, e with c
+        let new = reclaimable(di.clone(), &[NodeIndex::new(0), NodeIndex::new(2)]);
+        new.check_metadata();
+        assert_eq!(names(&new), vec!["b"]);
+        assert_eq!(
+            new.reachable_size(),
+            di.freed_by_deleting(&[NodeIndex::new(1)])
+        );
+        // b and c only share with each other
+        let new = reclaimable(di.clone(), &[NodeIndex::new(0)]);
+        new.check_metadata();
+        assert_eq!(names(&new), vec!["b", "c", "e"]);
+        assert_eq!(
+            new.roots_name(),
+            vec!["b".to_string(), "c".to_string()].into_iter().collect()
+        );
+        assert_eq!(new.reachable_size(), 2 + 4 + 16);
+        let new = reclaimable(di.clone(), &[]);
+        assert_eq!(new.reachable_size(), di.reachable_size());
+    }
+
+    #[test]
+    fn check_prune_light_subtrees() {
+        // root p -> heavy h -> {h2, s}, p -> light l -> l2, and l2 -> h2
+        let di = DepInfos::from_spec(
+            &[
+                ("p", 1),
+                ("h", 100),
+                ("h2", 50),
+                ("s", 2),
+                ("l", 4),
+                ("l2", 3),
+            ],
+            &[(0, 1), (1, 2), (1, 3), (0, 4), (4, 5), (5, 2)],
+            &[0],
+        );
+        let total = di.reachable_size();
+        let new = prune_light_subtrees(di.clone(), 10, &ClosureSizes::new(&di));
+        new.check_metadata();
+        assert_eq!(new.reachable_size(), total);
+        let mut sizes: Vec<_> = new
+            .graph
+            .node_indices()
+            .filter(|&idx| idx != new.root)
+            .map(|idx| (new.graph[idx].name().into_owned(), new.graph[idx].size))
+            .collect();
+        sizes.sort();
+        // the closure of l is heavy because of h2, s is folded into h
+        let expected: Vec<(Vec<u8>, u64)> = vec![
+            (b"h".to_vec(), 100 + 2),
+            (b"h2".to_vec(), 50),
+            (b"l".to_vec(), 4),
+            (b"l2".to_vec(), 3),
+            (b"p".to_vec(), 1),
+        ];
+        assert_eq!(sizes, expected);
+
+        // without h2 below l2, l and l2 are folded into p
+        let mut light = di.clone();
+        let edge = light
+            .graph
+            .find_edge(NodeIndex::new(5), NodeIndex::new(2))
+            .unwrap();
+        light.graph.remove_edge(edge);
+        let closure_sizes = ClosureSizes::new(&light);
+        let new = prune_light_subtrees(light, 10, &closure_sizes);
+        new.check_metadata();
+        assert_eq!(new.reachable_size(), total);
+        assert_eq!(new.graph.node_count(), 1 + 3);
+        let p = new.roots().next().unwrap();
+        assert_eq!(new.graph[p].size, 1 + 4 + 3);
+
+        let same = prune_light_subtrees(di.clone(), 1, &ClosureSizes::new(&di));
+        assert_eq!(same.graph.edge_count(), di.graph.edge_count());
+    }
+
+    #[test]
+    fn check_merge_shared() {
+        // roots a and b share files with c, d is dead and shares files with c too
+        let mut di = DepInfos::from_spec(
+            &[("a", 10), ("b", 20), ("c", 30), ("d", 40)],
+            &[(0, 2), (1, 2)],
+            &[0, 1],
+        );
+        let mut add_shared = |name: &[u8], size, owners: &[usize]| {
+            let idx = di.graph.add_node(DepNode {
+                description: NodeDescription::Shared(name.to_vec()),
+                size,
+                link_path: None,
+            });
+            for &owner in owners {
+                di.graph.add_edge(
+                    NodeIndex::new(owner),
+                    idx,
+                    Edge {
+                        shared_size: Some(size),
+                    },
+                );
+            }
+        };
+        add_shared(b"ab", 1, &[0, 1]);
+        add_shared(b"ac", 2, &[0, 2]);
+        add_shared(b"bc", 4, &[1, 2]);
+        add_shared(b"cd", 8, &[2, 3]);
+        add_shared(b"d", 16, &[3]);
+        let reachable = di.reachable_size();
+        let unreachable = di.unreachable_size();
+
+        let new = merge_shared(di);
+        new.check_metadata();
+        let kinds = |kind| {
+            new.graph
+                .node_indices()
+                .filter(|&idx| new.graph[idx].kind() == kind)
+                .collect::<Vec<_>>()
+        };
+        let dedup = kinds(NodeKind::Dedup);
+        assert_eq!(dedup.len(), 1);
+        assert_eq!(new.graph[dedup[0]].size, 1 + 2 + 4 + 8);
+        assert_eq!(&*new.graph[dedup[0]].name(), b"{dedup}");
+        assert!(new.roots().any(|idx| idx == dedup[0]));
+        assert_eq!(kinds(NodeKind::Shared).len(), 1);
+        assert_eq!(new.reachable_size(), reachable);
+        assert_eq!(new.unreachable_size(), unreachable);
+
+        // no-op without shared nodes
+        let di = DepInfos::from_spec(&[("a", 1)], &[], &[0]);
+        assert_eq!(merge_shared(di).graph.node_count(), 2);
+    }
+
+    #[test]
+    fn check_fold_small_leaves() {
+        // root a -> b -> {c, d}, a -> e -> {f, g}, f and g are also below root h;
+        // all but b are tiny
+        let di = DepInfos::from_spec(
+            &[
+                ("a", 1),
+                ("b", 100),
+                ("c", 1),
+                ("d", 2),
+                ("e", 3),
+                ("f", 4),
+                ("g", 5),
+                ("h", 1),
+            ],
+            &[
+                (0, 1),
+                (1, 2),
+                (1, 3),
+                (0, 4),
+                (4, 5),
+                (4, 6),
+                (7, 5),
+                (7, 6),
+            ],
+            &[0, 7],
+        );
+        let leaves = |di: &DepInfos| {
+            di.graph
+                .node_indices()
+                .filter(|&idx| di.graph.neighbors(idx).next().is_none())
+                .count()
+        };
+        let before = leaves(&di);
+        let total = di.reachable_size();
+        let new = fold_small_leaves(di, 10);
+        new.check_metadata();
+        assert_eq!(new.reachable_size(), total);
+        assert!(leaves(&new) < before);
+        let mut sizes: Vec<_> = new
+            .graph
+            .node_indices()
+            .filter(|&idx| idx != new.root)
+            .map(|idx| (new.graph[idx].name().into_owned(), new.graph[idx].size))
+            .collect();
+        sizes.sort();
+        // f and g are split between e and h, e becomes a leaf of size 3 + 2 + 3 = 8
+        // and is folded into a; b is large enough to stay
+        let expected: Vec<(Vec<u8>, u64)> = vec![
+            (b"a".to_vec(), 1 + 8),
+            (b"b".to_vec(), 100 + 1 + 2),
+            (b"h".to_vec(), 1 + 2 + 2),
+        ];
+        assert_eq!(sizes, expected);
+    }
+
+    #[test]
+    fn check_keep_top() {
+        // roots a and b, a -> c -> e -> f, b -> d -> f
+        let di = DepInfos::from_spec(
+            &[
+                ("a", 1),
+                ("b", 2),
+                ("c", 40),
+                ("d", 30),
+                ("e", 20),
+                ("f", 10),
+            ],
+            &[(0, 2), (2, 4), (4, 5), (1, 3), (3, 5)],
+            &[0, 1],
+        );
+        let size = di.reachable_size();
+        let top = keep_top(di.clone(), 2);
+        top.check_metadata();
+        assert_eq!(top.reachable_size(), size);
+        let mut names: Vec<_> = top
+            .graph
+            .node_indices()
+            .filter(|&idx| idx != top.root)
+            .map(|idx| String::from_utf8_lossy(&top.graph[idx].name()).into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "c", "d", "{others}"]);
+        let others = top
+            .graph
+            .node_indices()
+            .find(|&idx| top.graph[idx].kind() == NodeKind::Others)
+            .unwrap();
+        assert_eq!(top.graph[others].size, 30);
+        assert_eq!(
+            top.graph
+                .neighbors_directed(others, petgraph::Direction::Incoming)
+                .count(),
+            2
+        );
+        // nothing to merge
+        let all = keep_top(di, 10);
+        assert_eq!(all.graph.node_count(), 7);
+        assert_eq!(all.reachable_size(), size);
+    }
+
+    #[test]
+    fn check_merge_by_name() {
+        // two versions of the same path with the same name, each kept by a root
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("foo", 4), ("foo", 8), ("bar", 16)],
+            &[(0, 2), (1, 3), (2, 3), (3, 4)],
+            &[0, 1],
+        );
+        let new = merge_by_name(di.clone());
+        new.check_metadata();
+        assert_eq!(new.reachable_size(), di.reachable_size());
+        assert_eq!(new.graph.node_count(), 5);
+        assert_eq!(new.roots_name(), di.roots_name());
+        let foo = new
+            .graph
+            .node_indices()
+            .find(|&idx| &*new.graph[idx].name() == b"foo")
+            .unwrap();
+        assert_eq!(new.graph[foo].size, 12);
+        // no self loop, and edges from both roots
+        assert!(new.graph.find_edge(foo, foo).is_none());
+        assert_eq!(
+            new.graph
+                .neighbors_directed(foo, petgraph::Direction::Incoming)
+                .count(),
+            2
+        );
+        assert_eq!(new.graph.neighbors(foo).count(), 1);
+    }
+
+    #[test]
+    fn check_merge_by_name_versions() {
+        // two versions of glibc, and the dev output of one of them
+        let di = DepInfos::from_spec(
+            &[
+                ("a", 1),
+                ("b", 2),
+                ("glibc-2.37", 4),
+                ("glibc-2.38", 8),
+                ("glibc-2.38-dev", 16),
+            ],
+            &[(0, 2), (1, 3), (1, 4), (4, 3)],
+            &[0, 1],
+        );
+        let new = merge_by_name(di.clone());
+        new.check_metadata();
+        assert_eq!(new.reachable_size(), di.reachable_size());
+        assert_eq!(new.graph.node_count(), 5);
+        let glibc: Vec<_> = new
+            .graph
+            .node_indices()
+            .filter(|&idx| new.graph[idx].pname() == Some(&b"glibc"[..]))
+            .map(|idx| (new.graph[idx].output_name(), new.graph[idx].size))
+            .collect();
+        assert_eq!(glibc.len(), 2);
+        assert!(glibc.contains(&(None, 12)));
+        assert!(glibc.contains(&(Some(&b"dev"[..]), 16)));
+    }
 }
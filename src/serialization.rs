@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Support code for the `serde` feature, which makes `DepInfos` serializable.
+
+/// (De)serializes store paths, which are not necessarily valid UTF-8, as byte
+/// strings rather than sequences of integers.
+///
+/// Use as `#[serde(with = "crate::serialization::bytes")]`.
+pub mod bytes {
+    use serde::de::{Deserializer, Error, SeqAccess, Visitor};
+    use serde::Serializer;
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a byte string")
+        }
+
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        // formats without byte strings, like JSON, use strings or sequences
+        fn visit_str<E: Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            Ok(v.as_bytes().to_vec())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut res = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                res.push(byte);
+            }
+            Ok(res)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut di = DepInfos::fixture();
+        di.store_root = Some("/mnt".into());
+
+        let json = serde_json::to_string(&di).unwrap();
+        let new: DepInfos = serde_json::from_str(&json).unwrap();
+        new.check_metadata();
+        assert_eq!(new.root, di.root);
+        assert_eq!(new.store_root, di.store_root);
+        assert_eq!(new.metadata.size, di.metadata.size);
+        assert_eq!(
+            new.graph.node_weights().collect::<Vec<_>>(),
+            di.graph.node_weights().collect::<Vec<_>>()
+        );
+        let edges = |di: &DepInfos| {
+            di.graph
+                .raw_edges()
+                .iter()
+                .map(|e| (e.source(), e.target(), e.weight))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(edges(&new), edges(&di));
+        assert_eq!(serde_json::to_string(&new).unwrap(), json);
+        // JSON has no byte strings, so paths which are not valid UTF-8 are arrays
+        assert!(json.contains("[47,114,111,111,116,115,47,255,1]"));
+    }
+}
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use petgraph::prelude::NodeIndex;
+
+use crate::depgraph::*;
+
+/// Merges each strongly connected component of more than one node into a single node
+///
+/// The merged node has the size of the sum of the component and is described as
+/// `{cycle: name1, name2...}`. Edges from and to the component become deduplicated
+/// edges from and to the merged node, so a root in a cycle makes the merged node a root.
+/// If the root itself belongs to a cycle, the other members of the cycle are merged
+/// into it and it keeps its description.
+///
+/// noop if the graph is acyclic
+pub fn merge_cycles(mut di: DepInfos) -> DepInfos {
+    let components = petgraph::algo::kosaraju_scc(&di.graph);
+    if components.iter().all(|c| c.len() == 1) {
+        return di;
+    }
+    let mut new_graph = DepGraph::with_capacity(components.len(), di.graph.edge_count());
+    let mut new_ids = vec![NodeIndex::end(); di.graph.node_count()];
+    for component in components {
+        let size = component.iter().map(|&idx| di.graph[idx].size).sum();
+        let new_w = if component.len() == 1 || component.contains(&di.root) {
+            let representative = if component.len() == 1 {
+                component[0]
+            } else {
+                di.root
+            };
+            let mut w = DepNode::dummy();
+            std::mem::swap(&mut w, &mut di.graph[representative]);
+            w.size = size;
+            w
+        } else {
+            let mut names: Vec<_> = component
+                .iter()
+                .map(|&idx| di.graph[idx].name().into_owned())
+                .collect();
+            names.sort();
+            DepNode {
+                description: NodeDescription::Cycle(names.join(&b", "[..])),
+                size,
+                link_path: None,
+            }
+        };
+        let new_node = new_graph.add_node(new_w);
+        for idx in component {
+            new_ids[idx.index()] = new_node;
+        }
+    }
+    for edge in di.graph.raw_edges() {
+        let from = new_ids[edge.source().index()];
+        let to = new_ids[edge.target().index()];
+        if from != to {
+            merge_edge(&mut new_graph, from, to, edge.weight);
+        }
+    }
+    di.root = new_ids[di.root.index()];
+    di.graph = new_graph;
+    di
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transform::*;
+
+    #[test]
+    fn check_merge_cycles() {
+        // a -> b -> c -> a is a cycle, with a root and d a child of c
+        let mut di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2), ("c", 4), ("d", 8)],
+            &[(0, 1), (1, 2), (2, 0), (2, 3), (1, 0)],
+            &[0],
+        );
+        di.graph
+            .add_edge(di.root, NodeIndex::from(1), Edge::default());
+        let new = merge_cycles(di.clone());
+        new.check_metadata();
+        assert_eq!(new.reachable_size(), di.reachable_size());
+        assert_eq!(new.graph.node_count(), 3);
+        assert_eq!(new.graph.edge_count(), 2);
+        let cycle: Vec<_> = new.roots().collect();
+        assert_eq!(cycle.len(), 1);
+        let cycle = cycle[0];
+        assert_eq!(new.graph[cycle].kind(), NodeKind::Cycle);
+        assert_eq!(&*new.graph[cycle].name(), b"{cycle: a, b, c}");
+        assert_eq!(new.graph[cycle].size, 7);
+        let children: Vec<_> = new.graph.neighbors(cycle).collect();
+        assert_eq!(children.len(), 1);
+        assert_eq!(&*new.graph[children[0]].name(), b"d");
+        let _ = petgraph::algo::toposort(&new.graph, None).expect("the graph has a cycle");
+    }
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph;
+use crate::msg::format_size;
+use petgraph::prelude::NodeIndex;
+use std::io::{self, Write};
+
+/// Options for the tree output
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// nodes deeper than this are not shown. Roots have depth 0.
+    pub max_depth: Option<usize>,
+    /// print sizes as a number of bytes instead of human readable sizes
+    pub bytes: bool,
+}
+
+/// Outputs the graph as an indented tree, like `du` would, starting from each root.
+///
+/// Children are sorted by decreasing size. A node which was already expanded is
+/// marked with a `*` and not expanded again. A node met at `max_depth` is not
+/// expanded there, so it is expanded where it is met shallower, if anywhere.
+pub fn render<W: Write>(
+    dependencies: &depgraph::DepInfos,
+    options: &Options,
+    w: &mut W,
+) -> io::Result<()> {
+    let g = &dependencies.graph;
+    let sorted_children = |idx: NodeIndex| {
+        let mut children: Vec<_> = g.neighbors(idx).collect();
+        children.sort_by_key(|&child| std::cmp::Reverse(g[child].size));
+        children
+    };
+    let mut visited = vec![false; g.node_count()];
+    // children are pushed in reverse order, to be popped in order
+    let mut stack: Vec<_> = sorted_children(dependencies.root)
+        .into_iter()
+        .rev()
+        .map(|idx| (idx, 0))
+        .collect();
+    while let Some((idx, depth)) = stack.pop() {
+        for _ in 0..depth {
+            w.write_all(b"  ")?;
+        }
+        if visited[idx.index()] {
+            w.write_all(b"* ")?;
+        }
+        w.write_all(&g[idx].name())?;
+        if options.bytes {
+            writeln!(w, " ({} B)", g[idx].size)?;
+        } else {
+            writeln!(w, " ({})", format_size(g[idx].size))?;
+        }
+        if visited[idx.index()] || matches!(options.max_depth, Some(max) if depth >= max) {
+            continue;
+        }
+        visited[idx.index()] = true;
+        stack.extend(
+            sorted_children(idx)
+                .into_iter()
+                .rev()
+                .map(|child| (child, depth + 1)),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+
+    fn render(di: &DepInfos, max_depth: Option<usize>) -> String {
+        let mut out = Vec::new();
+        let options = crate::tree::Options {
+            max_depth,
+            ..Default::default()
+        };
+        crate::tree::render(di, &options, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn shared_and_depth() {
+        // roots a and b, which share d
+        let di = DepInfos::from_spec(
+            &[("a", 1), ("b", 2048), ("c", 4096), ("d", 8192), ("e", 16)],
+            &[(0, 2), (0, 3), (1, 3), (3, 4)],
+            &[0, 1],
+        );
+        assert_eq!(
+            render(&di, None),
+            "b (2.0 KiB)\n  d (8.0 KiB)\n    e (16 B)\na (1 B)\n  * d (8.0 KiB)\n  c (4.0 KiB)\n"
+        );
+        assert_eq!(render(&di, Some(0)), "b (2.0 KiB)\na (1 B)\n");
+    }
+
+    #[test]
+    fn expanded_where_shallower() {
+        // d is met at the maximum depth below b before being met as a root
+        let di = DepInfos::from_spec(
+            &[("b", 2048), ("d", 1024), ("e", 16)],
+            &[(0, 1), (1, 2)],
+            &[0, 1],
+        );
+        assert_eq!(
+            render(&di, Some(1)),
+            "b (2.0 KiB)\n  d (1.0 KiB)\nd (1.0 KiB)\n  e (16 B)\n"
+        );
+    }
+}
@@ -215,6 +215,8 @@ pub fn run_and_parse<'a>(args: &'a [&'a str], t: &'a TestDir) -> Output {
     let process = call_self(&t)
         .arg("--dump")
         .arg("/dev/stderr")
+        // human readable sizes are too imprecise to count dependencies
+        .arg("--bytes")
         .args(args)
         .expect_success();
     let out = String::from_utf8_lossy(&process.stdout);
@@ -583,3 +585,68 @@ dec_test!(
         assert_matches(&real, &expected);
     }
 );
+
+#[cfg(feature = "ssh-tests")]
+#[test]
+fn ssh_store() {
+    use std::os::unix::fs::PermissionsExt;
+    let t = TestDir::new("nix-du", "ssh_store");
+    let shell = which::which("sh").unwrap_or_else(|_| PathBuf::from("/bin/sh"));
+    // records its arguments and answers like nix path-info --json
+    t.create_file(
+        "bin/ssh",
+        format!(
+            r#"#!{}
+echo "$@" > "$(dirname "$0")/args"
+echo '[{{"path":"/nix/store/00000000000000000000000000000000-foo","narSize":1000000,"references":["/nix/store/11111111111111111111111111111111-bar"]}},
+{{"path":"/nix/store/11111111111111111111111111111111-bar","narSize":2000000,"references":[]}},
+{{"path":"/nix/store/22222222222222222222222222222222-baz","narSize":500000,"references":["/nix/store/11111111111111111111111111111111-bar"]}}]'
+"#,
+            shell.display()
+        ),
+    );
+    let ssh = t.path("bin/ssh");
+    fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755)).unwrap();
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![t.path("bin")];
+    paths.extend(std::env::split_paths(&path));
+
+    let out = t
+        .cmd()
+        .env("PATH", std::env::join_paths(paths).unwrap())
+        .args([
+            "--store",
+            "ssh://alice@builder",
+            "--format",
+            "csv",
+            "-s",
+            "0",
+        ])
+        .expect_success();
+    let args = fs::read_to_string(t.path("bin/args")).unwrap();
+    assert!(args.starts_with("alice@builder -- nix "), "{}", args);
+    assert!(args.contains("path-info --json --all"), "{}", args);
+    let csv = out.stdout_str();
+    assert!(
+        csv.contains(
+            "\"foo\",\"/nix/store/00000000000000000000000000000000-foo\",1000000,3000000,true"
+        ),
+        "{}",
+        csv
+    );
+    assert!(
+        csv.contains(
+            "\"bar\",\"/nix/store/11111111111111111111111111111111-bar\",2000000,2000000,false"
+        ),
+        "{}",
+        csv
+    );
+
+    // failures of ssh are reported
+    t.create_file("bin/ssh", format!("#!{}\nexit 255\n", shell.display()));
+    fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755)).unwrap();
+    t.cmd()
+        .env("PATH", std::env::join_paths([t.path("bin")]).unwrap())
+        .args(["--store", "ssh://builder"])
+        .expect_failure();
+}
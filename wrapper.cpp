@@ -6,6 +6,8 @@
 */
 #include <sstream>
 
+#include <cerrno>
+#include <filesystem>
 #include <iostream>
 #include <unordered_map>
 
@@ -15,6 +17,7 @@
 #include <util.hh> // restoreSignals
 #include <current-process.hh> // restoreProcessContext
 #include <shared.hh> // initNix
+#include <globals.hh> // nixVersion
 #include <local-store.hh>
 #include <remote-store.hh>
 
@@ -23,6 +26,7 @@
 #include <nix/config.h> // #define SYSTEM
 #include <nix/util.hh> // restoreSignals
 #include <nix/shared.hh> // initNix
+#include <nix/globals.hh> // nixVersion
 #include <nix/local-store.hh>
 #include <nix/remote-store.hh>
 
@@ -76,11 +80,19 @@ extern "C" {
   } Info;
   extern void register_node(void *graph, path_t *node);
   extern void register_edge(void *graph, unsigned from, unsigned to);
-  int populateGraph(void *graph, const char* rootPath) {
+  int populateGraph(void *graph, const char* rootPath, const char* storeUri, int reachableOnly, int withDerivations) {
     using namespace nix;
-    int retcode = handleExceptions("nix-du", [graph, rootPath]() {
+    auto populate = [graph, rootPath, storeUri, reachableOnly, withDerivations]() {
       initNix();
-      auto store = openStore();
+      const bool keepDerivations = settings.gcKeepDerivations || withDerivations;
+      auto store = [storeUri]() {
+        try {
+          return storeUri ? openStore(storeUri) : openStore();
+        } catch (BaseError & e) {
+          e.status = NIX_DU_CONNECTION_FAILED;
+          throw;
+        }
+      }();
 
       std::unordered_map<PATH, Info> node_to_id;
       // Registers the node if it was not already registered, and return its path info
@@ -94,6 +106,7 @@ extern "C" {
           };
           path_t entry;
           entry.is_root = 0;
+          entry.is_valid = 1;
           entry.size = info.data->narSize;
 #if NIXVER >= 204
           std::string path = store->storeDir + "/";
@@ -110,13 +123,33 @@ extern "C" {
         }
       };
 
+      // gc roots, only needed when reading the whole store
+      decltype(findroots(store)) roots;
+      if (!rootPath) {
+        roots = findroots(store);
+      }
+
       // queue for graph traversal
       std::vector<PATH> queue;
-      // initialise with either all nodes or just the root we want
-      if (!rootPath) {
+      // initialise with either all nodes, the closure of gc roots, or just the root we want
+      // all the valid paths, only filled when dumping all the store
+      std::set<PATH> validPaths;
+      if (!rootPath && reachableOnly) {
+        // dump only what gc roots keep alive
+#if NIXVER >= 203
+        for (auto &[storepath, links] : roots) {
+#else
+        for (auto root : roots) {
+          PATH storepath = root.second;
+#endif
+          if (store->isValidPath(storepath)) {
+            queue.push_back(storepath);
+          }
+        }
+      } else if (!rootPath) {
         // dump all the store
-        std::set<PATH> paths = store->queryAllValidPaths();
-        std::copy(paths.begin(), paths.end(), std::back_inserter(queue));
+        validPaths = store->queryAllValidPaths();
+        std::copy(validPaths.begin(), validPaths.end(), std::back_inserter(queue));
       } else {
         // dump only the recursive closure of rootPath
 #if NIXVER >= 204
@@ -146,10 +179,10 @@ extern "C" {
           }
         }
         // register edges from/to drv if this path has a derivation
-        if ((settings.gcKeepOutputs || settings.gcKeepDerivations) && (!DERIVER_IS_EMPTY(from.data->deriver)) && store->isValidPath(DERIVER_GET(from.data->deriver))) {
+        if ((settings.gcKeepOutputs || keepDerivations) && (!DERIVER_IS_EMPTY(from.data->deriver)) && store->isValidPath(DERIVER_GET(from.data->deriver))) {
           Info drv; bool drv_was_cached;
           std::tie(drv_was_cached, drv) = get_infos(DERIVER_GET(from.data->deriver));
-          if (settings.gcKeepDerivations) {
+          if (keepDerivations) {
             register_edge(graph, from.index, drv.index);
           }
           if (settings.gcKeepOutputs) {
@@ -161,20 +194,49 @@ extern "C" {
         }
       }
 
+#if NIXVER >= 204
+      // paths on disk which are not valid, like the outputs of interrupted builds
+      unsigned n_invalid = 0;
+      auto localStore = dynamic_cast<LocalFSStore*>(&*store);
+      if (!rootPath && !reachableOnly && localStore) {
+        for (auto & entry : std::filesystem::directory_iterator(localStore->getRealStoreDir())) {
+          std::string name = entry.path().filename().string();
+          if (name.empty() || name[0] == '.' || hasSuffix(name, ".lock") || hasSuffix(name, ".chroot")) {
+            continue;
+          }
+          std::string path = store->storeDir + "/" + name;
+          auto storePath = store->maybeParseStorePath(path);
+          // one lookup in memory instead of one query to the daemon per path
+          if (storePath && validPaths.count(*storePath) == 0) {
+            path_t invalid;
+            invalid.is_root = 0;
+            invalid.is_valid = 0;
+            invalid.size = 0;
+            invalid.path = path.c_str();
+            register_node(graph, &invalid);
+            ++n_invalid;
+          }
+        }
+      }
+#else
+      const unsigned n_invalid = 0;
+#endif
+
       if (!rootPath) {
         // register roots and add edge to corresponding store path
-        unsigned index = node_to_id.size();
+        unsigned index = node_to_id.size() + n_invalid;
 #if NIXVER >= 203
-        for (auto &[storepath, links] : findroots(store)) {
+        for (auto &[storepath, links] : roots) {
         for (auto link: links) {
 #else
-        for (auto root : findroots(store)) {{
+        for (auto root : roots) {{
           PATH link, storepath;
           std::tie(link, storepath) = root;
 #endif
           if (store->isValidPath(storepath)) {
             path_t entry;
             entry.is_root = 1;
+            entry.is_valid = 1;
             entry.size = link.size();
             entry.path = link.c_str();
             register_node(graph, &entry);
@@ -185,6 +247,16 @@ extern "C" {
         }
         }
       }
+    };
+    int retcode = handleExceptions("nix-du", [&populate]() {
+      try {
+        populate();
+      } catch (SysError & e) {
+        if (e.status == 1 && (e.errNo == EACCES || e.errNo == EPERM)) {
+          e.status = NIX_DU_PERMISSION_DENIED;
+        }
+        throw;
+      }
     });
 #if NIXVER >= 204
     restoreProcessContext();
@@ -193,6 +265,10 @@ extern "C" {
 #endif
     return retcode;
   }
+
+  const char* backendVersion() {
+    return nix::nixVersion.c_str();
+  }
 }
 
 
@@ -2,13 +2,30 @@
 
 #include <cstdint>
 
+// populateGraph returns 0 on success, and otherwise the exit status nix would
+// use, or one of these (from sysexits.h) when the cause is known.
+#define NIX_DU_CONNECTION_FAILED 69
+#define NIX_DU_PERMISSION_DENIED 77
+
 extern "C" {
   typedef struct {
     const char* path;
     uint64_t size;
     int is_root;
+    // zero for paths on disk which are not valid, like the output of an
+    // interrupted build. Their size is not known, and is 0.
+    int is_valid;
   } path_t;
-  int populateGraph(void *graph, const char* rootPath);
+  // storeUri is passed to openStore, so that the store to analyze can be
+  // chosen, for example "local?root=/mnt". When NULL, the default store is used.
+  // When rootPath is NULL, all valid paths are read, or only those reachable from
+  // gc roots if reachableOnly is non zero. When reading all paths of a local
+  // store, the invalid paths in the store directory are read too.
+  // When withDerivations is non zero, the derivation of each path is read, with
+  // its closure, as if keep-derivations was set.
+  int populateGraph(void *graph, const char* rootPath, const char* storeUri, int reachableOnly, int withDerivations);
+  // the version of the nix libraries this program is linked against
+  const char* backendVersion(void);
 }
 
 